@@ -0,0 +1,105 @@
+pub mod jwt;
+
+use async_trait::async_trait;
+use std::collections::HashMap;
+
+pub type Claims = serde_json::Map<String, serde_json::Value>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Identity {
+    /// `None` for clients accepted without any credentials.
+    pub user_id: Option<String>,
+    pub claims: Claims,
+}
+
+impl Identity {
+    pub fn anonymous() -> Self {
+        Self {
+            user_id: None,
+            claims: Claims::new(),
+        }
+    }
+
+    pub fn user(user_id: impl Into<String>) -> Self {
+        Self {
+            user_id: Some(user_id.into()),
+            claims: Claims::new(),
+        }
+    }
+}
+
+/// Verifies the token of a `HelloRequest`.
+/// A denial reason is sent to the client in `HelloResponse::message`.
+#[async_trait]
+pub trait Authenticator {
+    async fn authenticate(&self, token: &[u8]) -> Result<Identity, String>;
+}
+
+/// Accepts every client as anonymous.
+#[derive(Debug, Default)]
+pub struct AllowAllAuthenticator;
+
+#[async_trait]
+impl Authenticator for AllowAllAuthenticator {
+    async fn authenticate(&self, _token: &[u8]) -> Result<Identity, String> {
+        Ok(Identity::anonymous())
+    }
+}
+
+/// Accepts only pre-shared tokens, each of which belongs to a user.
+#[derive(Debug, Default)]
+pub struct StaticTokenAuthenticator {
+    users: HashMap<Vec<u8>, String>,
+}
+
+impl StaticTokenAuthenticator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_token(mut self, token: impl Into<Vec<u8>>, user_id: impl Into<String>) -> Self {
+        self.users.insert(token.into(), user_id.into());
+        self
+    }
+}
+
+#[async_trait]
+impl Authenticator for StaticTokenAuthenticator {
+    async fn authenticate(&self, token: &[u8]) -> Result<Identity, String> {
+        self.users
+            .get(token)
+            .map(Identity::user)
+            .ok_or_else(|| "unknown token".to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::auth::{AllowAllAuthenticator, Authenticator, Identity, StaticTokenAuthenticator};
+
+    #[tokio::test]
+    async fn allow_all() {
+        let auth = AllowAllAuthenticator;
+        assert_eq!(auth.authenticate(b"").await, Ok(Identity::anonymous()));
+        assert_eq!(auth.authenticate(b"foo").await, Ok(Identity::anonymous()));
+    }
+
+    #[tokio::test]
+    async fn static_token() {
+        let auth = StaticTokenAuthenticator::new()
+            .with_token("token-a", "alice")
+            .with_token("token-b", "bob");
+        assert_eq!(
+            auth.authenticate(b"token-a").await,
+            Ok(Identity::user("alice"))
+        );
+        assert_eq!(
+            auth.authenticate(b"token-b").await,
+            Ok(Identity::user("bob"))
+        );
+        assert_eq!(
+            auth.authenticate(b"token-c").await,
+            Err("unknown token".to_string())
+        );
+    }
+}
@@ -1,10 +1,17 @@
+use anyhow::anyhow;
 use envconfig::Envconfig;
+use kazahane::auth::jwt::{JwtAlgorithm, JwtVerifier};
+use kazahane::auth::{AllowAllAuthenticator, Authenticator, StaticTokenAuthenticator};
 use kazahane::dispatcher::Dispatcher;
 use kazahane::server;
+use kazahane::server::{Backend, MemoryBackend, ServerConfig, SledBackend};
+use std::fmt::{Debug, Formatter};
 use std::net::SocketAddr;
+use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::net::TcpListener;
-use tracing::info;
+use tracing::{info, warn};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 #[derive(Debug, Envconfig)]
@@ -12,8 +19,128 @@ pub struct Config {
     #[envconfig(from = "PORT", default = "8080")]
     pub listen_port: u16,
 
+    /// Where room state is stored. `redis-streams` also stores room state in Redis, but delivers
+    /// messages between servers through Redis Streams instead of PUBLISH.
+    /// `memory` and `sled` run a single server without Redis, and `sled` keeps room state on disk
+    /// across restarts.
+    #[envconfig(from = "BACKEND", default = "redis")]
+    pub backend: BackendKind,
+
+    /// Directory of the room state database for the sled backend.
+    #[envconfig(from = "SLED_PATH", default = "kazahane-state")]
+    pub sled_path: String,
+
     #[envconfig(from = "REDIS_ADDR", default = "redis://127.0.0.1")]
     pub redis_addr: String,
+
+    #[envconfig(from = "HELLO_TIMEOUT_SECS", default = "10")]
+    pub hello_timeout_secs: u64,
+
+    /// Maximum number of members a room may have on this server, not counting the other servers.
+    #[envconfig(from = "MAX_ROOM_MEMBERS_PER_SERVER")]
+    pub max_room_members_per_server: Option<usize>,
+
+    /// Maximum size of a packet in bytes.
+    #[envconfig(from = "MAX_PACKET_SIZE", default = "1048576")]
+    pub max_packet_size: usize,
+
+    /// Maximum length of a room state key in bytes.
+    #[envconfig(from = "MAX_STATE_KEY_SIZE", default = "256")]
+    pub max_state_key_size: usize,
+
+    /// Maximum size of a room state value in bytes.
+    #[envconfig(from = "MAX_STATE_VALUE_SIZE", default = "65536")]
+    pub max_state_value_size: usize,
+
+    /// Maximum TTL a client may set on a room state key.
+    #[envconfig(from = "MAX_STATE_TTL_SECS", default = "2592000")]
+    pub max_state_ttl_secs: u64,
+
+    /// Room state is deleted after the room has had no members for this period. Unset keeps it forever.
+    #[envconfig(from = "STATE_RETENTION_SECS")]
+    pub state_retention_secs: Option<u64>,
+
+    #[envconfig(from = "AUTHENTICATOR", default = "allow-all")]
+    pub authenticator: AuthenticatorKind,
+
+    /// Comma separated `token:user_id` pairs for the static-token authenticator.
+    #[envconfig(from = "STATIC_TOKENS")]
+    pub static_tokens: Option<Secret>,
+
+    #[envconfig(from = "JWT_ALGORITHM", default = "HS256")]
+    pub jwt_algorithm: JwtAlgorithm,
+
+    /// Shared secret for HS256.
+    #[envconfig(from = "JWT_SECRET")]
+    pub jwt_secret: Option<Secret>,
+
+    /// Path to a PEM encoded public key for RS256 and ES256.
+    #[envconfig(from = "JWT_PUBLIC_KEY_FILE")]
+    pub jwt_public_key_file: Option<String>,
+
+    #[envconfig(from = "JWT_AUDIENCE")]
+    pub jwt_audience: Option<String>,
+
+    #[envconfig(from = "JWT_ISSUER")]
+    pub jwt_issuer: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum BackendKind {
+    Redis,
+    RedisStreams,
+    Memory,
+    Sled,
+}
+
+impl FromStr for BackendKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "redis" => Ok(BackendKind::Redis),
+            "redis-streams" => Ok(BackendKind::RedisStreams),
+            "memory" => Ok(BackendKind::Memory),
+            "sled" => Ok(BackendKind::Sled),
+            _ => Err(anyhow!("unknown backend: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum AuthenticatorKind {
+    AllowAll,
+    StaticToken,
+    Jwt,
+}
+
+impl FromStr for AuthenticatorKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "allow-all" => Ok(AuthenticatorKind::AllowAll),
+            "static-token" => Ok(AuthenticatorKind::StaticToken),
+            "jwt" => Ok(AuthenticatorKind::Jwt),
+            _ => Err(anyhow!("unknown authenticator: {}", s)),
+        }
+    }
+}
+
+pub struct Secret(String);
+
+impl FromStr for Secret {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Secret(s.to_string()))
+    }
+}
+
+impl Debug for Secret {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Secret(***)")
+    }
 }
 
 #[tokio::main]
@@ -21,12 +148,79 @@ async fn main() {
     init_tracing();
     let config: Config = Config::init_from_env().unwrap();
     info!(?config);
+    let server_config = ServerConfig {
+        authenticator: authenticator(&config),
+        hello_timeout: Duration::from_secs(config.hello_timeout_secs),
+        max_room_members_per_server: config.max_room_members_per_server,
+        max_packet_size: config.max_packet_size,
+        max_state_key_size: config.max_state_key_size,
+        max_state_value_size: config.max_state_value_size,
+        max_state_ttl: Duration::from_secs(config.max_state_ttl_secs),
+        state_retention: config.state_retention_secs.map(Duration::from_secs),
+    };
 
     let addr = SocketAddr::from(([0, 0, 0, 0], config.listen_port));
     let listener = TcpListener::bind(&addr).await.expect("failed to bind");
-    let redis = redis::Client::open(config.redis_addr).unwrap();
+    let backend = match config.backend {
+        BackendKind::Redis => Backend::Redis(redis::Client::open(config.redis_addr).unwrap()),
+        BackendKind::RedisStreams => {
+            Backend::RedisStreams(redis::Client::open(config.redis_addr).unwrap())
+        }
+        BackendKind::Memory => Backend::Memory(MemoryBackend::new()),
+        BackendKind::Sled => Backend::Sled(SledBackend::open(&config.sled_path).unwrap()),
+    };
     let dispatcher = Arc::new(Dispatcher::new());
-    server::start(&listener, redis, dispatcher).await;
+    server::start_with_backend(&listener, backend, dispatcher, server_config)
+        .await
+        .expect("failed to start server");
+}
+
+fn authenticator(config: &Config) -> Arc<dyn Authenticator + Send + Sync> {
+    match config.authenticator {
+        AuthenticatorKind::AllowAll => {
+            warn!("AUTHENTICATOR is allow-all, hello tokens will not be verified");
+            Arc::new(AllowAllAuthenticator)
+        }
+        AuthenticatorKind::StaticToken => {
+            let tokens = config
+                .static_tokens
+                .as_ref()
+                .expect("STATIC_TOKENS is required");
+            let mut authenticator = StaticTokenAuthenticator::new();
+            for pair in tokens.0.split(',') {
+                let (token, user_id) = pair
+                    .split_once(':')
+                    .expect("STATIC_TOKENS must be `token:user_id` pairs");
+                authenticator = authenticator.with_token(token, user_id);
+            }
+            Arc::new(authenticator)
+        }
+        AuthenticatorKind::Jwt => Arc::new(jwt_verifier(config)),
+    }
+}
+
+fn jwt_verifier(config: &Config) -> JwtVerifier {
+    let key = match config.jwt_algorithm {
+        JwtAlgorithm::HS256 => {
+            let secret = config.jwt_secret.as_ref().expect("JWT_SECRET is required");
+            secret.0.as_bytes().to_vec()
+        }
+        JwtAlgorithm::RS256 | JwtAlgorithm::ES256 => {
+            let path = config
+                .jwt_public_key_file
+                .as_ref()
+                .expect("JWT_PUBLIC_KEY_FILE is required");
+            std::fs::read(path).expect("failed to read JWT public key")
+        }
+    };
+    let mut verifier = JwtVerifier::new(config.jwt_algorithm, &key).expect("invalid JWT key");
+    if let Some(audience) = &config.jwt_audience {
+        verifier = verifier.with_audience(audience);
+    }
+    if let Some(issuer) = &config.jwt_issuer {
+        verifier = verifier.with_issuer(issuer);
+    }
+    verifier
 }
 
 fn init_tracing() {
@@ -1,10 +1,22 @@
-use crate::dispatcher::{Dispatcher, MessageToConnection, MessageToRoom, MessageToServer};
-use crate::packets::{HelloResponseStatusCode, Packet, RoomNotification, ServerNotification};
+use crate::auth::Identity;
+use crate::dispatcher::{
+    Dispatcher, JoinReply, JoinRoomError, MessageToConnection, MessageToRoom, MessageToServer,
+    Recipients,
+};
+use crate::packets::{
+    ErrorCode, HelloResponseStatusCode, JoinRoomResponseStatusCode, Packet, RoomNotification,
+    ServerNotification, StateStatusCode, BROADCAST_FLAG_ECHO, STATE_FLAG_EXPECT_VERSION,
+    SUBSCRIBE_STATE_FLAG_PREFIX,
+};
+use crate::room_states::{StateKey, StateSubscription, StateVersion, SwapResult};
+use crate::server::ServerConfig;
+use crate::transports::{InvalidPacket, PacketTooLarge, UnsupportedVersion};
 use crate::types::{ConnectionID, RoomID};
 use async_trait::async_trait;
 use bytes::Bytes;
 use std::sync::Arc;
-use tokio::sync::mpsc;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
 use tracing::{debug, warn};
 
 #[async_trait]
@@ -12,46 +24,219 @@ pub trait Connection {
     fn connection_id(&self) -> ConnectionID;
     async fn send(&mut self, packet: Packet) -> crate::Result<()>;
     async fn recv(&mut self) -> crate::Result<Packet>;
+    async fn close(&mut self) -> crate::Result<()>;
+}
+
+enum AuthStatus {
+    NotAuthenticated,
+    Authenticated { identity: Identity },
 }
 
 enum RoomStatus {
     NotJoined,
-    Joined { room_id: RoomID },
+    Joining {
+        room_id: RoomID,
+        request_id: u32,
+        reply: oneshot::Receiver<Result<(), JoinRoomError>>,
+    },
+    Joined {
+        room_id: RoomID,
+    },
+    Leaving {
+        room_id: RoomID,
+        request_id: u32,
+    },
 }
 
 pub(crate) async fn connection_task(
     mut conn: impl Connection,
     mut receiver: mpsc::Receiver<MessageToConnection>,
     dispatcher: Arc<Dispatcher>,
+    config: Arc<ServerConfig>,
 ) {
     let connection_id = conn.connection_id();
     debug!("start connection task (connection_id: {})", connection_id);
+    let hello_timeout = tokio::time::sleep(config.hello_timeout);
+    tokio::pin!(hello_timeout);
     let mut handler = ConnectionHandler {
+        auth_status: AuthStatus::NotAuthenticated,
         room_status: RoomStatus::NotJoined,
+        to_room: None,
+        config,
     };
 
     loop {
         // TODO: handle shutdown
         tokio::select! {
-            Ok(packet) = conn.recv() => {
-                handler.handle_packet(&packet, &mut conn, &dispatcher).await;
+            // Packets are not read while a message for the room is waiting,
+            // but messages from the room still are, so that neither side blocks the other.
+            _ = send_to_room(&mut handler.to_room, &dispatcher), if handler.to_room.is_some() => {}
+            result = conn.recv(), if handler.to_room.is_none() => match result {
+                Ok(packet) => handler.handle_packet(&packet, &mut conn, &dispatcher).await,
+                Err(err) if err.is::<UnsupportedVersion>() => {
+                    debug!("unsupported protocol version (connection_id: {}): {:?}", connection_id, err);
+                    send_error(&mut conn, 0, ErrorCode::UnsupportedVersion, &err.to_string()).await;
+                    if let Err(err) = conn.close().await {
+                        warn!("failed to close connection: {:?}", err);
+                    }
+                    break;
+                }
+                Err(err) => {
+                    let code = if err.is::<PacketTooLarge>() {
+                        ErrorCode::PacketTooLarge
+                    } else if err.is::<InvalidPacket>() {
+                        ErrorCode::InvalidPacket
+                    } else {
+                        debug!("connection closed (connection_id: {}): {:?}", connection_id, err);
+                        break;
+                    };
+                    debug!("discard packet (connection_id: {}): {:?}", connection_id, err);
+                    send_error(&mut conn, 0, code, &err.to_string()).await;
+                }
+            },
+            result = join_reply(&mut handler.room_status) => {
+                handler.handle_join_reply(result, &mut conn).await;
             }
             Some(msg) = receiver.recv() => {
                 handler.handle_message(msg, &mut conn).await;
             }
+            _ = &mut hello_timeout, if !handler.is_authenticated() => {
+                debug!("hello timed out (connection_id: {})", connection_id);
+                send_error(&mut conn, 0, ErrorCode::HelloTimeout, "hello timed out").await;
+                if let Err(err) = conn.close().await {
+                    warn!("failed to close connection: {:?}", err);
+                }
+                break;
+            }
             else => break
         }
     }
     debug!("drop connection: {}", connection_id);
+    // Unregister before leaving, so that a room processing our join after this can tell that we are gone.
     dispatcher.drop_connection(&connection_id);
+    // A room blocked on sending to this connection must not wait for us while we wait for the room.
+    drop(receiver);
+    // The leave request may still be waiting to be sent, and a room ignores a second one.
+    if let RoomStatus::Joining { room_id, .. }
+    | RoomStatus::Joined { room_id }
+    | RoomStatus::Leaving { room_id, .. } = handler.room_status
+    {
+        dispatcher
+            .publish_to_room(&room_id, MessageToRoom::Leave { connection_id })
+            .await;
+    }
+}
+
+/// Sends the message waiting for the room once the room has capacity for it.
+/// Nothing is taken when the future is dropped before that.
+async fn send_to_room(to_room: &mut Option<(RoomID, MessageToRoom)>, dispatcher: &Dispatcher) {
+    let sender = match to_room {
+        Some((room_id, _)) => dispatcher.room_sender(room_id),
+        None => return,
+    };
+    let permit = match sender {
+        Some(sender) => sender.reserve_owned().await.ok(),
+        None => None,
+    };
+    // If the room is gone, the message is dropped.
+    let msg = to_room.take().map(|(_, msg)| msg);
+    if let (Some(permit), Some(msg)) = (permit, msg) {
+        permit.send(msg);
+    }
+}
+
+/// Waits for the room to accept or reject the join in progress.
+async fn join_reply(room_status: &mut RoomStatus) -> Result<(), JoinRoomError> {
+    match room_status {
+        RoomStatus::Joining { reply, .. } => {
+            reply.await.unwrap_or_else(|_| Err(room_unavailable()))
+        }
+        _ => std::future::pending().await,
+    }
+}
+
+/// The room dropped the join without a reply.
+fn room_unavailable() -> JoinRoomError {
+    JoinRoomError::Rejected("room is unavailable".to_string())
+}
+
+async fn send_error(conn: &mut impl Connection, request_id: u32, code: ErrorCode, message: &str) {
+    let packet = Packet::Error {
+        request_id,
+        code,
+        message: message.as_bytes().to_vec(),
+    };
+    if let Err(err) = conn.send(packet).await {
+        warn!("failed to send to client: {:?}", err);
+    }
 }
 
 struct ConnectionHandler {
+    auth_status: AuthStatus,
     room_status: RoomStatus,
+    /// A message for the room, sent by `connection_task` rather than awaited while handling a packet.
+    to_room: Option<(RoomID, MessageToRoom)>,
+    config: Arc<ServerConfig>,
 }
 
 impl ConnectionHandler {
+    fn is_authenticated(&self) -> bool {
+        matches!(self.auth_status, AuthStatus::Authenticated { .. })
+    }
+
+    async fn handle_join_reply(
+        &mut self,
+        result: Result<(), JoinRoomError>,
+        conn: &mut impl Connection,
+    ) {
+        let (room_id, request_id) = match &self.room_status {
+            RoomStatus::Joining {
+                room_id,
+                request_id,
+                ..
+            } => (*room_id, *request_id),
+            _ => return,
+        };
+        let (status_code, message) = match result {
+            Ok(()) => {
+                self.room_status = RoomStatus::Joined { room_id };
+                (JoinRoomResponseStatusCode::OK, vec![])
+            }
+            Err(JoinRoomError::RoomFull) => {
+                self.room_status = RoomStatus::NotJoined;
+                (
+                    JoinRoomResponseStatusCode::RoomFull,
+                    b"room is full".to_vec(),
+                )
+            }
+            Err(JoinRoomError::Rejected(reason)) => {
+                self.room_status = RoomStatus::NotJoined;
+                (JoinRoomResponseStatusCode::Rejected, reason.into_bytes())
+            }
+        };
+        let packet = Packet::JoinRoomResponse {
+            request_id,
+            status_code,
+            message,
+        };
+        if let Err(err) = conn.send(packet).await {
+            warn!("failed to send to client: {:?}", err);
+        }
+    }
+
     async fn handle_message(&mut self, msg: MessageToConnection, conn: &mut impl Connection) {
+        // The room replies to the join before sending anything else to the connection,
+        // so the reply goes first when both are ready.
+        if let RoomStatus::Joining { reply, .. } = &mut self.room_status {
+            let result = match reply.try_recv() {
+                Ok(result) => Some(result),
+                Err(oneshot::error::TryRecvError::Closed) => Some(Err(room_unavailable())),
+                Err(oneshot::error::TryRecvError::Empty) => None,
+            };
+            if let Some(result) = result {
+                self.handle_join_reply(result, conn).await;
+            }
+        }
         match (&self.room_status, msg) {
             (_, MessageToConnection::Shutdown { .. }) => {
                 let packet = Packet::ServerNotification(ServerNotification::Shutdown);
@@ -59,83 +244,638 @@ impl ConnectionHandler {
                     warn!("failed to send to client: {:?}", err);
                 }
             }
-            (RoomStatus::NotJoined, MessageToConnection::JoinResponse { room_id }) => {
-                self.room_status = RoomStatus::Joined { room_id };
-                let packet = Packet::JoinRoomResponse {};
+            (
+                RoomStatus::Leaving {
+                    room_id,
+                    request_id,
+                },
+                MessageToConnection::LeaveResponse {
+                    room_id: left_room_id,
+                },
+            ) if *room_id == left_room_id => {
+                let packet = Packet::LeaveRoomResponse {
+                    request_id: *request_id,
+                };
+                self.room_status = RoomStatus::NotJoined;
                 if let Err(err) = conn.send(packet).await {
                     warn!("failed to send to client: {:?}", err);
                 }
             }
-            (RoomStatus::Joined { .. }, msg) => match msg {
-                MessageToConnection::Broadcast { payload, .. } => {
-                    let payload = payload.to_vec();
-                    let packet = Packet::RoomNotification(RoomNotification::Broadcast { payload });
+            // Notifications of the room are dropped unless joined, while responses to requests
+            // sent before joining or leaving are still delivered.
+            (
+                RoomStatus::NotJoined | RoomStatus::Joining { .. } | RoomStatus::Leaving { .. },
+                msg,
+            ) if msg.is_room_notification() => {}
+            (_, msg) => match msg {
+                MessageToConnection::PlayerJoined { player } => {
+                    let player = player.into_bytes();
+                    let packet =
+                        Packet::RoomNotification(RoomNotification::PlayerJoined { player });
+                    if let Err(err) = conn.send(packet).await {
+                        warn!("failed to send to client: {:?}", err);
+                    }
+                }
+                MessageToConnection::PlayerLeft { player } => {
+                    let player = player.into_bytes();
+                    let packet = Packet::RoomNotification(RoomNotification::PlayerLeft { player });
+                    if let Err(err) = conn.send(packet).await {
+                        warn!("failed to send to client: {:?}", err);
+                    }
+                }
+                MessageToConnection::Broadcast {
+                    sender,
+                    user_id,
+                    timestamp,
+                    payload,
+                } => {
+                    let packet = Packet::RoomNotification(RoomNotification::Broadcast {
+                        sender: sender.into_bytes(),
+                        user_id: user_id.map(String::into_bytes).unwrap_or_default(),
+                        timestamp,
+                        payload: payload.to_vec(),
+                    });
                     if let Err(err) = conn.send(packet).await {
                         warn!("failed to send to client: {:?}", err);
                     }
                 }
-                MessageToConnection::TestCountUpResponse { counter } => {
+                MessageToConnection::DirectMessage {
+                    sender,
+                    user_id,
+                    timestamp,
+                    payload,
+                } => {
+                    let packet = Packet::RoomNotification(RoomNotification::DirectMessage {
+                        sender: sender.into_bytes(),
+                        user_id: user_id.map(String::into_bytes).unwrap_or_default(),
+                        timestamp,
+                        payload: payload.to_vec(),
+                    });
+                    if let Err(err) = conn.send(packet).await {
+                        warn!("failed to send to client: {:?}", err);
+                    }
+                }
+                MessageToConnection::GetStateResponse { request_id, value } => {
+                    let packet = match value.data {
+                        Some(data) => Packet::GetStateResponse {
+                            request_id,
+                            status_code: StateStatusCode::OK,
+                            version: value.version,
+                            value: data,
+                        },
+                        None => Packet::GetStateResponse {
+                            request_id,
+                            status_code: StateStatusCode::NotFound,
+                            version: value.version,
+                            value: vec![],
+                        },
+                    };
+                    if let Err(err) = conn.send(packet).await {
+                        warn!("failed to send to client: {:?}", err);
+                    }
+                }
+                MessageToConnection::SetStateResponse { request_id, result } => {
+                    let (status_code, version) = swap_status(result);
+                    let packet = Packet::SetStateResponse {
+                        request_id,
+                        status_code,
+                        version,
+                    };
+                    if let Err(err) = conn.send(packet).await {
+                        warn!("failed to send to client: {:?}", err);
+                    }
+                }
+                MessageToConnection::DeleteStateResponse { request_id, result } => {
+                    let (status_code, version) = swap_status(result);
+                    let packet = Packet::DeleteStateResponse {
+                        request_id,
+                        status_code,
+                        version,
+                    };
+                    if let Err(err) = conn.send(packet).await {
+                        warn!("failed to send to client: {:?}", err);
+                    }
+                }
+                MessageToConnection::IncrementStateResponse { request_id, result } => {
+                    let packet = match result {
+                        Some((value, version)) => Packet::IncrementStateResponse {
+                            request_id,
+                            status_code: StateStatusCode::OK,
+                            version,
+                            value,
+                        },
+                        None => Packet::IncrementStateResponse {
+                            request_id,
+                            status_code: StateStatusCode::NotAnInteger,
+                            version: 0,
+                            value: 0,
+                        },
+                    };
+                    if let Err(err) = conn.send(packet).await {
+                        warn!("failed to send to client: {:?}", err);
+                    }
+                }
+                MessageToConnection::SetStateIfAbsentResponse {
+                    request_id,
+                    version,
+                } => {
+                    let packet = match version {
+                        Some(version) => Packet::SetStateIfAbsentResponse {
+                            request_id,
+                            status_code: StateStatusCode::OK,
+                            version,
+                        },
+                        None => Packet::SetStateIfAbsentResponse {
+                            request_id,
+                            status_code: StateStatusCode::AlreadyExists,
+                            version: 0,
+                        },
+                    };
+                    if let Err(err) = conn.send(packet).await {
+                        warn!("failed to send to client: {:?}", err);
+                    }
+                }
+                MessageToConnection::SubscribeStateResponse {
+                    request_id,
+                    snapshot,
+                } => {
+                    let packet = Packet::SubscribeStateResponse {
+                        request_id,
+                        status_code: StateStatusCode::OK,
+                    };
+                    if let Err(err) = conn.send(packet).await {
+                        warn!("failed to send to client: {:?}", err);
+                    }
+                    for (key, version, value) in snapshot {
+                        let packet = Packet::RoomNotification(RoomNotification::StateChanged {
+                            key: key.into_bytes(),
+                            version,
+                            value,
+                        });
+                        if let Err(err) = conn.send(packet).await {
+                            warn!("failed to send to client: {:?}", err);
+                        }
+                    }
+                }
+                MessageToConnection::UnsubscribeStateResponse { request_id, found } => {
+                    let status_code = if found {
+                        StateStatusCode::OK
+                    } else {
+                        StateStatusCode::NotFound
+                    };
+                    let packet = Packet::UnsubscribeStateResponse {
+                        request_id,
+                        status_code,
+                    };
+                    if let Err(err) = conn.send(packet).await {
+                        warn!("failed to send to client: {:?}", err);
+                    }
+                }
+                MessageToConnection::StateChanged {
+                    key,
+                    version,
+                    value,
+                } => {
+                    let packet = Packet::RoomNotification(RoomNotification::StateChanged {
+                        key: key.into_bytes(),
+                        version,
+                        value,
+                    });
+                    if let Err(err) = conn.send(packet).await {
+                        warn!("failed to send to client: {:?}", err);
+                    }
+                }
+                MessageToConnection::StateDeleted { key, version } => {
+                    let key = key.into_bytes();
+                    let packet =
+                        Packet::RoomNotification(RoomNotification::StateDeleted { key, version });
+                    if let Err(err) = conn.send(packet).await {
+                        warn!("failed to send to client: {:?}", err);
+                    }
+                }
+                MessageToConnection::TestCountUpResponse {
+                    request_id,
+                    counter,
+                } => {
                     let packet = Packet::TestCountUpResponse {
+                        request_id,
                         counter: counter as u64,
                     };
                     if let Err(err) = conn.send(packet).await {
                         warn!("failed to send to client: {:?}", err);
                     }
                 }
+                MessageToConnection::Error {
+                    request_id,
+                    code,
+                    message,
+                } => {
+                    send_error(conn, request_id, code, &message).await;
+                }
                 _ => {
                     warn!("unknown message received: {:?}", msg)
                 }
             },
-            _ => {}
         }
     }
 
     async fn handle_packet(
-        &self,
+        &mut self,
         packet: &Packet,
         conn: &mut impl Connection,
         dispatcher: &Dispatcher,
     ) {
-        match (&self.room_status, &packet) {
-            (RoomStatus::NotJoined, Packet::HelloRequest { token }) => {
-                self.handle_hello(token, conn).await;
+        let request_id = packet.request_id();
+        let identity = match (&self.auth_status, packet) {
+            (AuthStatus::NotAuthenticated, Packet::HelloRequest { token, .. }) => {
+                self.handle_hello(request_id, token, conn).await;
+                return;
             }
-            (RoomStatus::NotJoined, Packet::JoinRoomRequest { room_id }) => {
-                let room_id = RoomID::from_bytes(*room_id);
-                self.handle_join_room(room_id, conn, dispatcher).await;
+            (AuthStatus::NotAuthenticated, _) => {
+                let message = "hello is required before any other request";
+                send_error(conn, request_id, ErrorCode::NotAuthenticated, message).await;
+                return;
             }
-            (RoomStatus::Joined { room_id }, Packet::BroadcastRequest { payload }) => {
-                self.handle_broadcast(payload, conn, *room_id, dispatcher)
-                    .await;
+            (AuthStatus::Authenticated { .. }, Packet::HelloRequest { .. }) => {
+                let message = "hello has already been completed";
+                send_error(conn, request_id, ErrorCode::AlreadyAuthenticated, message).await;
+                return;
             }
-            (RoomStatus::Joined { room_id }, Packet::TestCountUp {}) => {
-                dispatcher
-                    .publish_to_room(
-                        room_id,
-                        MessageToRoom::TestCountUp {
-                            sender: conn.connection_id(),
-                        },
-                    )
+            (AuthStatus::Authenticated { identity }, _) => identity,
+        };
+        match (&self.room_status, packet) {
+            (RoomStatus::NotJoined, Packet::JoinRoomRequest { room_id, .. }) => {
+                let room_id = RoomID::from_bytes(*room_id);
+                debug!(
+                    "join room (connection_id: {}, user_id: {:?}, room_id: {})",
+                    conn.connection_id(),
+                    identity.user_id,
+                    room_id
+                );
+                let user_id = identity.user_id.clone();
+                let (reply, on_reply) = oneshot::channel();
+                self.room_status = RoomStatus::Joining {
+                    room_id,
+                    request_id,
+                    reply: on_reply,
+                };
+                self.handle_join_room(room_id, user_id, reply, conn, dispatcher)
                     .await;
             }
+            (
+                RoomStatus::Joined { room_id },
+                Packet::BroadcastRequest {
+                    flags,
+                    filter,
+                    players,
+                    payload,
+                    ..
+                },
+            ) => {
+                let msg = MessageToRoom::Broadcast {
+                    sender: conn.connection_id(),
+                    echo: flags & BROADCAST_FLAG_ECHO != 0,
+                    recipients: Recipients::from_filter(*filter, players),
+                    payload: Bytes::from(payload.to_vec()),
+                };
+                self.to_room = Some((*room_id, msg));
+            }
+            (
+                RoomStatus::Joined { room_id },
+                Packet::DirectMessageRequest {
+                    targets, payload, ..
+                },
+            ) => {
+                self.to_room = Some((
+                    *room_id,
+                    MessageToRoom::DirectMessage {
+                        sender: conn.connection_id(),
+                        targets: targets
+                            .iter()
+                            .copied()
+                            .map(ConnectionID::from_bytes)
+                            .collect(),
+                        payload: Bytes::from(payload.to_vec()),
+                    },
+                ));
+            }
+            (RoomStatus::Joined { room_id }, Packet::LeaveRoomRequest { .. }) => {
+                let room_id = *room_id;
+                self.room_status = RoomStatus::Leaving {
+                    room_id,
+                    request_id,
+                };
+                self.to_room = Some((
+                    room_id,
+                    MessageToRoom::Leave {
+                        connection_id: conn.connection_id(),
+                    },
+                ));
+            }
+            (RoomStatus::Joined { room_id }, Packet::GetStateRequest { key, .. }) => {
+                let key = match self.validate_state_key(key) {
+                    Ok(key) => key,
+                    Err(status_code) => {
+                        let packet = Packet::GetStateResponse {
+                            request_id,
+                            status_code,
+                            version: 0,
+                            value: vec![],
+                        };
+                        if let Err(err) = conn.send(packet).await {
+                            warn!("failed to send to client: {:?}", err);
+                        }
+                        return;
+                    }
+                };
+                let msg = MessageToRoom::GetState {
+                    sender: conn.connection_id(),
+                    request_id,
+                    key,
+                };
+                self.to_room = Some((*room_id, msg));
+            }
+            (
+                RoomStatus::Joined { room_id },
+                Packet::SetStateRequest {
+                    key,
+                    flags,
+                    expected_version,
+                    ttl,
+                    value,
+                    ..
+                },
+            ) => {
+                let validated = self
+                    .validate_state_write(key, value)
+                    .and_then(|key| Ok((key, self.validate_state_ttl(*ttl)?)));
+                let (key, ttl) = match validated {
+                    Ok(validated) => validated,
+                    Err(status_code) => {
+                        let packet = Packet::SetStateResponse {
+                            request_id,
+                            status_code,
+                            version: 0,
+                        };
+                        if let Err(err) = conn.send(packet).await {
+                            warn!("failed to send to client: {:?}", err);
+                        }
+                        return;
+                    }
+                };
+                let msg = MessageToRoom::SetState {
+                    sender: conn.connection_id(),
+                    request_id,
+                    key,
+                    value: value.clone(),
+                    expected_version: expected_version_of(*flags, *expected_version),
+                    ttl,
+                };
+                self.to_room = Some((*room_id, msg));
+            }
+            (
+                RoomStatus::Joined { room_id },
+                Packet::DeleteStateRequest {
+                    key,
+                    flags,
+                    expected_version,
+                    ..
+                },
+            ) => {
+                let key = match self.validate_state_key(key) {
+                    Ok(key) => key,
+                    Err(status_code) => {
+                        let packet = Packet::DeleteStateResponse {
+                            request_id,
+                            status_code,
+                            version: 0,
+                        };
+                        if let Err(err) = conn.send(packet).await {
+                            warn!("failed to send to client: {:?}", err);
+                        }
+                        return;
+                    }
+                };
+                let msg = MessageToRoom::DeleteState {
+                    sender: conn.connection_id(),
+                    request_id,
+                    key,
+                    expected_version: expected_version_of(*flags, *expected_version),
+                };
+                self.to_room = Some((*room_id, msg));
+            }
+            (RoomStatus::Joined { room_id }, Packet::IncrementStateRequest { key, delta, .. }) => {
+                let key = match self.validate_state_key(key) {
+                    Ok(key) => key,
+                    Err(status_code) => {
+                        let packet = Packet::IncrementStateResponse {
+                            request_id,
+                            status_code,
+                            version: 0,
+                            value: 0,
+                        };
+                        if let Err(err) = conn.send(packet).await {
+                            warn!("failed to send to client: {:?}", err);
+                        }
+                        return;
+                    }
+                };
+                let msg = MessageToRoom::IncrementState {
+                    sender: conn.connection_id(),
+                    request_id,
+                    key,
+                    delta: *delta,
+                };
+                self.to_room = Some((*room_id, msg));
+            }
+            (
+                RoomStatus::Joined { room_id },
+                Packet::SetStateIfAbsentRequest { key, value, .. },
+            ) => {
+                let key = match self.validate_state_write(key, value) {
+                    Ok(key) => key,
+                    Err(status_code) => {
+                        let packet = Packet::SetStateIfAbsentResponse {
+                            request_id,
+                            status_code,
+                            version: 0,
+                        };
+                        if let Err(err) = conn.send(packet).await {
+                            warn!("failed to send to client: {:?}", err);
+                        }
+                        return;
+                    }
+                };
+                let msg = MessageToRoom::SetStateIfAbsent {
+                    sender: conn.connection_id(),
+                    request_id,
+                    key,
+                    value: value.clone(),
+                };
+                self.to_room = Some((*room_id, msg));
+            }
+            (RoomStatus::Joined { room_id }, Packet::SubscribeStateRequest { flags, key, .. }) => {
+                let subscription = match self.validate_state_subscription(*flags, key) {
+                    Ok(subscription) => subscription,
+                    Err(status_code) => {
+                        let packet = Packet::SubscribeStateResponse {
+                            request_id,
+                            status_code,
+                        };
+                        if let Err(err) = conn.send(packet).await {
+                            warn!("failed to send to client: {:?}", err);
+                        }
+                        return;
+                    }
+                };
+                let msg = MessageToRoom::SubscribeState {
+                    sender: conn.connection_id(),
+                    request_id,
+                    subscription,
+                };
+                self.to_room = Some((*room_id, msg));
+            }
+            (
+                RoomStatus::Joined { room_id },
+                Packet::UnsubscribeStateRequest { flags, key, .. },
+            ) => {
+                let subscription = match self.validate_state_subscription(*flags, key) {
+                    Ok(subscription) => subscription,
+                    Err(status_code) => {
+                        let packet = Packet::UnsubscribeStateResponse {
+                            request_id,
+                            status_code,
+                        };
+                        if let Err(err) = conn.send(packet).await {
+                            warn!("failed to send to client: {:?}", err);
+                        }
+                        return;
+                    }
+                };
+                let msg = MessageToRoom::UnsubscribeState {
+                    sender: conn.connection_id(),
+                    request_id,
+                    subscription,
+                };
+                self.to_room = Some((*room_id, msg));
+            }
+            (RoomStatus::Joined { room_id }, Packet::TestCountUp { .. }) => {
+                self.to_room = Some((
+                    *room_id,
+                    MessageToRoom::TestCountUp {
+                        sender: conn.connection_id(),
+                        request_id,
+                    },
+                ));
+            }
+            (_, Packet::JoinRoomRequest { .. }) => {
+                let message = "already joined a room";
+                send_error(conn, request_id, ErrorCode::InvalidState, message).await;
+            }
+            (
+                _,
+                Packet::BroadcastRequest { .. }
+                | Packet::DirectMessageRequest { .. }
+                | Packet::LeaveRoomRequest { .. }
+                | Packet::GetStateRequest { .. }
+                | Packet::SetStateRequest { .. }
+                | Packet::DeleteStateRequest { .. }
+                | Packet::IncrementStateRequest { .. }
+                | Packet::SetStateIfAbsentRequest { .. }
+                | Packet::SubscribeStateRequest { .. }
+                | Packet::UnsubscribeStateRequest { .. }
+                | Packet::TestCountUp { .. },
+            ) => {
+                let message = "not joined a room";
+                send_error(conn, request_id, ErrorCode::InvalidState, message).await;
+            }
             _ => {
-                warn!("unknown packet received: {:?}", packet);
+                debug!("unexpected packet received: {:?}", packet);
+                let message = "unexpected packet";
+                send_error(conn, request_id, ErrorCode::InvalidState, message).await;
             }
         }
     }
 
-    async fn handle_hello(&self, _token: &[u8], conn: &mut impl Connection) {
-        conn.send(Packet::HelloResponse {
-            status_code: HelloResponseStatusCode::OK,
-            message: vec![],
-        })
-        .await
-        .unwrap();
+    fn validate_state_key(&self, key: &[u8]) -> Result<StateKey, StateStatusCode> {
+        if key.is_empty() || key.len() > self.config.max_state_key_size {
+            return Err(StateStatusCode::InvalidKey);
+        }
+        String::from_utf8(key.to_vec()).map_err(|_| StateStatusCode::InvalidKey)
+    }
+
+    fn validate_state_subscription(
+        &self,
+        flags: u8,
+        key: &[u8],
+    ) -> Result<StateSubscription, StateStatusCode> {
+        if flags & SUBSCRIBE_STATE_FLAG_PREFIX == 0 {
+            return self.validate_state_key(key).map(StateSubscription::Key);
+        }
+        // Unlike keys, the prefix may be empty.
+        if key.len() > self.config.max_state_key_size {
+            return Err(StateStatusCode::InvalidKey);
+        }
+        String::from_utf8(key.to_vec())
+            .map(StateSubscription::Prefix)
+            .map_err(|_| StateStatusCode::InvalidKey)
+    }
+
+    /// 0 means no TTL of the key's own.
+    fn validate_state_ttl(&self, ttl: u64) -> Result<Option<Duration>, StateStatusCode> {
+        let ttl = Duration::from_millis(ttl);
+        if ttl > self.config.max_state_ttl {
+            return Err(StateStatusCode::InvalidTtl);
+        }
+        Ok((!ttl.is_zero()).then_some(ttl))
+    }
+
+    fn validate_state_write(&self, key: &[u8], value: &[u8]) -> Result<StateKey, StateStatusCode> {
+        let key = self.validate_state_key(key)?;
+        if value.len() > self.config.max_state_value_size {
+            return Err(StateStatusCode::ValueTooLarge);
+        }
+        Ok(key)
+    }
+
+    async fn handle_hello(&mut self, request_id: u32, token: &[u8], conn: &mut impl Connection) {
+        let packet = match self.config.authenticator.authenticate(token).await {
+            Ok(identity) => {
+                debug!(
+                    "hello accepted (connection_id: {}, user_id: {:?})",
+                    conn.connection_id(),
+                    identity.user_id
+                );
+                self.auth_status = AuthStatus::Authenticated { identity };
+                Packet::HelloResponse {
+                    request_id,
+                    status_code: HelloResponseStatusCode::OK,
+                    message: vec![],
+                }
+            }
+            Err(reason) => {
+                debug!(
+                    "hello denied (connection_id: {}): {}",
+                    conn.connection_id(),
+                    reason
+                );
+                Packet::HelloResponse {
+                    request_id,
+                    status_code: HelloResponseStatusCode::Denied,
+                    message: reason.into_bytes(),
+                }
+            }
+        };
+        if let Err(err) = conn.send(packet).await {
+            warn!("failed to send to client: {:?}", err);
+        }
     }
 
     async fn handle_join_room(
         &self,
         room_id: RoomID,
+        user_id: Option<String>,
+        reply: JoinReply,
         conn: &impl Connection,
         dispatcher: &Dispatcher,
     ) {
@@ -143,25 +883,21 @@ impl ConnectionHandler {
             .publish_to_server(MessageToServer::Join {
                 connection_id: conn.connection_id(),
                 room_id,
+                user_id,
+                reply,
             })
             .await;
     }
+}
 
-    async fn handle_broadcast(
-        &self,
-        payload: &[u8],
-        conn: &impl Connection,
-        room_id: RoomID,
-        dispatcher: &Dispatcher,
-    ) {
-        dispatcher
-            .publish_to_room(
-                &room_id,
-                MessageToRoom::Broadcast {
-                    sender: conn.connection_id(),
-                    payload: Bytes::from(payload.to_vec()),
-                },
-            )
-            .await;
+fn expected_version_of(flags: u8, expected_version: u64) -> Option<StateVersion> {
+    (flags & STATE_FLAG_EXPECT_VERSION != 0).then_some(expected_version)
+}
+
+/// Maps the result of a state write to the status code and version of its response.
+fn swap_status(result: SwapResult) -> (StateStatusCode, StateVersion) {
+    match result {
+        SwapResult::Swapped { version } => (StateStatusCode::OK, version),
+        SwapResult::Conflict { current } => (StateStatusCode::VersionMismatch, current.version),
     }
 }
@@ -1,14 +1,22 @@
+use crate::packets::{BroadcastFilter, ErrorCode};
+use crate::pubsub::PubSubHealth;
+use crate::room_states::{
+    StateData, StateKey, StateSubscription, StateVersion, SwapResult, VersionedData,
+};
 use crate::types::{ConnectionID, RoomID};
 use bytes::Bytes;
 use std::collections::HashMap;
 use std::sync::Mutex;
-use tokio::sync::mpsc;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
 
 #[derive(Debug)]
 pub enum MessageToServer {
     Join {
         connection_id: ConnectionID,
         room_id: RoomID,
+        user_id: Option<String>,
+        reply: JoinReply,
     },
     Shutdown {
         reason: ServerShutdownReason,
@@ -24,29 +32,219 @@ pub enum ServerShutdownReason {
 pub enum MessageToRoom {
     Join {
         connection_id: ConnectionID,
+        user_id: Option<String>,
+        /// Answered before anything else is sent to the connection from the room.
+        reply: JoinReply,
+    },
+    Leave {
+        connection_id: ConnectionID,
     },
     Broadcast {
         sender: ConnectionID,
+        /// Whether the sender receives its own broadcast.
+        echo: bool,
+        recipients: Recipients,
+        payload: Bytes,
+    },
+    DirectMessage {
+        sender: ConnectionID,
+        targets: Vec<ConnectionID>,
         payload: Bytes,
     },
+    GetState {
+        sender: ConnectionID,
+        request_id: u32,
+        key: StateKey,
+    },
+    SetState {
+        sender: ConnectionID,
+        request_id: u32,
+        key: StateKey,
+        value: StateData,
+        expected_version: Option<StateVersion>,
+        ttl: Option<Duration>,
+    },
+    DeleteState {
+        sender: ConnectionID,
+        request_id: u32,
+        key: StateKey,
+        expected_version: Option<StateVersion>,
+    },
+    IncrementState {
+        sender: ConnectionID,
+        request_id: u32,
+        key: StateKey,
+        delta: i64,
+    },
+    SetStateIfAbsent {
+        sender: ConnectionID,
+        request_id: u32,
+        key: StateKey,
+        value: StateData,
+    },
+    SubscribeState {
+        sender: ConnectionID,
+        request_id: u32,
+        subscription: StateSubscription,
+    },
+    UnsubscribeState {
+        sender: ConnectionID,
+        request_id: u32,
+        subscription: StateSubscription,
+    },
     TestCountUp {
         sender: ConnectionID,
+        request_id: u32,
     },
 }
 
 #[derive(Clone, Debug)]
 pub enum MessageToConnection {
-    JoinResponse { room_id: RoomID },
-    Broadcast { payload: Bytes },
-    TestCountUpResponse { counter: usize },
-    Shutdown { reason: ServerShutdownReason },
+    LeaveResponse {
+        room_id: RoomID,
+    },
+    PlayerJoined {
+        player: ConnectionID,
+    },
+    PlayerLeft {
+        player: ConnectionID,
+    },
+    Broadcast {
+        sender: ConnectionID,
+        user_id: Option<String>,
+        /// Milliseconds since the UNIX epoch at which the room received the message.
+        timestamp: u64,
+        payload: Bytes,
+    },
+    DirectMessage {
+        sender: ConnectionID,
+        user_id: Option<String>,
+        timestamp: u64,
+        payload: Bytes,
+    },
+    GetStateResponse {
+        request_id: u32,
+        value: VersionedData,
+    },
+    SetStateResponse {
+        request_id: u32,
+        result: SwapResult,
+    },
+    DeleteStateResponse {
+        request_id: u32,
+        result: SwapResult,
+    },
+    /// `None` when the current value is not an integer.
+    IncrementStateResponse {
+        request_id: u32,
+        result: Option<(i64, StateVersion)>,
+    },
+    /// `None` when the key already exists.
+    SetStateIfAbsentResponse {
+        request_id: u32,
+        version: Option<StateVersion>,
+    },
+    /// `snapshot` has the existing keys of the subscription in a single message,
+    /// since the room can't wait for the connection to drain its channel.
+    SubscribeStateResponse {
+        request_id: u32,
+        snapshot: Vec<(StateKey, StateVersion, StateData)>,
+    },
+    /// `found` is false when the connection was not subscribed.
+    UnsubscribeStateResponse {
+        request_id: u32,
+        found: bool,
+    },
+    StateChanged {
+        key: StateKey,
+        version: StateVersion,
+        value: StateData,
+    },
+    StateDeleted {
+        key: StateKey,
+        version: StateVersion,
+    },
+    TestCountUpResponse {
+        request_id: u32,
+        counter: usize,
+    },
+    /// An operation requested by the connection failed in the room.
+    Error {
+        request_id: u32,
+        code: ErrorCode,
+        message: String,
+    },
+    Shutdown {
+        reason: ServerShutdownReason,
+    },
 }
 
+impl MessageToConnection {
+    /// Notifications sent to every member rather than in response to a request of the connection.
+    pub fn is_room_notification(&self) -> bool {
+        matches!(
+            self,
+            MessageToConnection::PlayerJoined { .. }
+                | MessageToConnection::PlayerLeft { .. }
+                | MessageToConnection::Broadcast { .. }
+                | MessageToConnection::DirectMessage { .. }
+                | MessageToConnection::StateChanged { .. }
+                | MessageToConnection::StateDeleted { .. }
+        )
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Recipients {
+    All,
+    Only(Vec<ConnectionID>),
+    Except(Vec<ConnectionID>),
+}
+
+impl Recipients {
+    pub fn from_filter(filter: BroadcastFilter, players: &[uuid::Bytes]) -> Self {
+        let players = players.iter().copied().map(ConnectionID::from_bytes);
+        match filter {
+            BroadcastFilter::All => Recipients::All,
+            BroadcastFilter::Include => Recipients::Only(players.collect()),
+            BroadcastFilter::Exclude => Recipients::Except(players.collect()),
+        }
+    }
+
+    pub fn to_filter(&self) -> (BroadcastFilter, Vec<uuid::Bytes>) {
+        let to_bytes = |players: &[ConnectionID]| players.iter().map(|p| p.into_bytes()).collect();
+        match self {
+            Recipients::All => (BroadcastFilter::All, vec![]),
+            Recipients::Only(players) => (BroadcastFilter::Include, to_bytes(players)),
+            Recipients::Except(players) => (BroadcastFilter::Exclude, to_bytes(players)),
+        }
+    }
+
+    pub fn includes(&self, connection_id: &ConnectionID) -> bool {
+        match self {
+            Recipients::All => true,
+            Recipients::Only(players) => players.contains(connection_id),
+            Recipients::Except(players) => !players.contains(connection_id),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum JoinRoomError {
+    RoomFull,
+    /// The join was refused for the given reason.
+    Rejected(String),
+}
+
+/// Where the room accepts or rejects a join.
+pub type JoinReply = oneshot::Sender<Result<(), JoinRoomError>>;
+
 #[derive(Debug)]
 pub struct Dispatcher {
     server_sender: Mutex<Option<mpsc::Sender<MessageToServer>>>,
     room_senders: Mutex<HashMap<RoomID, mpsc::Sender<MessageToRoom>>>,
     connection_senders: Mutex<HashMap<ConnectionID, mpsc::Sender<MessageToConnection>>>,
+    pubsub_health: PubSubHealth,
 }
 
 impl Default for Dispatcher {
@@ -61,9 +259,15 @@ impl Dispatcher {
             server_sender: Mutex::new(None),
             room_senders: Mutex::new(HashMap::new()),
             connection_senders: Mutex::new(HashMap::new()),
+            pubsub_health: PubSubHealth::default(),
         }
     }
 
+    /// Health of the pubsub subscriptions of the rooms on this server.
+    pub fn pubsub_health(&self) -> &PubSubHealth {
+        &self.pubsub_health
+    }
+
     pub fn register_server(&self) -> mpsc::Receiver<MessageToServer> {
         let (tx, rx) = mpsc::channel(8);
         let _ = self.server_sender.lock().unwrap().insert(tx);
@@ -120,6 +324,13 @@ impl Dispatcher {
             .remove(connection_id);
     }
 
+    pub fn has_connection(&self, connection_id: &ConnectionID) -> bool {
+        self.connection_senders
+            .lock()
+            .unwrap()
+            .contains_key(connection_id)
+    }
+
     pub async fn publish_to_connection(
         &self,
         connection_id: &ConnectionID,
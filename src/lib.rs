@@ -1,5 +1,6 @@
 extern crate core;
 
+pub mod auth;
 pub mod connections;
 pub mod dispatcher;
 pub mod packets;
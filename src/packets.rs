@@ -1,60 +1,539 @@
-use binrw::binrw;
+use binrw::io::{Read, Seek, SeekFrom, Write};
+use binrw::{binrw, BinRead, BinResult, BinWrite, ReadOptions, VecArgs, WriteOptions};
+
+/// Default limit of the encoded size of a packet.
+pub const DEFAULT_MAX_PACKET_SIZE: usize = 1024 * 1024;
+
+pub const MAGIC_SIZE: usize = 14;
+
+/// Protocol versions supported by the server, identified by the magic string the first packet starts with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtocolVersion {
+    /// Every packet starts with the magic string, and length prefixes are uint16.
+    /// Join responses are empty, and broadcasts carry only the payload.
+    V1_0,
+    /// Every packet starts with the magic string.
+    V1_1,
+    /// Only the first packet in each direction starts with the magic string, and packets carry request ids.
+    V2_0,
+}
+
+impl ProtocolVersion {
+    pub const LATEST: ProtocolVersion = ProtocolVersion::V2_0;
+
+    pub fn magic(&self) -> &'static [u8; MAGIC_SIZE] {
+        match self {
+            ProtocolVersion::V1_0 => b"KAZAHANE 1.0.0",
+            ProtocolVersion::V1_1 => b"KAZAHANE 1.1.0",
+            ProtocolVersion::V2_0 => b"KAZAHANE 2.0.0",
+        }
+    }
+
+    pub fn from_magic(magic: &[u8]) -> Option<Self> {
+        [
+            ProtocolVersion::V1_0,
+            ProtocolVersion::V1_1,
+            ProtocolVersion::V2_0,
+        ]
+        .into_iter()
+        .find(|version| version.magic() == magic)
+    }
+
+    pub fn magic_on_every_packet(&self) -> bool {
+        matches!(self, ProtocolVersion::V1_0 | ProtocolVersion::V1_1)
+    }
+
+    /// Request ids are 0 in the versions without them.
+    pub fn has_request_ids(&self) -> bool {
+        matches!(self, ProtocolVersion::V2_0)
+    }
+
+    /// Whether length prefixes and counts are uint32 rather than uint16.
+    pub fn has_wide_sizes(&self) -> bool {
+        !matches!(self, ProtocolVersion::V1_0)
+    }
+
+    /// Whether join responses carry a status and broadcasts carry their filter and sender.
+    pub fn has_extended_bodies(&self) -> bool {
+        !matches!(self, ProtocolVersion::V1_0)
+    }
+}
+
+fn write_request_id<W: Write + Seek>(
+    request_id: &u32,
+    writer: &mut W,
+    options: &WriteOptions,
+    (version,): (ProtocolVersion,),
+) -> BinResult<()> {
+    if version.has_request_ids() {
+        request_id.write_options(writer, options, ())?;
+    }
+    Ok(())
+}
+
+/// Writes a field that `ProtocolVersion::V1_0` doesn't have.
+fn write_extended<W: Write + Seek, T: BinWrite<Args = ()>>(
+    value: &T,
+    writer: &mut W,
+    options: &WriteOptions,
+    (version,): (ProtocolVersion,),
+) -> BinResult<()> {
+    if version.has_extended_bodies() {
+        value.write_options(writer, options, ())?;
+    }
+    Ok(())
+}
+
+fn write_sized_extended<W: Write + Seek, T: BinWrite<Args = ()> + 'static>(
+    items: &Vec<T>,
+    writer: &mut W,
+    options: &WriteOptions,
+    (version,): (ProtocolVersion,),
+) -> BinResult<()> {
+    if version.has_extended_bodies() {
+        write_sized(items, writer, options, (version,))?;
+    }
+    Ok(())
+}
+
+/// `ProtocolVersion::V1_0` has no join status, so its empty join response can only mean success.
+fn write_join_status<W: Write + Seek>(
+    status_code: &JoinRoomResponseStatusCode,
+    writer: &mut W,
+    options: &WriteOptions,
+    (version,): (ProtocolVersion,),
+) -> BinResult<()> {
+    if version.has_extended_bodies() {
+        return status_code.write_options(writer, options, ());
+    }
+    if *status_code != JoinRoomResponseStatusCode::OK {
+        return Err(binrw::Error::AssertFail {
+            pos: writer.stream_position()?,
+            message: format!("{:?} can't be sent in 1.0.0", status_code),
+        });
+    }
+    Ok(())
+}
+
+/// Reads `count` items, failing before anything is allocated when the rest of the input can't hold them,
+/// since `count` comes from the peer. Only for types encoded in exactly their size in memory.
+pub(crate) fn read_counted<R: Read + Seek, T: BinRead<Args = ()>>(
+    reader: &mut R,
+    options: &ReadOptions,
+    (count,): (u32,),
+) -> BinResult<Vec<T>> {
+    let pos = reader.stream_position()?;
+    let remaining = reader.seek(SeekFrom::End(0))? - pos;
+    reader.seek(SeekFrom::Start(pos))?;
+    let count = count as usize;
+    if (count as u64).saturating_mul(std::mem::size_of::<T>() as u64) > remaining {
+        return Err(binrw::Error::AssertFail {
+            pos,
+            message: format!(
+                "{} items don't fit in the remaining {} bytes",
+                count, remaining
+            ),
+        });
+    }
+    Vec::read_options(reader, options, VecArgs { count, inner: () })
+}
+
+/// Reads a length prefix in the width of the protocol version, followed by that many items.
+fn read_sized<R: Read + Seek, T: BinRead<Args = ()>>(
+    reader: &mut R,
+    options: &ReadOptions,
+    (version,): (ProtocolVersion,),
+) -> BinResult<Vec<T>> {
+    let count = if version.has_wide_sizes() {
+        u32::read_options(reader, options, ())?
+    } else {
+        u16::read_options(reader, options, ())? as u32
+    };
+    read_counted(reader, options, (count,))
+}
+
+fn write_sized<W: Write + Seek, T: BinWrite<Args = ()> + 'static>(
+    items: &Vec<T>,
+    writer: &mut W,
+    options: &WriteOptions,
+    (version,): (ProtocolVersion,),
+) -> BinResult<()> {
+    let pos = writer.stream_position()?;
+    let too_large = || binrw::Error::AssertFail {
+        pos,
+        message: format!("{} items don't fit in the length prefix", items.len()),
+    };
+    if version.has_wide_sizes() {
+        let count = u32::try_from(items.len()).map_err(|_| too_large())?;
+        count.write_options(writer, options, ())?;
+    } else {
+        let count = u16::try_from(items.len()).map_err(|_| too_large())?;
+        count.write_options(writer, options, ())?;
+    }
+    items.write_options(writer, options, ())
+}
 
 #[binrw]
-#[brw(little, magic = b"KAZAHANE 1.0.0")]
+#[brw(little, import(protocol_version: ProtocolVersion))]
 #[derive(Debug, PartialEq)]
 pub enum Packet {
     #[brw(magic = 0x01u8)]
     HelloRequest {
-        #[br(temp)]
-        #[bw(calc = token.len() as u16)]
-        token_size: u16,
-        #[br(count = token_size)]
+        #[br(if(protocol_version.has_request_ids()))]
+        #[bw(args(protocol_version), write_with = write_request_id)]
+        request_id: u32,
+        #[br(args(protocol_version), parse_with = read_sized)]
+        #[bw(args(protocol_version), write_with = write_sized)]
         token: Vec<u8>,
     },
 
     #[brw(magic = 0x02u8)]
     HelloResponse {
+        #[br(if(protocol_version.has_request_ids()))]
+        #[bw(args(protocol_version), write_with = write_request_id)]
+        request_id: u32,
         status_code: HelloResponseStatusCode,
-        #[br(temp)]
-        #[bw(calc = message.len() as u16)]
-        message_size: u16,
-        #[br(count = message_size)]
+        #[br(args(protocol_version), parse_with = read_sized)]
+        #[bw(args(protocol_version), write_with = write_sized)]
         message: Vec<u8>,
     },
 
     #[brw(magic = 0x03u8)]
-    JoinRoomRequest { room_id: uuid::Bytes },
+    JoinRoomRequest {
+        #[br(if(protocol_version.has_request_ids()))]
+        #[bw(args(protocol_version), write_with = write_request_id)]
+        request_id: u32,
+        room_id: uuid::Bytes,
+    },
 
     #[brw(magic = 0x04u8)]
-    JoinRoomResponse {},
+    JoinRoomResponse {
+        #[br(if(protocol_version.has_request_ids()))]
+        #[bw(args(protocol_version), write_with = write_request_id)]
+        request_id: u32,
+        #[br(if(protocol_version.has_extended_bodies(), JoinRoomResponseStatusCode::OK))]
+        #[bw(args(protocol_version), write_with = write_join_status)]
+        status_code: JoinRoomResponseStatusCode,
+        #[br(if(protocol_version.has_extended_bodies()))]
+        #[br(args(protocol_version), parse_with = read_sized)]
+        #[bw(args(protocol_version), write_with = write_sized_extended)]
+        message: Vec<u8>,
+    },
 
     #[brw(magic = 0x05u8)]
     BroadcastRequest {
-        #[br(temp)]
-        #[bw(calc = payload.len() as u16)]
-        payload_size: u16,
-        #[br(count = payload_size)]
+        #[br(if(protocol_version.has_request_ids()))]
+        #[bw(args(protocol_version), write_with = write_request_id)]
+        request_id: u32,
+        /// A combination of `BROADCAST_FLAG_*`.
+        #[br(if(protocol_version.has_extended_bodies()))]
+        #[bw(args(protocol_version), write_with = write_extended)]
+        flags: u8,
+        #[br(if(protocol_version.has_extended_bodies(), BroadcastFilter::All))]
+        #[bw(args(protocol_version), write_with = write_extended)]
+        filter: BroadcastFilter,
+        /// Players the filter applies to. Ignored for `BroadcastFilter::All`.
+        #[br(if(protocol_version.has_extended_bodies()))]
+        #[br(args(protocol_version), parse_with = read_sized)]
+        #[bw(args(protocol_version), write_with = write_sized_extended)]
+        players: Vec<uuid::Bytes>,
+        #[br(args(protocol_version), parse_with = read_sized)]
+        #[bw(args(protocol_version), write_with = write_sized)]
         payload: Vec<u8>,
     },
 
     #[brw(magic = 0x06u8)]
-    RoomNotification(RoomNotification),
+    RoomNotification(#[brw(args(protocol_version))] RoomNotification),
 
     #[brw(magic = 0x07u8)]
     ServerNotification(ServerNotification),
 
+    /// Sent when a request is invalid for the current state or fails.
+    #[brw(magic = 0x08u8)]
+    Error {
+        /// 0 when the error is not caused by a request with an id.
+        #[br(if(protocol_version.has_request_ids()))]
+        #[bw(args(protocol_version), write_with = write_request_id)]
+        request_id: u32,
+        code: ErrorCode,
+        #[br(args(protocol_version), parse_with = read_sized)]
+        #[bw(args(protocol_version), write_with = write_sized)]
+        message: Vec<u8>,
+    },
+
+    #[brw(magic = 0x09u8)]
+    LeaveRoomRequest {
+        #[br(if(protocol_version.has_request_ids()))]
+        #[bw(args(protocol_version), write_with = write_request_id)]
+        request_id: u32,
+    },
+
+    #[brw(magic = 0x0Au8)]
+    LeaveRoomResponse {
+        #[br(if(protocol_version.has_request_ids()))]
+        #[bw(args(protocol_version), write_with = write_request_id)]
+        request_id: u32,
+    },
+
+    #[brw(magic = 0x0Bu8)]
+    DirectMessageRequest {
+        #[br(if(protocol_version.has_request_ids()))]
+        #[bw(args(protocol_version), write_with = write_request_id)]
+        request_id: u32,
+        #[br(args(protocol_version), parse_with = read_sized)]
+        #[bw(args(protocol_version), write_with = write_sized)]
+        targets: Vec<uuid::Bytes>,
+        #[br(args(protocol_version), parse_with = read_sized)]
+        #[bw(args(protocol_version), write_with = write_sized)]
+        payload: Vec<u8>,
+    },
+
+    #[brw(magic = 0x0Cu8)]
+    GetStateRequest {
+        #[br(if(protocol_version.has_request_ids()))]
+        #[bw(args(protocol_version), write_with = write_request_id)]
+        request_id: u32,
+        #[br(args(protocol_version), parse_with = read_sized)]
+        #[bw(args(protocol_version), write_with = write_sized)]
+        key: Vec<u8>,
+    },
+
+    /// `version` is returned for deleted keys as well. `value` is empty unless `status_code` is `OK`.
+    #[brw(magic = 0x0Du8)]
+    GetStateResponse {
+        #[br(if(protocol_version.has_request_ids()))]
+        #[bw(args(protocol_version), write_with = write_request_id)]
+        request_id: u32,
+        status_code: StateStatusCode,
+        version: u64,
+        #[br(args(protocol_version), parse_with = read_sized)]
+        #[bw(args(protocol_version), write_with = write_sized)]
+        value: Vec<u8>,
+    },
+
+    /// `expected_version` is checked only with `STATE_FLAG_EXPECT_VERSION`.
+    /// `ttl` is in milliseconds, 0 means the key expires with the rest of the room state.
+    #[brw(magic = 0x0Eu8)]
+    SetStateRequest {
+        #[br(if(protocol_version.has_request_ids()))]
+        #[bw(args(protocol_version), write_with = write_request_id)]
+        request_id: u32,
+        #[br(args(protocol_version), parse_with = read_sized)]
+        #[bw(args(protocol_version), write_with = write_sized)]
+        key: Vec<u8>,
+        flags: u8,
+        expected_version: u64,
+        ttl: u64,
+        #[br(args(protocol_version), parse_with = read_sized)]
+        #[bw(args(protocol_version), write_with = write_sized)]
+        value: Vec<u8>,
+    },
+
+    /// `version` is the new version on `OK`, and the current one on `VersionMismatch`.
+    #[brw(magic = 0x0Fu8)]
+    SetStateResponse {
+        #[br(if(protocol_version.has_request_ids()))]
+        #[bw(args(protocol_version), write_with = write_request_id)]
+        request_id: u32,
+        status_code: StateStatusCode,
+        version: u64,
+    },
+
+    /// `expected_version` is checked only with `STATE_FLAG_EXPECT_VERSION`.
+    #[brw(magic = 0x10u8)]
+    DeleteStateRequest {
+        #[br(if(protocol_version.has_request_ids()))]
+        #[bw(args(protocol_version), write_with = write_request_id)]
+        request_id: u32,
+        #[br(args(protocol_version), parse_with = read_sized)]
+        #[bw(args(protocol_version), write_with = write_sized)]
+        key: Vec<u8>,
+        flags: u8,
+        expected_version: u64,
+    },
+
+    /// `version` is the new version on `OK`, and the current one on `VersionMismatch`.
+    #[brw(magic = 0x11u8)]
+    DeleteStateResponse {
+        #[br(if(protocol_version.has_request_ids()))]
+        #[bw(args(protocol_version), write_with = write_request_id)]
+        request_id: u32,
+        status_code: StateStatusCode,
+        version: u64,
+    },
+
+    /// Adds `delta` to a value stored as a decimal string. A missing key counts as 0.
+    #[brw(magic = 0x12u8)]
+    IncrementStateRequest {
+        #[br(if(protocol_version.has_request_ids()))]
+        #[bw(args(protocol_version), write_with = write_request_id)]
+        request_id: u32,
+        #[br(args(protocol_version), parse_with = read_sized)]
+        #[bw(args(protocol_version), write_with = write_sized)]
+        key: Vec<u8>,
+        delta: i64,
+    },
+
+    /// `value` and `version` are the ones after the increment when `status_code` is `OK`.
+    #[brw(magic = 0x13u8)]
+    IncrementStateResponse {
+        #[br(if(protocol_version.has_request_ids()))]
+        #[bw(args(protocol_version), write_with = write_request_id)]
+        request_id: u32,
+        status_code: StateStatusCode,
+        version: u64,
+        value: i64,
+    },
+
+    #[brw(magic = 0x14u8)]
+    SetStateIfAbsentRequest {
+        #[br(if(protocol_version.has_request_ids()))]
+        #[bw(args(protocol_version), write_with = write_request_id)]
+        request_id: u32,
+        #[br(args(protocol_version), parse_with = read_sized)]
+        #[bw(args(protocol_version), write_with = write_sized)]
+        key: Vec<u8>,
+        #[br(args(protocol_version), parse_with = read_sized)]
+        #[bw(args(protocol_version), write_with = write_sized)]
+        value: Vec<u8>,
+    },
+
+    /// `version` is the new version when `status_code` is `OK`.
+    #[brw(magic = 0x15u8)]
+    SetStateIfAbsentResponse {
+        #[br(if(protocol_version.has_request_ids()))]
+        #[bw(args(protocol_version), write_with = write_request_id)]
+        request_id: u32,
+        status_code: StateStatusCode,
+        version: u64,
+    },
+
+    /// With `SUBSCRIBE_STATE_FLAG_PREFIX`, `key` is a prefix of the keys (empty for every key).
+    #[brw(magic = 0x16u8)]
+    SubscribeStateRequest {
+        #[br(if(protocol_version.has_request_ids()))]
+        #[bw(args(protocol_version), write_with = write_request_id)]
+        request_id: u32,
+        flags: u8,
+        #[br(args(protocol_version), parse_with = read_sized)]
+        #[bw(args(protocol_version), write_with = write_sized)]
+        key: Vec<u8>,
+    },
+
+    /// Followed by a state changed notification for each existing key of the subscription.
+    #[brw(magic = 0x17u8)]
+    SubscribeStateResponse {
+        #[br(if(protocol_version.has_request_ids()))]
+        #[bw(args(protocol_version), write_with = write_request_id)]
+        request_id: u32,
+        status_code: StateStatusCode,
+    },
+
+    /// `flags` and `key` must be the same as the ones subscribed with.
+    #[brw(magic = 0x18u8)]
+    UnsubscribeStateRequest {
+        #[br(if(protocol_version.has_request_ids()))]
+        #[bw(args(protocol_version), write_with = write_request_id)]
+        request_id: u32,
+        flags: u8,
+        #[br(args(protocol_version), parse_with = read_sized)]
+        #[bw(args(protocol_version), write_with = write_sized)]
+        key: Vec<u8>,
+    },
+
+    #[brw(magic = 0x19u8)]
+    UnsubscribeStateResponse {
+        #[br(if(protocol_version.has_request_ids()))]
+        #[bw(args(protocol_version), write_with = write_request_id)]
+        request_id: u32,
+        status_code: StateStatusCode,
+    },
+
     #[brw(magic = 0xDEu8)]
-    TestCountUp {},
+    TestCountUp {
+        #[br(if(protocol_version.has_request_ids()))]
+        #[bw(args(protocol_version), write_with = write_request_id)]
+        request_id: u32,
+    },
 
     #[brw(magic = 0xDFu8)]
-    TestCountUpResponse { counter: u64 },
+    TestCountUpResponse {
+        #[br(if(protocol_version.has_request_ids()))]
+        #[bw(args(protocol_version), write_with = write_request_id)]
+        request_id: u32,
+        counter: u64,
+    },
+}
+
+impl Packet {
+    /// The id chosen by the client to match responses with requests. 0 means none.
+    /// Only `ProtocolVersion::V2_0` carries it on the wire.
+    pub fn request_id(&self) -> u32 {
+        match self {
+            Packet::HelloRequest { request_id, .. }
+            | Packet::JoinRoomRequest { request_id, .. }
+            | Packet::BroadcastRequest { request_id, .. }
+            | Packet::LeaveRoomRequest { request_id }
+            | Packet::DirectMessageRequest { request_id, .. }
+            | Packet::GetStateRequest { request_id, .. }
+            | Packet::SetStateRequest { request_id, .. }
+            | Packet::DeleteStateRequest { request_id, .. }
+            | Packet::IncrementStateRequest { request_id, .. }
+            | Packet::SetStateIfAbsentRequest { request_id, .. }
+            | Packet::SubscribeStateRequest { request_id, .. }
+            | Packet::UnsubscribeStateRequest { request_id, .. }
+            | Packet::TestCountUp { request_id } => *request_id,
+            _ => 0,
+        }
+    }
+
+    /// `ProtocolVersion::V1_0` has none of the packets added later.
+    pub fn is_supported_by(&self, version: ProtocolVersion) -> bool {
+        if version.has_extended_bodies() {
+            return true;
+        }
+        matches!(
+            self,
+            Packet::HelloRequest { .. }
+                | Packet::HelloResponse { .. }
+                | Packet::JoinRoomRequest { .. }
+                | Packet::JoinRoomResponse { .. }
+                | Packet::BroadcastRequest { .. }
+                | Packet::RoomNotification(
+                    RoomNotification::PlayerJoined { .. }
+                        | RoomNotification::PlayerLeft { .. }
+                        | RoomNotification::Broadcast { .. }
+                )
+                | Packet::ServerNotification(_)
+                | Packet::TestCountUp { .. }
+                | Packet::TestCountUpResponse { .. }
+        )
+    }
+}
+
+/// Also delivers the broadcast to the sender itself.
+pub const BROADCAST_FLAG_ECHO: u8 = 0x01;
+
+/// Makes a state write fail with `VersionMismatch` unless the key is at `expected_version`.
+pub const STATE_FLAG_EXPECT_VERSION: u8 = 0x01;
+
+/// Subscribes to the keys starting with the given key instead of the key itself.
+pub const SUBSCRIBE_STATE_FLAG_PREFIX: u8 = 0x01;
+
+#[binrw]
+#[brw(repr = u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BroadcastFilter {
+    All = 0x00,
+    Include = 0x01,
+    Exclude = 0x02,
 }
 
 #[binrw]
-#[brw(little)]
+#[brw(little, import(protocol_version: ProtocolVersion))]
 #[derive(Debug, PartialEq)]
 pub enum RoomNotification {
+    /// Also sent to a new member once for each player already in the room, after `JoinRoomResponse`.
     #[brw(magic = 0x01u8)]
     PlayerJoined { player: uuid::Bytes },
 
@@ -63,12 +542,54 @@ pub enum RoomNotification {
 
     #[brw(magic = 0x03u8)]
     Broadcast {
-        #[br(temp)]
-        #[bw(calc = payload.len() as u16)]
-        payload_size: u16,
-        #[br(count = payload_size)]
+        #[br(if(protocol_version.has_extended_bodies()))]
+        #[bw(args(protocol_version), write_with = write_extended)]
+        sender: uuid::Bytes,
+        /// Empty when the sender is not authenticated as a user.
+        #[br(if(protocol_version.has_extended_bodies()))]
+        #[br(args(protocol_version), parse_with = read_sized)]
+        #[bw(args(protocol_version), write_with = write_sized_extended)]
+        user_id: Vec<u8>,
+        #[br(if(protocol_version.has_extended_bodies()))]
+        #[bw(args(protocol_version), write_with = write_extended)]
+        timestamp: u64,
+        #[br(args(protocol_version), parse_with = read_sized)]
+        #[bw(args(protocol_version), write_with = write_sized)]
+        payload: Vec<u8>,
+    },
+
+    #[brw(magic = 0x04u8)]
+    DirectMessage {
+        sender: uuid::Bytes,
+        #[br(args(protocol_version), parse_with = read_sized)]
+        #[bw(args(protocol_version), write_with = write_sized)]
+        user_id: Vec<u8>,
+        timestamp: u64,
+        #[br(args(protocol_version), parse_with = read_sized)]
+        #[bw(args(protocol_version), write_with = write_sized)]
         payload: Vec<u8>,
     },
+
+    /// A room state key the member subscribes to was set by another member, or sent as a snapshot on subscribe.
+    #[brw(magic = 0x05u8)]
+    StateChanged {
+        #[br(args(protocol_version), parse_with = read_sized)]
+        #[bw(args(protocol_version), write_with = write_sized)]
+        key: Vec<u8>,
+        version: u64,
+        #[br(args(protocol_version), parse_with = read_sized)]
+        #[bw(args(protocol_version), write_with = write_sized)]
+        value: Vec<u8>,
+    },
+
+    /// A room state key the member subscribes to was deleted by another member.
+    #[brw(magic = 0x06u8)]
+    StateDeleted {
+        #[br(args(protocol_version), parse_with = read_sized)]
+        #[bw(args(protocol_version), write_with = write_sized)]
+        key: Vec<u8>,
+        version: u64,
+    },
 }
 
 #[binrw]
@@ -88,32 +609,116 @@ pub enum HelloResponseStatusCode {
     Denied = 0x02,
 }
 
+#[binrw]
+#[brw(repr = u8)]
+#[derive(Debug, PartialEq, Eq)]
+pub enum JoinRoomResponseStatusCode {
+    Unknown = 0x00,
+    OK = 0x01,
+    RoomFull = 0x02,
+    /// The join was refused for another reason, given in the message.
+    Rejected = 0x03,
+}
+
+#[binrw]
+#[brw(repr = u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StateStatusCode {
+    Unknown = 0x00,
+    OK = 0x01,
+    NotFound = 0x02,
+    /// The key is empty, not UTF-8 or longer than the limit.
+    InvalidKey = 0x03,
+    ValueTooLarge = 0x04,
+    /// The current value is not a decimal integer, or the result overflows.
+    NotAnInteger = 0x05,
+    VersionMismatch = 0x06,
+    AlreadyExists = 0x07,
+    /// The TTL is longer than the limit.
+    InvalidTtl = 0x08,
+}
+
+#[binrw]
+#[brw(repr = u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    Unknown = 0x00,
+    NotAuthenticated = 0x01,
+    AlreadyAuthenticated = 0x02,
+    HelloTimeout = 0x03,
+    PacketTooLarge = 0x04,
+    InvalidPacket = 0x05,
+    InvalidState = 0x06,
+    OperationFailed = 0x07,
+    /// The connection is closed after this error.
+    UnsupportedVersion = 0x08,
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::packets::Packet;
+    use crate::packets::{Packet, ProtocolVersion};
     use binrw::io::Cursor;
-    use binrw::{BinReaderExt, BinWrite};
+    use binrw::{BinRead, BinWrite};
 
     #[test]
     fn read_packet() {
-        let p: Packet = Cursor::new(b"KAZAHANE 1.0.0\x01\x05\x00hello")
-            .read_le()
-            .unwrap();
+        let p = Packet::read_args(
+            &mut Cursor::new(b"\x01\x07\x00\x00\x00\x05\x00\x00\x00hello"),
+            (ProtocolVersion::V2_0,),
+        )
+        .unwrap();
         assert_eq!(
             p,
             Packet::HelloRequest {
+                request_id: 7,
                 token: b"hello".to_vec()
             }
         );
     }
 
+    #[test]
+    fn read_packet_with_too_large_count() {
+        // The players and payload sizes claim far more than the packet has.
+        let read =
+            |data: &[u8]| Packet::read_args(&mut Cursor::new(data), (ProtocolVersion::V2_0,));
+        assert!(read(b"\x05\x01\x00\x00\x00\x00\x01\xFF\xFF\xFF\xFF").is_err());
+        assert!(read(b"\x05\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00\xFF\xFF\xFF\xFF").is_err());
+    }
+
     #[test]
     fn write_packet() {
         let mut writer = Cursor::new(Vec::new());
         let p = Packet::HelloRequest {
+            request_id: 7,
+            token: b"world".to_vec(),
+        };
+        p.write_with_args(&mut writer, (ProtocolVersion::V2_0,))
+            .unwrap();
+        assert_eq!(
+            &writer.into_inner()[..],
+            b"\x01\x07\x00\x00\x00\x05\x00\x00\x00world"
+        );
+    }
+
+    #[test]
+    fn v1_packet_without_request_id() {
+        let mut writer = Cursor::new(Vec::new());
+        let p = Packet::HelloRequest {
+            request_id: 7,
             token: b"world".to_vec(),
         };
-        p.write_to(&mut writer).unwrap();
-        assert_eq!(&writer.into_inner()[..], b"KAZAHANE 1.0.0\x01\x05\x00world");
+        p.write_with_args(&mut writer, (ProtocolVersion::V1_1,))
+            .unwrap();
+        let data = writer.into_inner();
+        assert_eq!(&data[..], b"\x01\x05\x00\x00\x00world");
+
+        let p = Packet::read_args(&mut Cursor::new(data), (ProtocolVersion::V1_1,)).unwrap();
+        assert_eq!(
+            p,
+            Packet::HelloRequest {
+                request_id: 0,
+                token: b"world".to_vec()
+            }
+        );
     }
 }
@@ -1,26 +1,233 @@
+pub(crate) mod memory;
 pub mod redis;
+pub(crate) mod redis_streams;
 
+use crate::packets::{read_counted, BroadcastFilter};
 use anyhow::Context;
 use async_trait::async_trait;
 use binrw::{binrw, BinRead, BinWrite};
 use bytes::Bytes;
 use std::fmt::Debug;
 use std::io::Cursor;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
 pub(crate) type PubSubTopic = String;
 
+/// Number of received messages a subscription of the Redis backends can have waiting to be read.
+pub(crate) const SUBSCRIPTION_QUEUE_SIZE: usize = 1024;
+
+/// What a subscription receives the messages of.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) enum PubSubChannel {
+    Topic(PubSubTopic),
+    /// Every topic matching a glob-style pattern as in Redis PSUBSCRIBE:
+    /// `*`, `?`, `[...]` and `\` to escape them.
+    Pattern(String),
+}
+
+impl PubSubChannel {
+    pub fn matches(&self, topic: &str) -> bool {
+        match self {
+            PubSubChannel::Topic(t) => t == topic,
+            PubSubChannel::Pattern(pattern) => glob_matches(pattern.as_bytes(), topic.as_bytes()),
+        }
+    }
+}
+
+/// A message received by a subscription, with the topic it was published to.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ReceivedMessage {
+    pub topic: PubSubTopic,
+    pub data: Bytes,
+}
+
 #[async_trait]
-pub(crate) trait PubSub {
+pub(crate) trait PubSub: Send {
     async fn publish(&mut self, topic: PubSubTopic, msg: PubSubMessage) -> crate::Result<()>;
+
+    /// Subscribes to all of `channels` on one subscription.
+    async fn subscribe_channels(
+        &mut self,
+        channels: Vec<PubSubChannel>,
+    ) -> crate::Result<Box<dyn Subscription + Send>>;
+
     async fn subscribe(
         &mut self,
         topic: PubSubTopic,
-    ) -> crate::Result<Box<dyn Subscription + Send>>;
+    ) -> crate::Result<Box<dyn Subscription + Send>> {
+        self.subscribe_channels(vec![PubSubChannel::Topic(topic)])
+            .await
+    }
 }
 
 #[async_trait]
 pub(crate) trait Subscription {
-    async fn next_message(&mut self) -> crate::Result<Option<Bytes>>;
+    /// Returns the messages of every channel of the subscription. A message published to a topic
+    /// that several channels match is returned once per channel, as Redis does.
+    /// Returns `None` once every channel is unsubscribed and the received messages are returned.
+    async fn next_message(&mut self) -> crate::Result<Option<ReceivedMessage>>;
+
+    /// Adds `channel` to the subscription. Subscribing to a channel twice has no effect.
+    async fn subscribe(&mut self, channel: PubSubChannel) -> crate::Result<()>;
+
+    /// Stops receiving the messages of `channel`, though messages already received are still
+    /// returned. Unsubscribing from a channel that is not subscribed has no effect.
+    async fn unsubscribe(&mut self, channel: &PubSubChannel) -> crate::Result<()>;
+}
+
+/// Identifies a subscription among the ones sharing a connection or a process.
+pub(crate) fn next_subscription_id() -> u64 {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+    NEXT_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Matches `topic` against `pattern` the same way as Redis.
+fn glob_matches(pattern: &[u8], topic: &[u8]) -> bool {
+    match pattern {
+        [] => topic.is_empty(),
+        [b'*', rest @ ..] => (0..=topic.len()).any(|i| glob_matches(rest, &topic[i..])),
+        [b'?', rest @ ..] => match topic {
+            [_, topic @ ..] => glob_matches(rest, topic),
+            [] => false,
+        },
+        [b'[', rest @ ..] => match topic {
+            [c, topic @ ..] => {
+                let (matched, rest) = match_class(rest, *c);
+                matched && glob_matches(rest, topic)
+            }
+            [] => false,
+        },
+        [b'\\', c, rest @ ..] | [c, rest @ ..] => match topic {
+            [t, topic @ ..] => t == c && glob_matches(rest, topic),
+            [] => false,
+        },
+    }
+}
+
+/// Matches `c` against the character class that starts after `[`, and returns the rest of
+/// the pattern after the closing `]`.
+fn match_class(pattern: &[u8], c: u8) -> (bool, &[u8]) {
+    let (negate, mut pattern) = match pattern {
+        [b'^', rest @ ..] => (true, rest),
+        _ => (false, pattern),
+    };
+    let mut matched = false;
+    loop {
+        match pattern {
+            // An unclosed class ends with the pattern.
+            [] => break,
+            [b']', rest @ ..] => {
+                pattern = rest;
+                break;
+            }
+            [b'\\', escaped, rest @ ..] => {
+                matched |= *escaped == c;
+                pattern = rest;
+            }
+            [start, b'-', end, rest @ ..] => {
+                let (low, high) = if start <= end {
+                    (*start, *end)
+                } else {
+                    (*end, *start)
+                };
+                matched |= (low..=high).contains(&c);
+                pattern = rest;
+            }
+            [other, rest @ ..] => {
+                matched |= *other == c;
+                pattern = rest;
+            }
+        }
+    }
+    (matched != negate, pattern)
+}
+
+/// Tracks how many subscriptions, or connections shared by subscriptions,
+/// have lost their connection and are reconnecting.
+#[derive(Debug, Clone, Default)]
+pub struct PubSubHealth {
+    reconnecting: Arc<AtomicUsize>,
+}
+
+impl PubSubHealth {
+    pub fn is_healthy(&self) -> bool {
+        self.reconnecting_subscriptions() == 0
+    }
+
+    pub fn reconnecting_subscriptions(&self) -> usize {
+        self.reconnecting.load(Ordering::Relaxed)
+    }
+}
+
+/// The health of a single subscription, counted in `PubSubHealth` until it is dropped.
+#[derive(Debug)]
+pub(crate) struct SubscriptionHealth {
+    health: PubSubHealth,
+    reconnecting: bool,
+}
+
+impl SubscriptionHealth {
+    pub fn new(health: PubSubHealth) -> Self {
+        Self {
+            health,
+            reconnecting: false,
+        }
+    }
+
+    pub fn is_reconnecting(&self) -> bool {
+        self.reconnecting
+    }
+
+    pub fn set_reconnecting(&mut self, reconnecting: bool) {
+        if self.reconnecting == reconnecting {
+            return;
+        }
+        self.reconnecting = reconnecting;
+        if reconnecting {
+            self.health.reconnecting.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.health.reconnecting.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Drop for SubscriptionHealth {
+    fn drop(&mut self) {
+        self.set_reconnecting(false);
+    }
+}
+
+/// Delays between reconnect attempts, doubled on each attempt up to a limit.
+#[derive(Debug)]
+pub(crate) struct Backoff {
+    next: Duration,
+}
+
+impl Backoff {
+    const INITIAL: Duration = Duration::from_millis(100);
+    const MAX: Duration = Duration::from_secs(5);
+
+    pub fn new() -> Self {
+        Self {
+            next: Self::INITIAL,
+        }
+    }
+
+    pub async fn wait(&mut self) {
+        tokio::time::sleep(self.next_delay()).await;
+    }
+
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.next;
+        self.next = (self.next * 2).min(Self::MAX);
+        delay
+    }
+
+    pub fn reset(&mut self) {
+        self.next = Self::INITIAL;
+    }
 }
 
 #[binrw]
@@ -31,12 +238,98 @@ pub enum PubSubMessage {
     Broadcast {
         sender_server: uuid::Bytes,
         sender: uuid::Bytes,
+        filter: BroadcastFilter,
+        #[br(temp)]
+        #[bw(calc = players.len() as u32)]
+        players_size: u32,
+        #[br(args(players_size), parse_with = read_counted)]
+        players: Vec<uuid::Bytes>,
+        #[br(temp)]
+        #[bw(calc = user_id.len() as u32)]
+        user_id_size: u32,
+        #[br(args(user_id_size), parse_with = read_counted)]
+        user_id: Vec<u8>,
+        timestamp: u64,
+        #[br(temp)]
+        #[bw(calc = payload.len() as u32)]
+        payload_size: u32,
+        #[br(args(payload_size), parse_with = read_counted)]
+        payload: Vec<u8>,
+    },
+
+    #[brw(magic = 0x02u8)]
+    PlayerJoined {
+        sender_server: uuid::Bytes,
+        player: uuid::Bytes,
+    },
+
+    #[brw(magic = 0x03u8)]
+    PlayerLeft {
+        sender_server: uuid::Bytes,
+        player: uuid::Bytes,
+    },
+
+    #[brw(magic = 0x05u8)]
+    StateChanged {
+        sender_server: uuid::Bytes,
+        #[br(temp)]
+        #[bw(calc = key.len() as u32)]
+        key_size: u32,
+        #[br(args(key_size), parse_with = read_counted)]
+        key: Vec<u8>,
+        version: u64,
+        #[br(temp)]
+        #[bw(calc = value.len() as u32)]
+        value_size: u32,
+        #[br(args(value_size), parse_with = read_counted)]
+        value: Vec<u8>,
+    },
+
+    #[brw(magic = 0x06u8)]
+    StateDeleted {
+        sender_server: uuid::Bytes,
+        #[br(temp)]
+        #[bw(calc = key.len() as u32)]
+        key_size: u32,
+        #[br(args(key_size), parse_with = read_counted)]
+        key: Vec<u8>,
+        version: u64,
+    },
+
+    /// Carries only the targets that are not members on the sender server.
+    #[brw(magic = 0x04u8)]
+    DirectMessage {
+        sender_server: uuid::Bytes,
+        sender: uuid::Bytes,
+        #[br(temp)]
+        #[bw(calc = targets.len() as u32)]
+        targets_size: u32,
+        #[br(args(targets_size), parse_with = read_counted)]
+        targets: Vec<uuid::Bytes>,
+        #[br(temp)]
+        #[bw(calc = user_id.len() as u32)]
+        user_id_size: u32,
+        #[br(args(user_id_size), parse_with = read_counted)]
+        user_id: Vec<u8>,
+        timestamp: u64,
         #[br(temp)]
-        #[bw(calc = payload.len() as u16)]
-        payload_size: u16,
-        #[br(count = payload_size)]
+        #[bw(calc = payload.len() as u32)]
+        payload_size: u32,
+        #[br(args(payload_size), parse_with = read_counted)]
         payload: Vec<u8>,
     },
+
+    /// Sent in reply to `PlayerJoined` with the members on the sender server, for the new player only.
+    #[brw(magic = 0x07u8)]
+    Members {
+        sender_server: uuid::Bytes,
+        player: uuid::Bytes,
+        #[br(temp)]
+        #[bw(calc = members.len() as u32)]
+        members_size: u32,
+        #[br(args(members_size), parse_with = read_counted)]
+        members: Vec<uuid::Bytes>,
+    },
 }
 
 impl PubSubMessage {
@@ -51,3 +344,30 @@ impl PubSubMessage {
         Bytes::from(writer.into_inner())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::pubsub::PubSubChannel;
+
+    #[test]
+    fn pattern_matches() {
+        let matches =
+            |pattern: &str, topic: &str| PubSubChannel::Pattern(pattern.to_string()).matches(topic);
+        assert!(matches("*", ""));
+        assert!(matches("rooms/*", "rooms/abc"));
+        assert!(!matches("rooms/*", "cluster/abc"));
+        assert!(matches("*/announce", "cluster/announce"));
+        assert!(matches("h?llo", "hello"));
+        assert!(!matches("h?llo", "hllo"));
+        assert!(matches("h[ae]llo", "hallo"));
+        assert!(!matches("h[ae]llo", "hillo"));
+        assert!(matches("h[^e]llo", "hallo"));
+        assert!(!matches("h[^e]llo", "hello"));
+        assert!(matches("h[a-c]llo", "hbllo"));
+        assert!(matches("h[c-a]llo", "hbllo"));
+        assert!(!matches("h[a-c]llo", "hdllo"));
+        assert!(matches("h\\*llo", "h*llo"));
+        assert!(!matches("h\\*llo", "hello"));
+        assert!(!PubSubChannel::Topic("rooms/*".to_string()).matches("rooms/abc"));
+    }
+}
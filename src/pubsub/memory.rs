@@ -0,0 +1,195 @@
+use crate::pubsub::{
+    next_subscription_id, PubSub, PubSubChannel, PubSubMessage, PubSubTopic, ReceivedMessage,
+    Subscription,
+};
+use async_trait::async_trait;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+use tracing::debug;
+
+type Subscribers = Vec<(u64, mpsc::UnboundedSender<ReceivedMessage>)>;
+
+/// Delivers messages to the subscriptions in this process.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct MemoryPubSub {
+    channels: Arc<Mutex<HashMap<PubSubChannel, Subscribers>>>,
+}
+
+impl MemoryPubSub {
+    fn remove_subscriber(&self, channel: &PubSubChannel, id: u64) {
+        let mut channels = self.channels.lock().unwrap();
+        if let Some(subscribers) = channels.get_mut(channel) {
+            subscribers.retain(|(subscriber_id, _)| *subscriber_id != id);
+            if subscribers.is_empty() {
+                channels.remove(channel);
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl PubSub for MemoryPubSub {
+    async fn publish(&mut self, topic: PubSubTopic, msg: PubSubMessage) -> crate::Result<()> {
+        debug!("publish to pubsub (topic: {}, data: {:?})", topic, msg);
+        let msg = ReceivedMessage {
+            topic,
+            data: msg.to_bytes(),
+        };
+        let mut channels = self.channels.lock().unwrap();
+        channels.retain(|channel, subscribers| {
+            if channel.matches(&msg.topic) {
+                // Subscriptions dropped without unsubscribing are removed here.
+                subscribers.retain(|(_, subscriber)| subscriber.send(msg.clone()).is_ok());
+            }
+            !subscribers.is_empty()
+        });
+        Ok(())
+    }
+
+    async fn subscribe_channels(
+        &mut self,
+        channels: Vec<PubSubChannel>,
+    ) -> crate::Result<Box<dyn Subscription + Send>> {
+        debug!("start subscribe from pubsub (channels: {:?})", channels);
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let mut sub = MemorySubscription {
+            id: next_subscription_id(),
+            pubsub: self.clone(),
+            channels: HashSet::new(),
+            sender,
+            receiver,
+        };
+        for channel in channels {
+            sub.subscribe(channel).await?;
+        }
+        Ok(Box::new(sub))
+    }
+}
+
+struct MemorySubscription {
+    id: u64,
+    pubsub: MemoryPubSub,
+    channels: HashSet<PubSubChannel>,
+    sender: mpsc::UnboundedSender<ReceivedMessage>,
+    receiver: mpsc::UnboundedReceiver<ReceivedMessage>,
+}
+
+#[async_trait]
+impl Subscription for MemorySubscription {
+    async fn next_message(&mut self) -> crate::Result<Option<ReceivedMessage>> {
+        if self.channels.is_empty() {
+            return Ok(self.receiver.try_recv().ok());
+        }
+        Ok(self.receiver.recv().await)
+    }
+
+    async fn subscribe(&mut self, channel: PubSubChannel) -> crate::Result<()> {
+        if self.channels.insert(channel.clone()) {
+            let mut channels = self.pubsub.channels.lock().unwrap();
+            channels
+                .entry(channel)
+                .or_default()
+                .push((self.id, self.sender.clone()));
+        }
+        Ok(())
+    }
+
+    async fn unsubscribe(&mut self, channel: &PubSubChannel) -> crate::Result<()> {
+        if self.channels.remove(channel) {
+            self.pubsub.remove_subscriber(channel, self.id);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for MemorySubscription {
+    fn drop(&mut self) {
+        for channel in &self.channels {
+            self.pubsub.remove_subscriber(channel, self.id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pubsub::memory::MemoryPubSub;
+    use crate::pubsub::{PubSub, PubSubChannel, PubSubMessage, Subscription};
+    use crate::types::{ConnectionID, ServerID};
+
+    fn msg(player: u128) -> PubSubMessage {
+        PubSubMessage::PlayerJoined {
+            sender_server: ServerID::nil().into_bytes(),
+            player: ConnectionID::from_u128(player).into_bytes(),
+        }
+    }
+
+    async fn assert_next(sub: &mut (dyn Subscription + Send), topic: &str, player: u128) {
+        let received = sub.next_message().await.unwrap().unwrap();
+        assert_eq!(received.topic, topic);
+        assert_eq!(
+            PubSubMessage::from_bytes(received.data).unwrap(),
+            msg(player)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_memory() {
+        let mut pubsub = MemoryPubSub::default();
+        let mut sub1 = pubsub.subscribe("test".to_string()).await.unwrap();
+        let mut sub2 = pubsub.clone().subscribe("test".to_string()).await.unwrap();
+        pubsub.publish("other".to_string(), msg(0)).await.unwrap();
+        pubsub.publish("test".to_string(), msg(1)).await.unwrap();
+        for sub in [&mut sub1, &mut sub2] {
+            assert_next(sub.as_mut(), "test", 1).await;
+        }
+
+        drop(sub1);
+        pubsub.publish("test".to_string(), msg(2)).await.unwrap();
+        assert_next(sub2.as_mut(), "test", 2).await;
+        assert_eq!(
+            pubsub.channels.lock().unwrap()[&PubSubChannel::Topic("test".to_string())].len(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn channels() {
+        let mut pubsub = MemoryPubSub::default();
+        let mut sub = pubsub
+            .subscribe_channels(vec![
+                PubSubChannel::Topic("cluster".to_string()),
+                PubSubChannel::Pattern("rooms/*".to_string()),
+            ])
+            .await
+            .unwrap();
+        pubsub.publish("cluster".to_string(), msg(1)).await.unwrap();
+        pubsub.publish("rooms/a".to_string(), msg(2)).await.unwrap();
+        pubsub.publish("other".to_string(), msg(3)).await.unwrap();
+        assert_next(sub.as_mut(), "cluster", 1).await;
+        assert_next(sub.as_mut(), "rooms/a", 2).await;
+
+        // Messages received before unsubscribing are still returned.
+        pubsub.publish("rooms/b".to_string(), msg(4)).await.unwrap();
+        let rooms = PubSubChannel::Pattern("rooms/*".to_string());
+        sub.unsubscribe(&rooms).await.unwrap();
+        pubsub.publish("rooms/c".to_string(), msg(5)).await.unwrap();
+        pubsub.publish("cluster".to_string(), msg(6)).await.unwrap();
+        assert_next(sub.as_mut(), "rooms/b", 4).await;
+        assert_next(sub.as_mut(), "cluster", 6).await;
+
+        sub.subscribe(PubSubChannel::Topic("other".to_string()))
+            .await
+            .unwrap();
+        sub.unsubscribe(&PubSubChannel::Topic("cluster".to_string()))
+            .await
+            .unwrap();
+        pubsub.publish("other".to_string(), msg(7)).await.unwrap();
+        sub.unsubscribe(&PubSubChannel::Topic("other".to_string()))
+            .await
+            .unwrap();
+        assert_next(sub.as_mut(), "other", 7).await;
+        assert!(sub.next_message().await.unwrap().is_none());
+        assert!(pubsub.channels.lock().unwrap().is_empty());
+    }
+}
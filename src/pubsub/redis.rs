@@ -1,20 +1,32 @@
-use crate::pubsub::{PubSub, PubSubMessage, PubSubTopic, Subscription};
-use anyhow::Context;
+use crate::pubsub::{
+    next_subscription_id, Backoff, PubSub, PubSubChannel, PubSubHealth, PubSubMessage, PubSubTopic,
+    ReceivedMessage, Subscription, SubscriptionHealth, SUBSCRIPTION_QUEUE_SIZE,
+};
+use anyhow::{anyhow, Context};
 use async_trait::async_trait;
 use bytes::Bytes;
+use futures::stream::BoxStream;
 use futures::StreamExt;
 use redis::AsyncCommands;
-use tracing::debug;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context as TaskContext, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::Instant;
+use tracing::{debug, info, warn};
 
 pub(crate) struct RedisPubSub {
-    client: redis::Client,
+    subscriber: RedisSubscriber,
     pub_conn: redis::aio::ConnectionManager,
 }
 
 impl RedisPubSub {
-    pub fn new(client: redis::Client, conn: redis::aio::ConnectionManager) -> Self {
+    pub fn new(subscriber: RedisSubscriber, conn: redis::aio::ConnectionManager) -> Self {
         Self {
-            client,
+            subscriber,
             pub_conn: conn,
         }
     }
@@ -30,71 +42,637 @@ impl PubSub for RedisPubSub {
             .context("failed to publish")
     }
 
-    async fn subscribe(
+    async fn subscribe_channels(
         &mut self,
-        topic: PubSubTopic,
+        channels: Vec<PubSubChannel>,
     ) -> crate::Result<Box<dyn Subscription + Send>> {
-        debug!("start subscribe from pubsub (topic: {})", topic);
-        let conn = self
-            .client
-            .get_async_connection()
-            .await
-            .context("failed to create subscribe connection")?;
-        let mut pubsub = conn.into_pubsub();
-        pubsub
-            .subscribe(topic)
-            .await
-            .context("failed to subscribe")?;
-        Ok(Box::new(RedisSubscription { pubsub }))
+        debug!("start subscribe from pubsub (channels: {:?})", channels);
+        let mut sub = self.subscriber.subscription();
+        for channel in channels {
+            sub.subscribe(channel).await?;
+        }
+        Ok(Box::new(sub))
+    }
+}
+
+/// Shares one Redis connection between the subscriptions of a server.
+/// A background task subscribes to the channels on the connection and routes messages to
+/// the subscriptions. When the connection is lost, it reconnects and subscribes again.
+/// Messages published while it is disconnected are not delivered, and neither are messages to a
+/// subscription that already has `SUBSCRIPTION_QUEUE_SIZE` messages waiting to be read.
+#[derive(Debug, Clone)]
+pub(crate) struct RedisSubscriber {
+    commands: mpsc::UnboundedSender<Command>,
+}
+
+#[derive(Debug)]
+enum Command {
+    Subscribe {
+        channel: PubSubChannel,
+        id: u64,
+        sender: mpsc::Sender<ReceivedMessage>,
+        subscribed: oneshot::Sender<()>,
+    },
+    Unsubscribe {
+        channel: PubSubChannel,
+        id: u64,
+    },
+}
+
+impl RedisSubscriber {
+    /// The connection is reported to `health` while it is reconnecting.
+    /// The task stops when the subscriber and all its subscriptions are dropped.
+    pub fn new(client: redis::Client, health: PubSubHealth) -> Self {
+        let (commands, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(subscriber_task(
+            client,
+            receiver,
+            SubscriptionHealth::new(health),
+        ));
+        Self { commands }
+    }
+
+    /// Returns a subscription without any channels.
+    fn subscription(&self) -> RedisSubscription {
+        let (sender, receiver) = mpsc::channel(SUBSCRIPTION_QUEUE_SIZE);
+        RedisSubscription {
+            id: next_subscription_id(),
+            channels: HashSet::new(),
+            sender,
+            receiver,
+            commands: self.commands.clone(),
+        }
     }
 }
 
 struct RedisSubscription {
-    pubsub: redis::aio::PubSub,
+    id: u64,
+    channels: HashSet<PubSubChannel>,
+    sender: mpsc::Sender<ReceivedMessage>,
+    receiver: mpsc::Receiver<ReceivedMessage>,
+    commands: mpsc::UnboundedSender<Command>,
 }
 
 #[async_trait]
 impl Subscription for RedisSubscription {
-    async fn next_message(&mut self) -> crate::Result<Option<Bytes>> {
-        let msg = self.pubsub.on_message().next().await;
-        match msg {
-            Some(msg) => {
-                let payload: Vec<u8> = msg.get_payload().context("failed to get payload")?;
-                Ok(Some(Bytes::from(payload)))
+    async fn next_message(&mut self) -> crate::Result<Option<ReceivedMessage>> {
+        if self.channels.is_empty() {
+            return Ok(self.receiver.try_recv().ok());
+        }
+        Ok(self.receiver.recv().await)
+    }
+
+    /// Returns once Redis has confirmed the subscription, or right away while disconnected.
+    async fn subscribe(&mut self, channel: PubSubChannel) -> crate::Result<()> {
+        if self.channels.contains(&channel) {
+            return Ok(());
+        }
+        let (subscribed, on_subscribed) = oneshot::channel();
+        self.commands
+            .send(Command::Subscribe {
+                channel: channel.clone(),
+                id: self.id,
+                sender: self.sender.clone(),
+                subscribed,
+            })
+            .map_err(|_| anyhow!("subscriber task has stopped"))?;
+        self.channels.insert(channel);
+        // Dropped without a confirmation when the connection is lost.
+        let _ = on_subscribed.await;
+        Ok(())
+    }
+
+    async fn unsubscribe(&mut self, channel: &PubSubChannel) -> crate::Result<()> {
+        if self.channels.remove(channel) {
+            // Nothing is left to unsubscribe from when the task has stopped.
+            let _ = self.commands.send(Command::Unsubscribe {
+                channel: channel.clone(),
+                id: self.id,
+            });
+        }
+        Ok(())
+    }
+}
+
+impl Drop for RedisSubscription {
+    fn drop(&mut self) {
+        for channel in self.channels.drain() {
+            let _ = self.commands.send(Command::Unsubscribe {
+                channel,
+                id: self.id,
+            });
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct ChannelSubscribers {
+    subscribers: Vec<(u64, mpsc::Sender<ReceivedMessage>)>,
+    /// Subscriptions waiting for Redis to confirm the channel is subscribed.
+    waiting: Vec<oneshot::Sender<()>>,
+}
+
+type Channels = HashMap<PubSubChannel, ChannelSubscribers>;
+
+async fn subscriber_task(
+    client: redis::Client,
+    mut commands: mpsc::UnboundedReceiver<Command>,
+    mut health: SubscriptionHealth,
+) {
+    let mut channels = Channels::new();
+    let mut conn: Option<SubscriberConnection> = None;
+    let mut backoff = Backoff::new();
+    let mut retry_at = Instant::now();
+    loop {
+        if conn.is_none() && retry_at <= Instant::now() {
+            match SubscriberConnection::connect(&client, channels.keys()).await {
+                Ok(connected) => {
+                    if health.is_reconnecting() {
+                        info!("reconnected to pubsub ({} channels)", channels.len());
+                        health.set_reconnecting(false);
+                    }
+                    conn = Some(connected);
+                    backoff.reset();
+                }
+                Err(err) => {
+                    warn!("failed to connect to pubsub, retrying: {:?}", err);
+                    health.set_reconnecting(true);
+                    retry_at = Instant::now() + backoff.next_delay();
+                }
+            }
+        }
+        let result = match &mut conn {
+            Some(conn) => tokio::select! {
+                command = commands.recv() => match command {
+                    Some(command) => handle_command(command, &mut channels, Some(conn)).await,
+                    None => break,
+                },
+                value = conn.values.next() => match value {
+                    Some(value) => {
+                        handle_value(&value, &mut channels);
+                        Ok(())
+                    }
+                    None => Err(anyhow!("connection closed")),
+                },
+            },
+            None => tokio::select! {
+                command = commands.recv() => match command {
+                    Some(command) => handle_command(command, &mut channels, None).await,
+                    None => break,
+                },
+                _ = tokio::time::sleep_until(retry_at) => Ok(()),
+            },
+        };
+        if let Err(err) = result {
+            warn!("lost pubsub connection, reconnecting: {:?}", err);
+            conn = None;
+            for channel in channels.values_mut() {
+                channel.waiting.clear();
+            }
+            health.set_reconnecting(true);
+            retry_at = Instant::now();
+        }
+    }
+    debug!("stop subscriber task");
+}
+
+/// Updates the channels, and subscribes the connection to a channel when it gets its first
+/// subscription or unsubscribes it when the channel no longer has any.
+async fn handle_command(
+    command: Command,
+    channels: &mut Channels,
+    conn: Option<&mut SubscriberConnection>,
+) -> crate::Result<()> {
+    match command {
+        Command::Subscribe {
+            channel,
+            id,
+            sender,
+            subscribed,
+        } => {
+            let entry = channels.entry(channel.clone()).or_default();
+            entry.subscribers.push((id, sender));
+            match conn {
+                Some(conn) if entry.subscribers.len() == 1 => {
+                    entry.waiting.push(subscribed);
+                    conn.send_channels("SUBSCRIBE", "PSUBSCRIBE", [&channel])
+                        .await?;
+                }
+                Some(_) if !entry.waiting.is_empty() => entry.waiting.push(subscribed),
+                _ => {
+                    let _ = subscribed.send(());
+                }
+            }
+        }
+        Command::Unsubscribe { channel, id } => {
+            let entry = match channels.get_mut(&channel) {
+                Some(entry) => entry,
+                None => return Ok(()),
+            };
+            entry
+                .subscribers
+                .retain(|(subscriber_id, _)| *subscriber_id != id);
+            if entry.subscribers.is_empty() {
+                channels.remove(&channel);
+                if let Some(conn) = conn {
+                    conn.send_channels("UNSUBSCRIBE", "PUNSUBSCRIBE", [&channel])
+                        .await?;
+                }
             }
-            None => Ok(None),
         }
     }
+    Ok(())
+}
+
+/// Routes a message to the subscriptions of its channel, or completes the subscriptions
+/// waiting for a confirmation.
+fn handle_value(value: &redis::Value, channels: &mut Channels) {
+    if let Some(msg) = redis::Msg::from_value(value) {
+        let topic = msg.get_channel_name().to_string();
+        let channel = match msg.get_pattern::<Option<String>>() {
+            Ok(Some(pattern)) => PubSubChannel::Pattern(pattern),
+            _ => PubSubChannel::Topic(topic.clone()),
+        };
+        let entry = match channels.get(&channel) {
+            Some(entry) => entry,
+            // Unsubscribed while the message was on its way.
+            None => return,
+        };
+        let msg = ReceivedMessage {
+            topic,
+            data: Bytes::copy_from_slice(msg.get_payload_bytes()),
+        };
+        for (id, sender) in &entry.subscribers {
+            // The subscriber task must not wait for a slow subscription, as it would hold up
+            // the others. A closed receiver is removed by its Unsubscribe command.
+            if let Err(mpsc::error::TrySendError::Full(_)) = sender.try_send(msg.clone()) {
+                warn!(
+                    "pubsub subscription is too slow, dropped a message (subscription: {}, topic: {})",
+                    id, msg.topic
+                );
+            }
+        }
+        return;
+    }
+    if let Ok((kind, name, _)) = redis::from_redis_value::<(String, String, i64)>(value) {
+        let channel = match kind.as_str() {
+            "subscribe" => PubSubChannel::Topic(name),
+            "psubscribe" => PubSubChannel::Pattern(name),
+            _ => return,
+        };
+        if let Some(entry) = channels.get_mut(&channel) {
+            for subscribed in entry.waiting.drain(..) {
+                let _ = subscribed.send(());
+            }
+        }
+    }
+}
+
+/// A pubsub connection whose subscriptions can be changed while messages are read.
+/// `redis::aio::PubSub` borrows the connection for both, so the socket is split instead:
+/// the read half is consumed as a stream of values, and commands are written to the write half.
+/// The values are the messages and the replies to the commands.
+struct SubscriberConnection {
+    writer: SharedWriter,
+    values: BoxStream<'static, redis::Value>,
+}
+
+impl SubscriberConnection {
+    async fn connect(
+        client: &redis::Client,
+        channels: impl Iterator<Item = &PubSubChannel>,
+    ) -> crate::Result<Self> {
+        let info = client.get_connection_info();
+        let stream: Box<dyn Socket> = match &info.addr {
+            redis::ConnectionAddr::Tcp(host, port) => Box::new(
+                tokio::net::TcpStream::connect((host.as_str(), *port))
+                    .await
+                    .context("failed to connect")?,
+            ),
+            #[cfg(unix)]
+            redis::ConnectionAddr::Unix(path) => Box::new(
+                tokio::net::UnixStream::connect(path)
+                    .await
+                    .context("failed to connect")?,
+            ),
+            addr => return Err(anyhow!("unsupported address for pubsub: {:?}", addr)),
+        };
+        let (reader, writer) = tokio::io::split(stream);
+        let writer = SharedWriter(Arc::new(Mutex::new(writer)));
+        let socket = SplitSocket {
+            reader,
+            writer: writer.clone(),
+        };
+        // Authenticates and selects the database.
+        let conn = redis::aio::Connection::new(&info.redis, socket)
+            .await
+            .context("failed to connect")?;
+        // Unlike `PubSub`, `Monitor` yields every value including the replies.
+        // MONITOR itself is never sent.
+        let mut conn = Self {
+            writer,
+            values: conn.into_monitor().into_on_message().boxed(),
+        };
+        conn.send_channels("SUBSCRIBE", "PSUBSCRIBE", channels)
+            .await?;
+        Ok(conn)
+    }
+
+    /// Sends `topic_cmd` with the topics of `channels` and `pattern_cmd` with the patterns.
+    async fn send_channels(
+        &mut self,
+        topic_cmd: &str,
+        pattern_cmd: &str,
+        channels: impl IntoIterator<Item = &PubSubChannel>,
+    ) -> crate::Result<()> {
+        let (patterns, topics): (Vec<_>, Vec<_>) = channels
+            .into_iter()
+            .partition(|channel| matches!(channel, PubSubChannel::Pattern(_)));
+        for (cmd, channels) in [(topic_cmd, topics), (pattern_cmd, patterns)] {
+            if channels.is_empty() {
+                continue;
+            }
+            let mut cmd = redis::cmd(cmd);
+            for channel in channels {
+                match channel {
+                    PubSubChannel::Topic(name) | PubSubChannel::Pattern(name) => cmd.arg(name),
+                };
+            }
+            self.send(&cmd).await?;
+        }
+        Ok(())
+    }
+
+    async fn send(&mut self, cmd: &redis::Cmd) -> crate::Result<()> {
+        self.writer
+            .write_all(&cmd.get_packed_command())
+            .await
+            .context("failed to send")
+    }
+}
+
+trait Socket: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Socket for T {}
+
+#[derive(Clone)]
+struct SharedWriter(Arc<Mutex<tokio::io::WriteHalf<Box<dyn Socket>>>>);
+
+impl AsyncWrite for SharedWriter {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut *self.0.lock().unwrap()).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.0.lock().unwrap()).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.0.lock().unwrap()).poll_shutdown(cx)
+    }
+}
+
+/// Reads from the read half, and writes to the write half shared with `SubscriberConnection`.
+struct SplitSocket {
+    reader: tokio::io::ReadHalf<Box<dyn Socket>>,
+    writer: SharedWriter,
+}
+
+impl AsyncRead for SplitSocket {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.reader).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for SplitSocket {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.writer).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.writer).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.writer).poll_shutdown(cx)
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::pubsub::redis::RedisPubSub;
-    use crate::pubsub::{PubSub, PubSubMessage};
+    use crate::packets::BroadcastFilter;
+    use crate::pubsub::redis::{
+        handle_value, ChannelSubscribers, Channels, RedisPubSub, RedisSubscriber,
+    };
+    use crate::pubsub::{
+        PubSub, PubSubChannel, PubSubHealth, PubSubMessage, SUBSCRIPTION_QUEUE_SIZE,
+    };
     use crate::types::{ConnectionID, ServerID};
+    use std::time::Duration;
+    use tokio::sync::mpsc;
 
-    #[tokio::test]
-    async fn test_redis() {
+    async fn new_pubsub(subscriber: RedisSubscriber) -> RedisPubSub {
         let client = redis::Client::open("redis://127.0.0.1").unwrap();
         let conn = client.get_tokio_connection_manager().await.unwrap();
-        let mut pubsub = RedisPubSub::new(client, conn);
+        RedisPubSub::new(subscriber, conn)
+    }
+
+    fn new_subscriber() -> RedisSubscriber {
+        let client = redis::Client::open("redis://127.0.0.1").unwrap();
+        RedisSubscriber::new(client, PubSubHealth::default())
+    }
+
+    #[tokio::test]
+    async fn test_redis() {
+        let mut pubsub = new_pubsub(new_subscriber()).await;
         let mut sub = pubsub.subscribe("test".to_string()).await.unwrap();
         let sender_server = ServerID::new_v4();
         let sender = ConnectionID::new_v4();
         let msg = PubSubMessage::Broadcast {
             sender_server: sender_server.into_bytes(),
             sender: sender.into_bytes(),
+            filter: BroadcastFilter::Exclude,
+            players: vec![sender.into_bytes()],
+            user_id: b"alice".to_vec(),
+            timestamp: 1234,
             payload: b"hello".to_vec(),
         };
         pubsub.publish("test".to_string(), msg).await.unwrap();
-        let msg = PubSubMessage::from_bytes(sub.next_message().await.unwrap().unwrap()).unwrap();
+        let received = sub.next_message().await.unwrap().unwrap();
+        assert_eq!(received.topic, "test");
+        let msg = PubSubMessage::from_bytes(received.data).unwrap();
         assert_eq!(
             msg,
             PubSubMessage::Broadcast {
                 sender_server: sender_server.into_bytes(),
                 sender: sender.into_bytes(),
+                filter: BroadcastFilter::Exclude,
+                players: vec![sender.into_bytes()],
+                user_id: b"alice".to_vec(),
+                timestamp: 1234,
                 payload: b"hello".to_vec()
             }
         );
     }
+
+    #[tokio::test]
+    async fn shared_connection() {
+        let subscriber = new_subscriber();
+        let mut pubsub = new_pubsub(subscriber.clone()).await;
+        let topic1 = format!("test-{}", ServerID::new_v4());
+        let topic2 = format!("test-{}", ServerID::new_v4());
+        let mut sub1 = pubsub.subscribe(topic1.clone()).await.unwrap();
+        let mut sub2 = pubsub.subscribe(topic1.clone()).await.unwrap();
+        let mut sub3 = pubsub.subscribe(topic2.clone()).await.unwrap();
+
+        let msg = |player| PubSubMessage::PlayerJoined {
+            sender_server: ServerID::nil().into_bytes(),
+            player: ConnectionID::from_u128(player).into_bytes(),
+        };
+        pubsub.publish(topic1.clone(), msg(1)).await.unwrap();
+        pubsub.publish(topic2.clone(), msg(2)).await.unwrap();
+        for (sub, player) in [(&mut sub1, 1), (&mut sub2, 1), (&mut sub3, 2)] {
+            let received = sub.next_message().await.unwrap().unwrap();
+            assert_eq!(
+                PubSubMessage::from_bytes(received.data).unwrap(),
+                msg(player)
+            );
+        }
+
+        // The topic stays subscribed until its last subscription is dropped.
+        drop(sub1);
+        drop(sub3);
+        // Unsubscribing happens in the subscriber task.
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        pubsub.publish(topic1.clone(), msg(3)).await.unwrap();
+        let received = sub2.next_message().await.unwrap().unwrap();
+        assert_eq!(PubSubMessage::from_bytes(received.data).unwrap(), msg(3));
+
+        assert_eq!(receivers(&topic2).await, 0);
+    }
+
+    /// Returns how many subscriptions on Redis receive a message published to `topic`.
+    async fn receivers(topic: &str) -> u32 {
+        let client = redis::Client::open("redis://127.0.0.1").unwrap();
+        let mut conn = client.get_async_connection().await.unwrap();
+        redis::cmd("PUBLISH")
+            .arg(topic)
+            .arg("ignored")
+            .query_async(&mut conn)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn channels() {
+        let mut pubsub = new_pubsub(new_subscriber()).await;
+        let prefix = format!("test-{}", ServerID::new_v4());
+        let topic = format!("{}/cluster", prefix);
+        let pattern = PubSubChannel::Pattern(format!("{}/rooms/*", prefix));
+        let mut sub = pubsub
+            .subscribe_channels(vec![PubSubChannel::Topic(topic.clone()), pattern.clone()])
+            .await
+            .unwrap();
+        let msg = |player| PubSubMessage::PlayerJoined {
+            sender_server: ServerID::nil().into_bytes(),
+            player: ConnectionID::from_u128(player).into_bytes(),
+        };
+        let room = format!("{}/rooms/a", prefix);
+        pubsub.publish(topic.clone(), msg(1)).await.unwrap();
+        pubsub.publish(room.clone(), msg(2)).await.unwrap();
+        pubsub
+            .publish(format!("{}/other", prefix), msg(3))
+            .await
+            .unwrap();
+        for (topic, player) in [(&topic, 1), (&room, 2)] {
+            let received = sub.next_message().await.unwrap().unwrap();
+            assert_eq!(&received.topic, topic);
+            assert_eq!(
+                PubSubMessage::from_bytes(received.data).unwrap(),
+                msg(player)
+            );
+        }
+
+        sub.unsubscribe(&pattern).await.unwrap();
+        // Unsubscribing happens in the subscriber task.
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(receivers(&room).await, 0);
+        assert_eq!(receivers(&topic).await, 1);
+        sub.unsubscribe(&PubSubChannel::Topic(topic.clone()))
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(receivers(&topic).await, 0);
+        // The message published while subscribed is still returned.
+        let received = sub.next_message().await.unwrap().unwrap();
+        assert_eq!(received.data.as_ref(), b"ignored");
+        assert!(sub.next_message().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn subscribe_while_unavailable() {
+        // Nothing listens on this port.
+        let client = redis::Client::open("redis://127.0.0.1:1").unwrap();
+        let health = PubSubHealth::default();
+        let subscriber = RedisSubscriber::new(client, health.clone());
+        let mut pubsub = new_pubsub(subscriber).await;
+        let mut sub = pubsub.subscribe("test".to_string()).await.unwrap();
+        let next = tokio::time::timeout(Duration::from_millis(300), sub.next_message()).await;
+        assert!(next.is_err());
+        assert!(!health.is_healthy());
+        assert_eq!(health.reconnecting_subscriptions(), 1);
+
+        // The subscriber task stops once everything is dropped.
+        drop(sub);
+        drop(pubsub);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(health.is_healthy());
+    }
+
+    #[tokio::test]
+    async fn slow_subscription() {
+        let channel = PubSubChannel::Topic("test".to_string());
+        let (sender, mut receiver) = mpsc::channel(SUBSCRIPTION_QUEUE_SIZE);
+        let mut channels = Channels::new();
+        channels.insert(
+            channel,
+            ChannelSubscribers {
+                subscribers: vec![(0, sender)],
+                waiting: vec![],
+            },
+        );
+        let value = |i: usize| {
+            redis::Value::Bulk(vec![
+                redis::Value::Data(b"message".to_vec()),
+                redis::Value::Data(b"test".to_vec()),
+                redis::Value::Data(i.to_string().into_bytes()),
+            ])
+        };
+
+        // Messages beyond the queue are dropped instead of waiting for the subscription.
+        for i in 0..SUBSCRIPTION_QUEUE_SIZE + 1 {
+            handle_value(&value(i), &mut channels);
+        }
+        let first = receiver.recv().await.unwrap();
+        assert_eq!(first.data.as_ref(), b"0");
+        handle_value(&value(0), &mut channels);
+        for _ in 1..SUBSCRIPTION_QUEUE_SIZE {
+            receiver.recv().await.unwrap();
+        }
+        let last = receiver.recv().await.unwrap();
+        assert_eq!(last.data.as_ref(), b"0");
+        assert!(receiver.try_recv().is_err());
+    }
 }
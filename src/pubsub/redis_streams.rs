@@ -0,0 +1,833 @@
+use crate::pubsub::{
+    next_subscription_id, Backoff, PubSub, PubSubChannel, PubSubHealth, PubSubMessage, PubSubTopic,
+    ReceivedMessage, Subscription, SubscriptionHealth, SUBSCRIPTION_QUEUE_SIZE,
+};
+use anyhow::{anyhow, bail, Context};
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use redis::streams::StreamReadOptions;
+use redis::AsyncCommands;
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::Instant;
+use tracing::{debug, info, warn};
+
+const DATA_FIELD: &str = "data";
+/// Streams are trimmed to about this many messages. A subscriber that falls further behind
+/// than this loses the oldest ones.
+const MAX_LEN: usize = 10000;
+/// Streams of rooms that no longer have messages published are deleted after this period.
+const STREAM_TTL: Duration = Duration::from_secs(60 * 60);
+const PUBLISH_ATTEMPTS: u32 = 3;
+/// XREADGROUP returns after this period without messages, so that a broken connection is noticed.
+const READ_BLOCK: Duration = Duration::from_secs(5);
+const READ_COUNT: usize = 100;
+/// The ID to read the pending entries of a group from the start with.
+const PENDING_START: &str = "0";
+/// How often a paused stream checks whether its subscriptions have room again.
+const RESUME_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Appends messages to a Redis Stream per topic. Unlike PUBLISH, messages stay in the stream,
+/// so a subscriber keeps its position and resumes from it after a reconnect or a stall.
+pub(crate) struct RedisStreamsPubSub {
+    subscriber: RedisStreamsSubscriber,
+    pub_conn: redis::aio::ConnectionManager,
+}
+
+impl RedisStreamsPubSub {
+    pub fn new(subscriber: RedisStreamsSubscriber, conn: redis::aio::ConnectionManager) -> Self {
+        Self {
+            subscriber,
+            pub_conn: conn,
+        }
+    }
+}
+
+const STREAM_PREFIX: &str = "pubsub/";
+
+fn stream_key(topic: &str) -> String {
+    format!("{}{}", STREAM_PREFIX, topic)
+}
+
+#[async_trait]
+impl PubSub for RedisStreamsPubSub {
+    async fn publish(&mut self, topic: PubSubTopic, msg: PubSubMessage) -> crate::Result<()> {
+        debug!("publish to pubsub (topic: {}, data: {:?})", topic, msg);
+        let key = stream_key(&topic);
+        let data = msg.to_bytes().to_vec();
+        let mut pipe = redis::pipe();
+        pipe.atomic()
+            .cmd("XADD")
+            .arg(&key)
+            .arg("MAXLEN")
+            .arg("~")
+            .arg(MAX_LEN)
+            .arg("*")
+            .arg(DATA_FIELD)
+            .arg(data)
+            .ignore()
+            .pexpire(&key, STREAM_TTL.as_millis() as usize)
+            .ignore();
+        // The connection manager reconnects in the background, so a few retries get through
+        // a short outage. A retry after an ambiguous failure may deliver the message twice.
+        let mut backoff = Backoff::new();
+        let mut attempt = 1;
+        loop {
+            match pipe.query_async::<_, ()>(&mut self.pub_conn).await {
+                Ok(()) => return Ok(()),
+                Err(err) if attempt < PUBLISH_ATTEMPTS => {
+                    warn!("failed to publish, retrying (topic: {}): {:?}", topic, err);
+                }
+                Err(err) => return Err(err).context("failed to publish"),
+            }
+            backoff.wait().await;
+            attempt += 1;
+        }
+    }
+
+    /// Patterns are not supported, as a stream is read by its key.
+    async fn subscribe_channels(
+        &mut self,
+        channels: Vec<PubSubChannel>,
+    ) -> crate::Result<Box<dyn Subscription + Send>> {
+        debug!("start subscribe from pubsub (channels: {:?})", channels);
+        let mut sub = self.subscriber.subscription();
+        for channel in channels {
+            sub.subscribe(channel).await?;
+        }
+        Ok(Box::new(sub))
+    }
+}
+
+/// Reads the streams of every subscription of a server on one connection.
+/// The position in each stream is kept in Redis, in a consumer group named after the server,
+/// so a read that fails resumes from where the last one ended. The group is deleted when
+/// the stream no longer has subscriptions, and otherwise expires with the stream.
+/// A message is acknowledged once it is handed to every subscription of its stream, and read
+/// again after a reconnect until then, so it may be delivered twice but is not lost.
+/// A stream is not read while one of its subscriptions has `SUBSCRIPTION_QUEUE_SIZE` messages
+/// waiting to be read, and its messages wait in Redis instead.
+#[derive(Debug, Clone)]
+pub(crate) struct RedisStreamsSubscriber {
+    commands: mpsc::UnboundedSender<Command>,
+}
+
+#[derive(Debug)]
+enum Command {
+    Subscribe {
+        topic: PubSubTopic,
+        id: u64,
+        sender: mpsc::Sender<ReceivedMessage>,
+        subscribed: oneshot::Sender<()>,
+    },
+    Unsubscribe {
+        topic: PubSubTopic,
+        id: u64,
+    },
+}
+
+impl RedisStreamsSubscriber {
+    /// The read connection is reported to `health` while it is reconnecting.
+    /// The task stops when the subscriber and all its subscriptions are dropped.
+    pub fn new(
+        client: redis::Client,
+        conn: redis::aio::ConnectionManager,
+        group: String,
+        health: PubSubHealth,
+    ) -> Self {
+        let (commands, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(reader_task(
+            client,
+            conn,
+            group,
+            receiver,
+            SubscriptionHealth::new(health),
+        ));
+        Self { commands }
+    }
+
+    /// Returns a subscription without any topics.
+    fn subscription(&self) -> RedisStreamsSubscription {
+        let (sender, receiver) = mpsc::channel(SUBSCRIPTION_QUEUE_SIZE);
+        RedisStreamsSubscription {
+            id: next_subscription_id(),
+            topics: HashSet::new(),
+            sender,
+            receiver,
+            commands: self.commands.clone(),
+        }
+    }
+}
+
+struct RedisStreamsSubscription {
+    id: u64,
+    topics: HashSet<PubSubTopic>,
+    sender: mpsc::Sender<ReceivedMessage>,
+    receiver: mpsc::Receiver<ReceivedMessage>,
+    commands: mpsc::UnboundedSender<Command>,
+}
+
+#[async_trait]
+impl Subscription for RedisStreamsSubscription {
+    async fn next_message(&mut self) -> crate::Result<Option<ReceivedMessage>> {
+        if self.topics.is_empty() {
+            return Ok(self.receiver.try_recv().ok());
+        }
+        Ok(self.receiver.recv().await)
+    }
+
+    /// Messages published from now on are delivered, even before the first read.
+    /// When Redis is unavailable, the position is taken once the reader connects.
+    async fn subscribe(&mut self, channel: PubSubChannel) -> crate::Result<()> {
+        let topic = match channel {
+            PubSubChannel::Topic(topic) => topic,
+            PubSubChannel::Pattern(pattern) => {
+                bail!(
+                    "pattern subscriptions are not supported with Redis Streams (pattern: {})",
+                    pattern
+                )
+            }
+        };
+        if self.topics.contains(&topic) {
+            return Ok(());
+        }
+        let (subscribed, on_subscribed) = oneshot::channel();
+        self.commands
+            .send(Command::Subscribe {
+                topic: topic.clone(),
+                id: self.id,
+                sender: self.sender.clone(),
+                subscribed,
+            })
+            .map_err(|_| anyhow!("reader task has stopped"))?;
+        self.topics.insert(topic);
+        let _ = on_subscribed.await;
+        Ok(())
+    }
+
+    async fn unsubscribe(&mut self, channel: &PubSubChannel) -> crate::Result<()> {
+        if let PubSubChannel::Topic(topic) = channel {
+            if self.topics.remove(topic) {
+                // Nothing is left to unsubscribe from when the task has stopped.
+                let _ = self.commands.send(Command::Unsubscribe {
+                    topic: topic.clone(),
+                    id: self.id,
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for RedisStreamsSubscription {
+    fn drop(&mut self) {
+        for topic in self.topics.drain() {
+            let _ = self
+                .commands
+                .send(Command::Unsubscribe { topic, id: self.id });
+        }
+    }
+}
+
+struct Stream {
+    subscribers: Vec<(u64, mpsc::Sender<ReceivedMessage>)>,
+    /// Where to read the entries delivered to the group but not acknowledged from, before new ones.
+    pending_from: Option<String>,
+    /// Set while a subscription has no room for more messages.
+    paused: bool,
+}
+
+impl Stream {
+    fn new(subscriber: (u64, mpsc::Sender<ReceivedMessage>)) -> Self {
+        Self {
+            subscribers: vec![subscriber],
+            // The group may already exist with entries that were never acknowledged.
+            pending_from: Some(PENDING_START.to_string()),
+            paused: false,
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        self.subscribers
+            .iter()
+            .any(|(_, sender)| sender.capacity() == 0 && !sender.is_closed())
+    }
+
+    /// Resumes a paused stream from its pending entries once every subscription has room for half
+    /// of its queue, rather than one message at a time. Returns whether the stream was resumed.
+    fn resume(&mut self) -> bool {
+        let has_room = self
+            .subscribers
+            .iter()
+            .all(|(_, sender)| sender.capacity() >= SUBSCRIPTION_QUEUE_SIZE / 2);
+        if self.paused && has_room {
+            self.paused = false;
+            self.pending_from = Some(PENDING_START.to_string());
+            return true;
+        }
+        false
+    }
+}
+
+type Streams = HashMap<PubSubTopic, Stream>;
+
+/// Stream keys with their entries, each with its ID and fields, like `StreamReadReply`. The fields of
+/// an entry are nil when it was trimmed from the stream while pending, which `StreamReadReply` can't parse.
+type ReadReply = Vec<HashMap<String, Vec<HashMap<String, redis::Value>>>>;
+
+/// The connection XREADGROUP blocks on, with its ID for CLIENT UNBLOCK.
+struct ReadConnection {
+    conn: redis::aio::Connection,
+    client_id: i64,
+}
+
+impl ReadConnection {
+    async fn connect(client: &redis::Client) -> crate::Result<Self> {
+        let mut conn = client
+            .get_async_connection()
+            .await
+            .context("failed to create read connection")?;
+        let client_id = redis::cmd("CLIENT")
+            .arg("ID")
+            .query_async(&mut conn)
+            .await
+            .context("failed to get client id")?;
+        Ok(Self { conn, client_id })
+    }
+}
+
+type PendingRead = BoxFuture<'static, (ReadConnection, redis::RedisResult<Option<ReadReply>>)>;
+
+/// Holds the read connection until the reply arrives. A read is not dropped when the streams
+/// change, since its reply would be taken as the reply to the next read. It is unblocked instead.
+struct Reader {
+    client: redis::Client,
+    /// Used for everything but XREADGROUP.
+    conn: redis::aio::ConnectionManager,
+    group: String,
+    /// `None` while a read is in progress, or after an error.
+    read_conn: Option<ReadConnection>,
+    pending_read: Option<PendingRead>,
+    /// Client ID of the connection of `pending_read`.
+    reading_client_id: Option<i64>,
+}
+
+impl Reader {
+    /// Starts reading the pending entries of the streams that have them, and the messages after
+    /// the position of the group in the others. Paused streams are not read.
+    /// The read doesn't block when there are pending entries to read.
+    async fn start_read(&mut self, streams: &Streams) -> crate::Result<()> {
+        let mut read_conn = match self.read_conn.take() {
+            Some(read_conn) => read_conn,
+            None => ReadConnection::connect(&self.client).await?,
+        };
+        let (keys, ids): (Vec<_>, Vec<_>) = streams
+            .iter()
+            .filter(|(_, stream)| !stream.paused)
+            .map(|(topic, stream)| {
+                let id = stream.pending_from.as_deref().unwrap_or(">").to_string();
+                (stream_key(topic), id)
+            })
+            .unzip();
+        let options = StreamReadOptions::default()
+            .group(&self.group, &self.group)
+            .block(READ_BLOCK.as_millis() as usize)
+            .count(READ_COUNT);
+        self.reading_client_id = Some(read_conn.client_id);
+        self.pending_read = Some(
+            async move {
+                let reply = read_conn.conn.xread_options(&keys, &ids, &options).await;
+                (read_conn, reply)
+            }
+            .boxed(),
+        );
+        Ok(())
+    }
+
+    async fn finish_read(&mut self) -> redis::RedisResult<Option<ReadReply>> {
+        let (read_conn, reply) = match &mut self.pending_read {
+            Some(read) => read.await,
+            None => std::future::pending().await,
+        };
+        self.pending_read = None;
+        self.reading_client_id = None;
+        // An error reply from Redis leaves the connection usable.
+        if !matches!(&reply, Err(err) if err.code().is_none()) {
+            self.read_conn = Some(read_conn);
+        }
+        reply
+    }
+
+    /// Makes the read in progress return, so that the next one reads the current streams.
+    /// The read is not unblocked if it hasn't started blocking yet, and then returns after
+    /// `READ_BLOCK` at the latest. Messages of the new streams are delayed but not lost.
+    async fn unblock(&mut self) {
+        if let Some(client_id) = self.reading_client_id {
+            let result: redis::RedisResult<()> = redis::cmd("CLIENT")
+                .arg("UNBLOCK")
+                .arg(client_id)
+                .query_async(&mut self.conn)
+                .await;
+            if let Err(err) = result {
+                warn!("failed to unblock stream read: {:?}", err);
+            }
+        }
+    }
+
+    /// Creates the group at the end of the stream, unless it exists.
+    async fn create_group(&mut self, topic: &str) -> crate::Result<()> {
+        let key = stream_key(topic);
+        let created: redis::RedisResult<()> = self
+            .conn
+            .xgroup_create_mkstream(&key, &self.group, "$")
+            .await;
+        match created {
+            // The stream may have just been created empty, so it must expire as well.
+            Ok(()) => self
+                .conn
+                .pexpire(&key, STREAM_TTL.as_millis() as usize)
+                .await
+                .context("failed to set stream expiry"),
+            Err(err) if err.code() == Some("BUSYGROUP") => Ok(()),
+            Err(err) => Err(err).context("failed to create consumer group"),
+        }
+    }
+
+    async fn ack(&mut self, topic: &str, ids: &[String]) -> crate::Result<()> {
+        if ids.is_empty() {
+            return Ok(());
+        }
+        self.conn
+            .xack(stream_key(topic), &self.group, ids)
+            .await
+            .context("failed to acknowledge messages")
+    }
+
+    async fn destroy_group(&mut self, topic: &str) {
+        let destroyed: redis::RedisResult<()> = self
+            .conn
+            .xgroup_destroy(stream_key(topic), &self.group)
+            .await;
+        if let Err(err) = destroyed {
+            warn!(
+                "failed to delete consumer group, leaving it to expire (topic: {}): {:?}",
+                topic, err
+            );
+        }
+    }
+}
+
+async fn reader_task(
+    client: redis::Client,
+    conn: redis::aio::ConnectionManager,
+    group: String,
+    mut commands: mpsc::UnboundedReceiver<Command>,
+    mut health: SubscriptionHealth,
+) {
+    let mut reader = Reader {
+        client,
+        conn,
+        group,
+        read_conn: None,
+        pending_read: None,
+        reading_client_id: None,
+    };
+    let mut streams = Streams::new();
+    let mut backoff = Backoff::new();
+    let mut retry_at = Instant::now();
+    loop {
+        let mut resumed = false;
+        for stream in streams.values_mut() {
+            resumed |= stream.resume();
+        }
+        if resumed {
+            reader.unblock().await;
+        }
+        let readable = streams.values().any(|stream| !stream.paused);
+        if reader.pending_read.is_none() && readable && retry_at <= Instant::now() {
+            if let Err(err) = reader.start_read(&streams).await {
+                warn!("failed to read pubsub streams, retrying: {:?}", err);
+                health.set_reconnecting(true);
+                retry_at = Instant::now() + backoff.next_delay();
+            }
+        }
+        let waiting = reader.pending_read.is_none() && readable;
+        let paused = streams.values().any(|stream| stream.paused);
+        tokio::select! {
+            command = commands.recv() => match command {
+                Some(command) => {
+                    if handle_command(command, &mut streams, &mut reader).await {
+                        reader.unblock().await;
+                    }
+                }
+                None => break,
+            },
+            reply = reader.finish_read() => match reply {
+                Ok(reply) => {
+                    if health.is_reconnecting() {
+                        info!("reconnected to pubsub streams ({} streams)", streams.len());
+                        health.set_reconnecting(false);
+                    }
+                    backoff.reset();
+                    for (key, entries) in reply.unwrap_or_default().into_iter().flatten() {
+                        let entries = entries.into_iter().flatten().collect();
+                        route(key, entries, &mut streams, &mut reader).await;
+                    }
+                }
+                // The stream has expired with its group, or the group was deleted while
+                // the stream was being subscribed to again.
+                Err(err) if err.code() == Some("NOGROUP") => {
+                    let topics: Vec<_> = streams.keys().cloned().collect();
+                    for topic in topics {
+                        if let Err(err) = reader.create_group(&topic).await {
+                            warn!("failed to subscribe again (topic: {}): {:?}", topic, err);
+                            retry_at = Instant::now() + backoff.next_delay();
+                        }
+                    }
+                }
+                Err(err) => {
+                    warn!(
+                        "failed to read pubsub streams, reconnecting ({} streams): {:?}",
+                        streams.len(),
+                        err
+                    );
+                    health.set_reconnecting(true);
+                    retry_at = Instant::now() + backoff.next_delay();
+                    // The reply may have been lost after the entries were delivered to the group.
+                    for stream in streams.values_mut() {
+                        stream.pending_from = Some(PENDING_START.to_string());
+                    }
+                }
+            },
+            _ = tokio::time::sleep_until(retry_at), if waiting => {}
+            _ = tokio::time::sleep(RESUME_CHECK_INTERVAL), if paused => {}
+        }
+    }
+    debug!("stop pubsub streams reader task");
+}
+
+/// Updates the streams, creating the group of a stream when it gets its first subscription
+/// and deleting it when the stream no longer has any. Returns whether the set of streams changed.
+async fn handle_command(command: Command, streams: &mut Streams, reader: &mut Reader) -> bool {
+    match command {
+        Command::Subscribe {
+            topic,
+            id,
+            sender,
+            subscribed,
+        } => {
+            if let Some(stream) = streams.get_mut(&topic) {
+                stream.subscribers.push((id, sender));
+                let _ = subscribed.send(());
+                return false;
+            }
+            if let Err(err) = reader.create_group(&topic).await {
+                // The group is created when the read fails without it.
+                warn!(
+                    "failed to subscribe, retrying in background (topic: {}): {:?}",
+                    topic, err
+                );
+            }
+            streams.insert(topic, Stream::new((id, sender)));
+            let _ = subscribed.send(());
+            true
+        }
+        Command::Unsubscribe { topic, id } => {
+            let stream = match streams.get_mut(&topic) {
+                Some(stream) => stream,
+                None => return false,
+            };
+            stream
+                .subscribers
+                .retain(|(subscriber_id, _)| *subscriber_id != id);
+            if !stream.subscribers.is_empty() {
+                return false;
+            }
+            streams.remove(&topic);
+            reader.destroy_group(&topic).await;
+            true
+        }
+    }
+}
+
+/// Hands the entries of a stream to its subscriptions and acknowledges them. When a subscription
+/// has no room left, the stream is paused and the rest of the entries stay pending in the group.
+async fn route(
+    key: String,
+    entries: Vec<(String, redis::Value)>,
+    streams: &mut Streams,
+    reader: &mut Reader,
+) {
+    let topic = match key.strip_prefix(STREAM_PREFIX) {
+        Some(topic) => topic,
+        None => return,
+    };
+    let stream = match streams.get_mut(topic) {
+        Some(stream) => stream,
+        // Unsubscribed while the read was in progress.
+        None => return,
+    };
+    let count = entries.len();
+    let mut handed = vec![];
+    for (id, fields) in entries {
+        if stream.is_full() {
+            debug!(
+                "pubsub subscription is full, pausing the stream (topic: {})",
+                topic
+            );
+            stream.paused = true;
+            break;
+        }
+        let data = match fields {
+            // Trimmed from the stream while it was pending.
+            redis::Value::Nil => None,
+            fields => redis::from_redis_value::<HashMap<String, Vec<u8>>>(&fields)
+                .ok()
+                .and_then(|mut fields| fields.remove(DATA_FIELD)),
+        };
+        match data {
+            Some(data) => {
+                let msg = ReceivedMessage {
+                    topic: topic.to_string(),
+                    data: Bytes::from(data),
+                };
+                for (_, sender) in &stream.subscribers {
+                    // A closed receiver is removed by its Unsubscribe command.
+                    let _ = sender.try_send(msg.clone());
+                }
+            }
+            None => warn!("skip stream entry without data (id: {})", id),
+        }
+        handed.push(id);
+    }
+    if stream.paused {
+        stream.pending_from = Some(PENDING_START.to_string());
+    } else if stream.pending_from.is_some() {
+        // Fewer entries than asked for means that every pending one has been read.
+        stream.pending_from = if count < READ_COUNT {
+            None
+        } else {
+            handed.last().cloned()
+        };
+    }
+    if let Err(err) = reader.ack(topic, &handed).await {
+        warn!(
+            "failed to acknowledge pubsub messages, reading them again (topic: {}): {:?}",
+            topic, err
+        );
+        if let Some(stream) = streams.get_mut(topic) {
+            stream.pending_from = Some(PENDING_START.to_string());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pubsub::redis_streams::{stream_key, RedisStreamsPubSub, RedisStreamsSubscriber};
+    use crate::pubsub::{
+        PubSub, PubSubChannel, PubSubHealth, PubSubMessage, Subscription, SUBSCRIPTION_QUEUE_SIZE,
+    };
+    use crate::types::{ConnectionID, ServerID};
+    use std::time::Duration;
+
+    /// Returns a pubsub with its own consumer groups, as if it were another server.
+    async fn new_pubsub() -> (RedisStreamsPubSub, String) {
+        let client = redis::Client::open("redis://127.0.0.1").unwrap();
+        let conn = client.get_tokio_connection_manager().await.unwrap();
+        let group = ServerID::new_v4().to_string();
+        let subscriber = RedisStreamsSubscriber::new(
+            client,
+            conn.clone(),
+            group.clone(),
+            PubSubHealth::default(),
+        );
+        (RedisStreamsPubSub::new(subscriber, conn), group)
+    }
+
+    fn player_joined(player: u128) -> PubSubMessage {
+        PubSubMessage::PlayerJoined {
+            sender_server: ServerID::nil().into_bytes(),
+            player: ConnectionID::from_u128(player).into_bytes(),
+        }
+    }
+
+    async fn assert_next(sub: &mut (impl Subscription + ?Sized), topic: &str, player: u128) {
+        let received = sub.next_message().await.unwrap().unwrap();
+        assert_eq!(received.topic, topic);
+        assert_eq!(
+            PubSubMessage::from_bytes(received.data).unwrap(),
+            player_joined(player)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_redis_streams() {
+        let topic = format!("test-{}", ServerID::new_v4());
+        let (mut pubsub, _) = new_pubsub().await;
+        pubsub
+            .publish(topic.clone(), player_joined(0))
+            .await
+            .unwrap();
+
+        // Messages published before subscribing are not delivered.
+        let mut sub1 = pubsub.subscribe(topic.clone()).await.unwrap();
+        let mut sub2 = pubsub.subscribe(topic.clone()).await.unwrap();
+        let mut sub3 = new_pubsub().await.0.subscribe(topic.clone()).await.unwrap();
+        for player in 1..=2 {
+            pubsub
+                .publish(topic.clone(), player_joined(player))
+                .await
+                .unwrap();
+        }
+        for sub in [&mut sub1, &mut sub2, &mut sub3] {
+            assert_next(sub.as_mut(), &topic, 1).await;
+            assert_next(sub.as_mut(), &topic, 2).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn group_position() {
+        let topic = format!("test-{}", ServerID::new_v4());
+        let (mut pubsub, group) = new_pubsub().await;
+        let mut sub = pubsub.subscribe(topic.clone()).await.unwrap();
+        pubsub
+            .publish(topic.clone(), player_joined(1))
+            .await
+            .unwrap();
+        assert_next(sub.as_mut(), &topic, 1).await;
+
+        // The position is kept in Redis until the stream is no longer subscribed.
+        let client = redis::Client::open("redis://127.0.0.1").unwrap();
+        let mut conn = client.get_async_connection().await.unwrap();
+        let groups: redis::streams::StreamInfoGroupsReply = redis::cmd("XINFO")
+            .arg("GROUPS")
+            .arg(stream_key(&topic))
+            .query_async(&mut conn)
+            .await
+            .unwrap();
+        let last: redis::streams::StreamRangeReply = redis::cmd("XREVRANGE")
+            .arg(stream_key(&topic))
+            .arg("+")
+            .arg("-")
+            .arg("COUNT")
+            .arg(1)
+            .query_async(&mut conn)
+            .await
+            .unwrap();
+        assert_eq!(groups.groups.len(), 1);
+        assert_eq!(groups.groups[0].name, group);
+        assert_eq!(groups.groups[0].last_delivered_id, last.ids[0].id);
+
+        drop(sub);
+        // Unsubscribing happens in the reader task.
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let groups: redis::streams::StreamInfoGroupsReply = redis::cmd("XINFO")
+            .arg("GROUPS")
+            .arg(stream_key(&topic))
+            .query_async(&mut conn)
+            .await
+            .unwrap();
+        assert!(groups.groups.is_empty());
+    }
+
+    #[tokio::test]
+    async fn slow_subscription() {
+        let topic = format!("test-{}", ServerID::new_v4());
+        let (mut pubsub, _) = new_pubsub().await;
+        let mut sub = pubsub.subscribe(topic.clone()).await.unwrap();
+        let count = SUBSCRIPTION_QUEUE_SIZE as u128 * 2;
+        for player in 0..count {
+            pubsub
+                .publish(topic.clone(), player_joined(player))
+                .await
+                .unwrap();
+        }
+        // Messages that don't fit in the queue wait in the stream instead of being dropped.
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        for player in 0..count {
+            assert_next(sub.as_mut(), &topic, player).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn subscribe_while_reading() {
+        let topic1 = format!("test-{}", ServerID::new_v4());
+        let topic2 = format!("test-{}", ServerID::new_v4());
+        let (mut pubsub, _) = new_pubsub().await;
+        let mut sub1 = pubsub.subscribe(topic1.clone()).await.unwrap();
+        let next = tokio::time::timeout(Duration::from_millis(100), sub1.next_message()).await;
+        assert!(next.is_err());
+
+        // The read blocking on the first stream is unblocked to read the second one as well.
+        let mut sub2 = pubsub.subscribe(topic2.clone()).await.unwrap();
+        pubsub
+            .publish(topic2.clone(), player_joined(1))
+            .await
+            .unwrap();
+        tokio::time::timeout(
+            Duration::from_secs(1),
+            assert_next(sub2.as_mut(), &topic2, 1),
+        )
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn channels() {
+        let topic1 = format!("test-{}", ServerID::new_v4());
+        let topic2 = format!("test-{}", ServerID::new_v4());
+        let (mut pubsub, _) = new_pubsub().await;
+        let pattern = PubSubChannel::Pattern("test-*".to_string());
+        assert!(pubsub
+            .subscribe_channels(vec![pattern.clone()])
+            .await
+            .is_err());
+
+        let mut sub = pubsub
+            .subscribe_channels(vec![
+                PubSubChannel::Topic(topic1.clone()),
+                PubSubChannel::Topic(topic2.clone()),
+            ])
+            .await
+            .unwrap();
+        assert!(sub.subscribe(pattern).await.is_err());
+        for (topic, player) in [(&topic1, 1), (&topic2, 2)] {
+            pubsub
+                .publish(topic.clone(), player_joined(player))
+                .await
+                .unwrap();
+        }
+        let mut received = vec![];
+        for _ in 0..2 {
+            let msg = sub.next_message().await.unwrap().unwrap();
+            received.push((msg.topic, PubSubMessage::from_bytes(msg.data).unwrap()));
+        }
+        received.sort_by(|a, b| a.0.cmp(&b.0));
+        let mut expected = vec![
+            (topic1.clone(), player_joined(1)),
+            (topic2.clone(), player_joined(2)),
+        ];
+        expected.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(received, expected);
+
+        sub.unsubscribe(&PubSubChannel::Topic(topic1.clone()))
+            .await
+            .unwrap();
+        // Unsubscribing happens in the reader task.
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        for (topic, player) in [(&topic1, 3), (&topic2, 4)] {
+            pubsub
+                .publish(topic.clone(), player_joined(player))
+                .await
+                .unwrap();
+        }
+        assert_next(sub.as_mut(), &topic2, 4).await;
+        sub.unsubscribe(&PubSubChannel::Topic(topic2.clone()))
+            .await
+            .unwrap();
+        assert!(sub.next_message().await.unwrap().is_none());
+    }
+}
@@ -1,13 +1,127 @@
+pub(crate) mod memory;
 pub(crate) mod redis;
+pub(crate) mod sled;
 
 use async_trait::async_trait;
+use std::time::Duration;
 
 pub type StateKey = String;
 pub type StateData = Vec<u8>;
+/// Incremented on every write to a key, including deletes, so it never goes back.
+/// 0 means the key has never been written.
+pub type StateVersion = u64;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VersionedData {
+    /// `None` when the key does not exist.
+    pub data: Option<StateData>,
+    pub version: StateVersion,
+}
+
+/// How long a deleted key keeps its version by default.
+pub(crate) const DEFAULT_DELETED_KEY_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// How a write changes the expiry of a key.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Expiry {
+    /// Expires the key after its own TTL, or with the rest of the room state when `None`.
+    Set(Option<Duration>),
+    /// `Set(None)` for keys without a value, which may have the expiry of a deleted key.
+    /// Leaves the expiry of the others as it is.
+    New,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SwapResult {
+    Swapped { version: StateVersion },
+    Conflict { current: VersionedData },
+}
+
+/// Keys of the room state a member is notified about.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StateSubscription {
+    Key(StateKey),
+    /// An empty prefix matches every key.
+    Prefix(String),
+}
+
+impl StateSubscription {
+    pub fn matches(&self, key: &str) -> bool {
+        match self {
+            StateSubscription::Key(k) => k == key,
+            StateSubscription::Prefix(prefix) => key.starts_with(prefix.as_str()),
+        }
+    }
+}
 
 #[async_trait]
-pub trait RoomStateStore {
-    async fn get_state(&mut self, key: StateKey) -> crate::Result<Option<StateData>>;
-    async fn save_state(&mut self, key: StateKey, data: StateData) -> crate::Result<()>;
-    async fn delete_state(&mut self, key: StateKey) -> crate::Result<()>;
+pub trait RoomStateStore: Send {
+    async fn get_state(&mut self, key: StateKey) -> crate::Result<VersionedData>;
+
+    /// Returns the existing keys starting with `prefix`, in no particular order.
+    async fn list_state_keys(&mut self, prefix: &str) -> crate::Result<Vec<StateKey>>;
+
+    /// Returns the values of `keys` in the same order.
+    async fn get_states(&mut self, keys: Vec<StateKey>) -> crate::Result<Vec<VersionedData>>;
+
+    /// Writes all of `states` atomically and returns their new versions in the same order.
+    /// The keys expire with the rest of the room state, even ones that had their own TTL.
+    async fn save_states(
+        &mut self,
+        states: Vec<(StateKey, StateData)>,
+    ) -> crate::Result<Vec<StateVersion>>;
+
+    /// Pushes back the expiry of the keys without their own TTL by the retention period of the store.
+    /// The room calls it periodically while it has members, so that the state of a room without
+    /// members on any server expires.
+    async fn refresh_expiry(&mut self) -> crate::Result<()>;
+
+    /// Returns the existing keys starting with `prefix` with their values, in no particular order.
+    async fn get_state_by_prefix(
+        &mut self,
+        prefix: &str,
+    ) -> crate::Result<Vec<(StateKey, VersionedData)>> {
+        let keys = self.list_state_keys(prefix).await?;
+        let values = self.get_states(keys.clone()).await?;
+        // A key may be deleted between the two calls.
+        Ok(keys
+            .into_iter()
+            .zip(values)
+            .filter(|(_, value)| value.data.is_some())
+            .collect())
+    }
+
+    /// Writes only if the version of the key is `expected_version`, when given.
+    /// The key expires after `ttl`, or with the rest of the room state when `None`.
+    async fn save_state(
+        &mut self,
+        key: StateKey,
+        data: StateData,
+        expected_version: Option<StateVersion>,
+        ttl: Option<Duration>,
+    ) -> crate::Result<SwapResult>;
+
+    /// Deletes only if the version of the key is `expected_version`, when given.
+    /// The deleted key keeps its version for `DEFAULT_DELETED_KEY_TTL`.
+    async fn delete_state(
+        &mut self,
+        key: StateKey,
+        expected_version: Option<StateVersion>,
+    ) -> crate::Result<SwapResult>;
+
+    /// Adds `delta` to the value stored as a decimal string, treating a missing key as 0,
+    /// and returns the result with the new version.
+    /// Returns `None` without writing if the current value is not a decimal integer.
+    async fn increment_state(
+        &mut self,
+        key: StateKey,
+        delta: i64,
+    ) -> crate::Result<Option<(i64, StateVersion)>>;
+
+    /// Returns the new version, or `None` without writing if the key already exists.
+    async fn save_state_if_absent(
+        &mut self,
+        key: StateKey,
+        data: StateData,
+    ) -> crate::Result<Option<StateVersion>>;
 }
@@ -0,0 +1,435 @@
+use crate::room_states::{
+    Expiry, RoomStateStore, StateData, StateKey, StateVersion, SwapResult, VersionedData,
+    DEFAULT_DELETED_KEY_TTL,
+};
+use crate::types::RoomID;
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Room state of every room in this process, shared by the stores of the rooms.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct MemoryStates {
+    rooms: Arc<Mutex<HashMap<RoomID, HashMap<StateKey, Entry>>>>,
+}
+
+impl MemoryStates {
+    /// Removes the expired keys of every room.
+    /// Keys are otherwise removed only when their room is accessed again.
+    fn purge_expired(&self) {
+        let now = Instant::now();
+        let mut rooms = self.rooms.lock().unwrap();
+        rooms.retain(|_, room| {
+            room.retain(|_, entry| !entry.is_expired(now));
+            !room.is_empty()
+        });
+    }
+}
+
+/// Purges expired keys from `states` every `interval`.
+pub(crate) async fn sweep_expired(states: MemoryStates, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        states.purge_expired();
+    }
+}
+
+#[derive(Debug, Default)]
+struct Entry {
+    /// `None` when the key is deleted. The entry is kept for its version until it expires.
+    data: Option<StateData>,
+    version: StateVersion,
+    /// Set only on keys with their own TTL.
+    ttl: Option<Duration>,
+    expires_at: Option<Instant>,
+}
+
+impl Entry {
+    fn is_expired(&self, now: Instant) -> bool {
+        matches!(self.expires_at, Some(expires_at) if expires_at <= now)
+    }
+
+    fn versioned_data(&self) -> VersionedData {
+        VersionedData {
+            data: self.data.clone(),
+            version: self.version,
+        }
+    }
+}
+
+/// Keeps room state in memory with the same semantics as `RedisStateStore`.
+pub(crate) struct MemoryStateStore {
+    room_id: RoomID,
+    states: MemoryStates,
+    /// Keys without their own TTL expire after this period unless `refresh_expiry` is called.
+    retention: Option<Duration>,
+    deleted_key_ttl: Duration,
+}
+
+impl MemoryStateStore {
+    pub fn new(room_id: RoomID, states: MemoryStates) -> Self {
+        Self {
+            room_id,
+            states,
+            retention: None,
+            deleted_key_ttl: DEFAULT_DELETED_KEY_TTL,
+        }
+    }
+
+    pub fn with_retention(mut self, retention: Option<Duration>) -> Self {
+        self.retention = retention;
+        self
+    }
+
+    #[cfg(test)]
+    fn with_deleted_key_ttl(mut self, deleted_key_ttl: Duration) -> Self {
+        self.deleted_key_ttl = deleted_key_ttl;
+        self
+    }
+
+    /// Runs `f` on the keys of the room, with the expired ones removed.
+    fn with_room<T>(&self, f: impl FnOnce(&mut HashMap<StateKey, Entry>) -> T) -> T {
+        let mut rooms = self.states.rooms.lock().unwrap();
+        let room = rooms.entry(self.room_id).or_default();
+        let now = Instant::now();
+        room.retain(|_, entry| !entry.is_expired(now));
+        let result = f(room);
+        if room.is_empty() {
+            rooms.remove(&self.room_id);
+        }
+        result
+    }
+
+    fn room_expiry(&self) -> Option<Instant> {
+        // A period too long to represent never expires.
+        self.retention
+            .and_then(|retention| Instant::now().checked_add(retention))
+    }
+}
+
+/// Writes `data`, or deletes the value when it is `None`, and returns the new version.
+fn write(
+    room: &mut HashMap<StateKey, Entry>,
+    key: StateKey,
+    data: Option<StateData>,
+    expiry: Expiry,
+    room_expiry: Option<Instant>,
+) -> StateVersion {
+    let entry = room.entry(key).or_default();
+    let ttl = match expiry {
+        Expiry::Set(ttl) => Some(ttl),
+        Expiry::New if entry.data.is_none() => Some(None),
+        Expiry::New => None,
+    };
+    if let Some(ttl) = ttl {
+        entry.ttl = ttl;
+        entry.expires_at = match ttl {
+            Some(ttl) => Instant::now().checked_add(ttl),
+            None => room_expiry,
+        };
+    }
+    entry.data = data;
+    entry.version += 1;
+    entry.version
+}
+
+fn current(room: &HashMap<StateKey, Entry>, key: &str) -> VersionedData {
+    room.get(key).map(Entry::versioned_data).unwrap_or_default()
+}
+
+#[async_trait]
+impl RoomStateStore for MemoryStateStore {
+    async fn get_state(&mut self, key: StateKey) -> crate::Result<VersionedData> {
+        Ok(self.with_room(|room| current(room, &key)))
+    }
+
+    async fn list_state_keys(&mut self, prefix: &str) -> crate::Result<Vec<StateKey>> {
+        Ok(self.with_room(|room| {
+            room.iter()
+                .filter(|(key, entry)| key.starts_with(prefix) && entry.data.is_some())
+                .map(|(key, _)| key.clone())
+                .collect()
+        }))
+    }
+
+    async fn get_states(&mut self, keys: Vec<StateKey>) -> crate::Result<Vec<VersionedData>> {
+        Ok(self.with_room(|room| keys.iter().map(|key| current(room, key)).collect()))
+    }
+
+    async fn save_states(
+        &mut self,
+        states: Vec<(StateKey, StateData)>,
+    ) -> crate::Result<Vec<StateVersion>> {
+        let room_expiry = self.room_expiry();
+        Ok(self.with_room(|room| {
+            states
+                .into_iter()
+                .map(|(key, data)| write(room, key, Some(data), Expiry::Set(None), room_expiry))
+                .collect()
+        }))
+    }
+
+    async fn save_state(
+        &mut self,
+        key: StateKey,
+        data: StateData,
+        expected_version: Option<StateVersion>,
+        ttl: Option<Duration>,
+    ) -> crate::Result<SwapResult> {
+        let room_expiry = self.room_expiry();
+        Ok(self.with_room(|room| {
+            let current = current(room, &key);
+            if matches!(expected_version, Some(expected) if expected != current.version) {
+                return SwapResult::Conflict { current };
+            }
+            let version = write(room, key, Some(data), Expiry::Set(ttl), room_expiry);
+            SwapResult::Swapped { version }
+        }))
+    }
+
+    async fn delete_state(
+        &mut self,
+        key: StateKey,
+        expected_version: Option<StateVersion>,
+    ) -> crate::Result<SwapResult> {
+        let room_expiry = self.room_expiry();
+        // Deleted keys are kept only for their versions, so they expire even without retention.
+        let expiry = Expiry::Set(Some(self.deleted_key_ttl));
+        Ok(self.with_room(|room| {
+            let current = current(room, &key);
+            if matches!(expected_version, Some(expected) if expected != current.version) {
+                return SwapResult::Conflict { current };
+            }
+            let version = write(room, key, None, expiry, room_expiry);
+            SwapResult::Swapped { version }
+        }))
+    }
+
+    async fn increment_state(
+        &mut self,
+        key: StateKey,
+        delta: i64,
+    ) -> crate::Result<Option<(i64, StateVersion)>> {
+        let room_expiry = self.room_expiry();
+        Ok(self.with_room(|room| {
+            let value = match current(room, &key).data {
+                Some(data) => parse_integer(&data)?,
+                None => 0,
+            };
+            let value = value.checked_add(delta)?;
+            let data = value.to_string().into_bytes();
+            let version = write(room, key, Some(data), Expiry::New, room_expiry);
+            Some((value, version))
+        }))
+    }
+
+    async fn save_state_if_absent(
+        &mut self,
+        key: StateKey,
+        data: StateData,
+    ) -> crate::Result<Option<StateVersion>> {
+        let room_expiry = self.room_expiry();
+        Ok(self.with_room(|room| {
+            if current(room, &key).data.is_some() {
+                return None;
+            }
+            Some(write(room, key, Some(data), Expiry::New, room_expiry))
+        }))
+    }
+
+    async fn refresh_expiry(&mut self) -> crate::Result<()> {
+        let room_expiry = match self.room_expiry() {
+            Some(room_expiry) => room_expiry,
+            None => return Ok(()),
+        };
+        self.with_room(|room| {
+            for entry in room.values_mut().filter(|entry| entry.ttl.is_none()) {
+                entry.expires_at = Some(room_expiry);
+            }
+        });
+        Ok(())
+    }
+}
+
+fn parse_integer(data: &[u8]) -> Option<i64> {
+    std::str::from_utf8(data).ok()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::room_states::memory::{MemoryStateStore, MemoryStates};
+    use crate::room_states::{RoomStateStore, StateData, SwapResult, VersionedData};
+    use crate::types::RoomID;
+    use std::time::{Duration, Instant};
+
+    #[tokio::test]
+    async fn versions() {
+        let states = MemoryStates::default();
+        let room_id = RoomID::new_v4();
+        let mut store = MemoryStateStore::new(room_id, states.clone());
+        let key = "test".to_string();
+        let result = store
+            .save_state(key.clone(), "a".into(), Some(1), None)
+            .await;
+        assert_eq!(
+            result.unwrap(),
+            SwapResult::Conflict {
+                current: VersionedData::default()
+            }
+        );
+        let result = store
+            .save_state(key.clone(), "a".into(), Some(0), None)
+            .await;
+        assert_eq!(result.unwrap(), SwapResult::Swapped { version: 1 });
+        let result = store.delete_state(key.clone(), Some(1)).await;
+        assert_eq!(result.unwrap(), SwapResult::Swapped { version: 2 });
+
+        // Another store of the same room sees the deleted key with its version.
+        let mut store = MemoryStateStore::new(room_id, states);
+        let current = store.get_state(key.clone()).await.unwrap();
+        assert_eq!(
+            current,
+            VersionedData {
+                data: None,
+                version: 2
+            }
+        );
+        assert_eq!(
+            store.list_state_keys("").await.unwrap(),
+            Vec::<String>::new()
+        );
+        let saved = store.save_state_if_absent(key.clone(), "b".into()).await;
+        assert_eq!(saved.unwrap(), Some(3));
+        let saved = store.save_state_if_absent(key, "c".into()).await;
+        assert_eq!(saved.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn increment() {
+        let mut store = MemoryStateStore::new(RoomID::new_v4(), MemoryStates::default());
+        let value = store.increment_state("counter".to_string(), 2).await;
+        assert_eq!(value.unwrap(), Some((2, 1)));
+        let value = store.increment_state("counter".to_string(), i64::MAX).await;
+        assert_eq!(value.unwrap(), None);
+        let data = store.get_state("counter".to_string()).await.unwrap();
+        assert_eq!(data.data, Some(StateData::from("2")));
+    }
+
+    #[tokio::test]
+    async fn purge() {
+        let states = MemoryStates::default();
+        let mut store = MemoryStateStore::new(RoomID::new_v4(), states.clone());
+        store
+            .save_state(
+                "short".to_string(),
+                "a".into(),
+                None,
+                Some(Duration::from_millis(50)),
+            )
+            .await
+            .unwrap();
+        let mut other = MemoryStateStore::new(RoomID::new_v4(), states.clone());
+        other
+            .save_state("long".to_string(), "b".into(), None, None)
+            .await
+            .unwrap();
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        states.purge_expired();
+        assert_eq!(states.rooms.lock().unwrap().len(), 1);
+        let state = other.get_state("long".to_string()).await.unwrap();
+        assert_eq!(state.data, Some(StateData::from("b")));
+    }
+
+    #[tokio::test]
+    async fn expiry() {
+        let mut store = MemoryStateStore::new(RoomID::new_v4(), MemoryStates::default())
+            .with_retention(Some(Duration::from_millis(200)));
+        store
+            .save_state(
+                "short".to_string(),
+                "a".into(),
+                None,
+                Some(Duration::from_millis(50)),
+            )
+            .await
+            .unwrap();
+        store
+            .save_state("long".to_string(), "b".into(), None, None)
+            .await
+            .unwrap();
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        store.refresh_expiry().await.unwrap();
+        let mut keys = store.list_state_keys("").await.unwrap();
+        keys.sort();
+        assert_eq!(keys, vec!["long".to_string()]);
+
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        let state = store.get_state("long".to_string()).await.unwrap();
+        assert_eq!(state.data, Some(StateData::from("b")));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let state = store.get_state("long".to_string()).await.unwrap();
+        assert_eq!(state, VersionedData::default());
+    }
+
+    #[tokio::test]
+    async fn deleted_key_expiry() {
+        let ttl = Duration::from_millis(100);
+        let states = MemoryStates::default();
+        let mut store =
+            MemoryStateStore::new(RoomID::new_v4(), states.clone()).with_deleted_key_ttl(ttl);
+        store
+            .save_state("test".to_string(), "a".into(), None, None)
+            .await
+            .unwrap();
+        store.delete_state("test".to_string(), None).await.unwrap();
+        let state = store.get_state("test".to_string()).await.unwrap();
+        assert_eq!(state.version, 2);
+
+        // Writing again gives the key back the expiry of the room state.
+        store.increment_state("test".to_string(), 1).await.unwrap();
+        assert_eq!(expires_in(&store, "test"), None);
+        store.delete_state("test".to_string(), None).await.unwrap();
+
+        tokio::time::sleep(ttl * 2).await;
+        states.purge_expired();
+        assert!(states.rooms.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn bulk_expiry() {
+        let retention = Duration::from_secs(10);
+        let mut store = MemoryStateStore::new(RoomID::new_v4(), MemoryStates::default())
+            .with_retention(Some(retention));
+        let ttl = Duration::from_millis(100);
+        store
+            .save_state("a".to_string(), "1".into(), None, Some(ttl))
+            .await
+            .unwrap();
+
+        // Like `save_state` without a TTL, batch writes expire with the rest of the room state.
+        store
+            .save_states(vec![
+                ("a".to_string(), "2".into()),
+                ("b".to_string(), "3".into()),
+            ])
+            .await
+            .unwrap();
+        assert!(expires_in(&store, "a").unwrap() > ttl);
+        assert!(expires_in(&store, "b").unwrap() > ttl);
+
+        tokio::time::sleep(ttl * 2).await;
+        store.refresh_expiry().await.unwrap();
+        let state = store.get_state("a".to_string()).await.unwrap();
+        assert_eq!(state.data, Some(StateData::from("2")));
+    }
+
+    fn expires_in(store: &MemoryStateStore, key: &str) -> Option<Duration> {
+        store
+            .with_room(|room| room[key].expires_at)
+            .map(|expires_at| expires_at.saturating_duration_since(Instant::now()))
+    }
+}
@@ -1,70 +1,851 @@
-use crate::room_states::{RoomStateStore, StateData, StateKey};
+use crate::room_states::{
+    Expiry, RoomStateStore, StateData, StateKey, StateVersion, SwapResult, VersionedData,
+    DEFAULT_DELETED_KEY_TTL,
+};
 use crate::types::RoomID;
 use anyhow::Context;
 use async_trait::async_trait;
 use redis::AsyncCommands;
+use std::time::Duration;
 
+const VALUE_FIELD: &str = "value";
+const VERSION_FIELD: &str = "version";
+
+/// Functions of the scripts that write keys. Every key of a room is a member of the index of the room,
+/// which lives at least as long as the keys in it: without an expiry only while one of them has none.
+const WRITE_FUNCTIONS: &str = r#"
+local function expire(key, ttl, retention)
+    if ttl ~= '0' then
+        redis.call('HSET', key, 'ttl', ttl)
+        redis.call('PEXPIRE', key, ttl)
+    else
+        redis.call('HDEL', key, 'ttl')
+        if retention ~= '0' then
+            redis.call('PEXPIRE', key, retention)
+        else
+            redis.call('PERSIST', key)
+        end
+    end
+end
+
+local function add_to_index(index, key)
+    local persistent = redis.call('PTTL', index) == -1
+    redis.call('SADD', index, key)
+    local lifetime = redis.call('PTTL', key)
+    if lifetime == -1 then
+        redis.call('PERSIST', index)
+        return
+    end
+    if persistent then
+        -- The keys the index was kept for may have expired or got an expiry since.
+        for _, member in ipairs(redis.call('SMEMBERS', index)) do
+            local ttl = redis.call('PTTL', member)
+            if ttl == -1 then
+                return
+            elseif ttl == -2 then
+                redis.call('SREM', index, member)
+            elseif ttl > lifetime then
+                lifetime = ttl
+            end
+        end
+    end
+    if redis.call('PTTL', index) < lifetime then
+        redis.call('PEXPIRE', index, lifetime)
+    end
+end
+"#;
+
+/// Writes a key if `condition` holds for its current value, atomically on the Redis server,
+/// so that conditional writes need no connection of their own to WATCH.
+/// Replies `{1, new version, incremented value}`, or `{0, current value, current version}`.
+const WRITE_SCRIPT: &str = r#"
+local index, key = KEYS[1], KEYS[2]
+local condition, expected, operation, operand, expiry, ttl, retention = unpack(ARGV)
+local current = redis.call('HMGET', key, 'value', 'version')
+local value, version = current[1], current[2] or '0'
+if (condition == 'version' and version ~= expected) or (condition == 'absent' and value) then
+    return {0, value, version}
+end
+local result
+if operation == 'set' then
+    redis.call('HSET', key, 'value', operand)
+elseif operation == 'delete' then
+    redis.call('HDEL', key, 'value')
+else
+    -- Fails without writing when the value is not an integer or the result overflows.
+    if type(redis.pcall('HINCRBY', key, 'value', operand)) == 'table' then
+        return {0, value, version}
+    end
+    -- Read back as a string, as Lua numbers can't hold every 64-bit integer.
+    result = redis.call('HGET', key, 'value')
+end
+if expiry == 'new' then
+    expiry = value and 'keep' or 'set'
+end
+if expiry == 'set' then
+    expire(key, ttl, retention)
+end
+add_to_index(index, key)
+return {1, redis.call('HINCRBY', key, 'version', 1), result}
+"#;
+
+/// Sets every key in `KEYS[2..]` to the value at the same position in `ARGV[2..]` without a TTL,
+/// and replies their new versions.
+const SAVE_ALL_SCRIPT: &str = r#"
+local index, retention = KEYS[1], ARGV[1]
+local versions = {}
+for i = 2, #KEYS do
+    redis.call('HSET', KEYS[i], 'value', ARGV[i])
+    expire(KEYS[i], '0', retention)
+    add_to_index(index, KEYS[i])
+    versions[i - 1] = redis.call('HINCRBY', KEYS[i], 'version', 1)
+end
+return versions
+"#;
+
+/// Replies the keys in the index starting with `ARGV[1]` that have a value,
+/// and removes the expired ones from the index.
+const LIST_SCRIPT: &str = r#"
+local index, prefix = KEYS[1], ARGV[1]
+local keys = {}
+for _, key in ipairs(redis.call('SMEMBERS', index)) do
+    if redis.call('EXISTS', key) == 0 then
+        redis.call('SREM', index, key)
+    elseif string.sub(key, 1, #prefix) == prefix and redis.call('HEXISTS', key, 'value') == 1 then
+        table.insert(keys, key)
+    end
+end
+return keys
+"#;
+
+/// Expires the keys in the index without their own TTL after the retention period in `ARGV[1]`,
+/// and the index itself when its last key does, and removes the expired keys from the index.
+const REFRESH_SCRIPT: &str = r#"
+local index, retention = KEYS[1], ARGV[1]
+local lifetime = tonumber(retention)
+for _, key in ipairs(redis.call('SMEMBERS', index)) do
+    if redis.call('EXISTS', key) == 0 then
+        redis.call('SREM', index, key)
+    elseif redis.call('HEXISTS', key, 'ttl') == 0 then
+        redis.call('PEXPIRE', key, retention)
+    else
+        lifetime = math.max(lifetime, redis.call('PTTL', key))
+    end
+end
+if redis.call('PTTL', index) < lifetime then
+    redis.call('PEXPIRE', index, lifetime)
+end
+"#;
+
+/// Converts a key written by a server before versioned keys, stored as a string, to a hash at version 1.
+/// Counters were stored as 8 little endian bytes, and are converted to decimal strings.
+/// Replies 1 if the key was converted.
+const MIGRATE_SCRIPT: &str = r#"
+local index, key = KEYS[1], KEYS[2]
+if redis.call('TYPE', key).ok ~= 'string' then
+    return 0
+end
+local value = redis.call('GET', key)
+if string.sub(key, -8) == '/counter' and #value == 8 then
+    local counter = 0
+    for i = 8, 1, -1 do
+        counter = counter * 256 + string.byte(value, i)
+    end
+    value = string.format('%.0f', counter)
+end
+local ttl = redis.call('PTTL', key)
+redis.call('DEL', key)
+redis.call('HSET', key, 'value', value, 'version', 1)
+if ttl > 0 then
+    redis.call('PEXPIRE', key, ttl)
+end
+add_to_index(index, key)
+return 1
+"#;
+
+/// Stores each key as a hash of its value and version, and its own TTL in milliseconds if any.
+/// A deleted key keeps the hash without the value so that its version is not reset,
+/// until the deleted key TTL passes.
 pub(crate) struct RedisStateStore {
     room_id: RoomID,
     conn: redis::aio::ConnectionManager,
+    scripts: Scripts,
+    /// Keys without their own TTL expire after this period unless `refresh_expiry` is called.
+    retention: Option<Duration>,
+    deleted_key_ttl: Duration,
 }
 
 impl RedisStateStore {
     pub fn new(room_id: RoomID, conn: redis::aio::ConnectionManager) -> Self {
-        Self { room_id, conn }
+        Self {
+            room_id,
+            conn,
+            scripts: Scripts::new(),
+            retention: None,
+            deleted_key_ttl: DEFAULT_DELETED_KEY_TTL,
+        }
+    }
+
+    pub fn with_retention(mut self, retention: Option<Duration>) -> Self {
+        self.retention = retention;
+        self
     }
 
-    fn redis_key(&self, key: StateKey) -> String {
+    #[cfg(test)]
+    fn with_deleted_key_ttl(mut self, deleted_key_ttl: Duration) -> Self {
+        self.deleted_key_ttl = deleted_key_ttl;
+        self
+    }
+
+    fn redis_key(&self, key: &str) -> String {
         format!("{}/{}", self.room_id, key)
     }
+
+    /// A set of the Redis keys of the room, including deleted ones.
+    fn index_key(&self) -> String {
+        format!("keys/{}", self.room_id)
+    }
+
+    async fn write(
+        &mut self,
+        key: &str,
+        condition: Condition,
+        operation: Operation,
+        expiry: Expiry,
+    ) -> crate::Result<Written> {
+        let mut invocation = self.scripts.write.key(self.index_key());
+        invocation.key(self.redis_key(key));
+        match condition {
+            Condition::Always => invocation.arg("always").arg(0),
+            Condition::Version(version) => invocation.arg("version").arg(version),
+            Condition::Absent => invocation.arg("absent").arg(0),
+        };
+        match operation {
+            Operation::Set(data) => invocation.arg("set").arg(data),
+            Operation::Delete => invocation.arg("delete").arg(0),
+            Operation::Increment(delta) => invocation.arg("increment").arg(delta),
+        };
+        match expiry {
+            Expiry::Set(ttl) => invocation.arg("set").arg(ttl.map_or(0, millis)),
+            Expiry::New => invocation.arg("new").arg(0),
+        };
+        invocation.arg(self.retention.map_or(0, millis));
+        let reply: Vec<redis::Value> = invocation
+            .invoke_async(&mut self.conn)
+            .await
+            .context("failed to write")?;
+        let field = |i: usize| reply.get(i).unwrap_or(&redis::Value::Nil);
+        let written = if redis::from_redis_value(field(0))? {
+            Written::Done {
+                version: redis::from_redis_value(field(1))?,
+                value: redis::from_redis_value(field(2))?,
+            }
+        } else {
+            Written::Rejected(VersionedData {
+                data: redis::from_redis_value(field(1))?,
+                version: redis::from_redis_value(field(2))?,
+            })
+        };
+        Ok(written)
+    }
+}
+
+struct Scripts {
+    write: redis::Script,
+    save_all: redis::Script,
+    list: redis::Script,
+    refresh: redis::Script,
+    migrate: redis::Script,
+}
+
+impl Scripts {
+    fn new() -> Self {
+        Self {
+            write: redis::Script::new(&format!("{}{}", WRITE_FUNCTIONS, WRITE_SCRIPT)),
+            save_all: redis::Script::new(&format!("{}{}", WRITE_FUNCTIONS, SAVE_ALL_SCRIPT)),
+            list: redis::Script::new(LIST_SCRIPT),
+            refresh: redis::Script::new(REFRESH_SCRIPT),
+            migrate: redis::Script::new(&format!("{}{}", WRITE_FUNCTIONS, MIGRATE_SCRIPT)),
+        }
+    }
+}
+
+/// What the current value of a key must be for a write to happen.
+#[derive(Debug, Clone, Copy)]
+enum Condition {
+    Always,
+    Version(StateVersion),
+    /// The key has no value, though it may have a version.
+    Absent,
+}
+
+#[derive(Debug)]
+enum Operation {
+    Set(StateData),
+    Delete,
+    /// Adds to the value stored as a decimal string, which fails when it is not an integer.
+    Increment(i64),
+}
+
+#[derive(Debug)]
+enum Written {
+    /// `value` is the result of `Operation::Increment`.
+    Done {
+        version: StateVersion,
+        value: Option<i64>,
+    },
+    Rejected(VersionedData),
+}
+
+/// Redis rejects expiry times that overflow when added to the current time, so longer ones are capped.
+/// Converts the room state keys written by servers before versioned keys, which fail with WRONGTYPE,
+/// and returns how many were converted. Keys already converted are left as they are,
+/// so it is safe to run on every start.
+pub(crate) async fn migrate(conn: &mut redis::aio::ConnectionManager) -> crate::Result<usize> {
+    let mut keys = vec![];
+    let mut scan_conn = conn.clone();
+    let mut iter: redis::AsyncIter<String> = scan_conn
+        .scan_match("*/*")
+        .await
+        .context("failed to scan")?;
+    while let Some(key) = iter.next_item().await {
+        keys.push(key);
+    }
+    drop(iter);
+    let scripts = Scripts::new();
+    let mut migrated = 0;
+    for key in keys {
+        let room_id = match key
+            .split_once('/')
+            .map(|(room_id, _)| room_id.parse::<RoomID>())
+        {
+            Some(Ok(room_id)) => room_id,
+            _ => continue,
+        };
+        let converted: bool = scripts
+            .migrate
+            .key(format!("keys/{}", room_id))
+            .key(&key)
+            .invoke_async(conn)
+            .await
+            .context("failed to migrate")?;
+        migrated += converted as usize;
+    }
+    Ok(migrated)
+}
+
+fn millis(duration: Duration) -> usize {
+    const MAX_EXPIRY: Duration = Duration::from_secs(100 * 365 * 24 * 60 * 60);
+    duration.min(MAX_EXPIRY).as_millis() as usize
 }
 
 #[async_trait]
 impl RoomStateStore for RedisStateStore {
-    async fn get_state(&mut self, key: StateKey) -> crate::Result<Option<StateData>> {
-        self.conn
-            .get(self.redis_key(key))
+    async fn get_state(&mut self, key: StateKey) -> crate::Result<VersionedData> {
+        let (data, version): (Option<StateData>, Option<StateVersion>) = self
+            .conn
+            .hget(self.redis_key(&key), &[VALUE_FIELD, VERSION_FIELD])
+            .await
+            .context("failed to get")?;
+        Ok(VersionedData {
+            data,
+            version: version.unwrap_or_default(),
+        })
+    }
+
+    async fn list_state_keys(&mut self, prefix: &str) -> crate::Result<Vec<StateKey>> {
+        let room_prefix = self.redis_key("");
+        let redis_keys: Vec<String> = self
+            .scripts
+            .list
+            .key(self.index_key())
+            .arg(self.redis_key(prefix))
+            .invoke_async(&mut self.conn)
             .await
-            .context("failed to get")
+            .context("failed to list")?;
+        Ok(redis_keys
+            .into_iter()
+            .map(|redis_key| redis_key[room_prefix.len()..].to_string())
+            .collect())
     }
 
-    async fn save_state(&mut self, key: StateKey, data: StateData) -> crate::Result<()> {
-        self.conn
-            .set(self.redis_key(key), data)
+    async fn get_states(&mut self, keys: Vec<StateKey>) -> crate::Result<Vec<VersionedData>> {
+        if keys.is_empty() {
+            return Ok(vec![]);
+        }
+        let mut pipe = redis::pipe();
+        for key in &keys {
+            pipe.hget(self.redis_key(key), &[VALUE_FIELD, VERSION_FIELD]);
+        }
+        // Decoded one by one, as a Vec of tuples would be read from the flattened replies.
+        let values: Vec<redis::Value> = pipe
+            .query_async(&mut self.conn)
+            .await
+            .context("failed to get")?;
+        values
+            .iter()
+            .map(|value| {
+                let (data, version): (Option<StateData>, Option<StateVersion>) =
+                    redis::from_redis_value(value).context("failed to get")?;
+                Ok(VersionedData {
+                    data,
+                    version: version.unwrap_or_default(),
+                })
+            })
+            .collect()
+    }
+
+    async fn save_states(
+        &mut self,
+        states: Vec<(StateKey, StateData)>,
+    ) -> crate::Result<Vec<StateVersion>> {
+        if states.is_empty() {
+            return Ok(vec![]);
+        }
+        let mut invocation = self.scripts.save_all.key(self.index_key());
+        invocation.arg(self.retention.map_or(0, millis));
+        for (key, data) in states {
+            invocation.key(self.redis_key(&key)).arg(data);
+        }
+        invocation
+            .invoke_async(&mut self.conn)
             .await
             .context("failed to set")
     }
 
-    async fn delete_state(&mut self, key: StateKey) -> crate::Result<()> {
-        self.conn
-            .del(self.redis_key(key))
+    async fn save_state(
+        &mut self,
+        key: StateKey,
+        data: StateData,
+        expected_version: Option<StateVersion>,
+        ttl: Option<Duration>,
+    ) -> crate::Result<SwapResult> {
+        let condition = expected_version.map_or(Condition::Always, Condition::Version);
+        let written = self
+            .write(&key, condition, Operation::Set(data), Expiry::Set(ttl))
+            .await?;
+        Ok(swap_result(written))
+    }
+
+    async fn delete_state(
+        &mut self,
+        key: StateKey,
+        expected_version: Option<StateVersion>,
+    ) -> crate::Result<SwapResult> {
+        let condition = expected_version.map_or(Condition::Always, Condition::Version);
+        // Deleted keys are kept only for their versions, so they expire even without retention.
+        let expiry = Expiry::Set(Some(self.deleted_key_ttl));
+        let written = self
+            .write(&key, condition, Operation::Delete, expiry)
+            .await?;
+        Ok(swap_result(written))
+    }
+
+    async fn increment_state(
+        &mut self,
+        key: StateKey,
+        delta: i64,
+    ) -> crate::Result<Option<(i64, StateVersion)>> {
+        let written = self
+            .write(
+                &key,
+                Condition::Always,
+                Operation::Increment(delta),
+                Expiry::New,
+            )
+            .await?;
+        match written {
+            Written::Done { version, value } => Ok(value.map(|value| (value, version))),
+            Written::Rejected(_) => Ok(None),
+        }
+    }
+
+    async fn save_state_if_absent(
+        &mut self,
+        key: StateKey,
+        data: StateData,
+    ) -> crate::Result<Option<StateVersion>> {
+        let written = self
+            .write(&key, Condition::Absent, Operation::Set(data), Expiry::New)
+            .await?;
+        match written {
+            Written::Done { version, .. } => Ok(Some(version)),
+            Written::Rejected(_) => Ok(None),
+        }
+    }
+
+    async fn refresh_expiry(&mut self) -> crate::Result<()> {
+        let retention = match self.retention {
+            Some(retention) => retention,
+            None => return Ok(()),
+        };
+        self.scripts
+            .refresh
+            .key(self.index_key())
+            .arg(millis(retention))
+            .invoke_async(&mut self.conn)
             .await
-            .context("failed to delete")
+            .context("failed to expire")
+    }
+}
+
+fn swap_result(written: Written) -> SwapResult {
+    match written {
+        Written::Done { version, .. } => SwapResult::Swapped { version },
+        Written::Rejected(current) => SwapResult::Conflict { current },
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::room_states::redis::RedisStateStore;
-    use crate::room_states::{RoomStateStore, StateData};
+    use crate::room_states::redis::{migrate, RedisStateStore};
+    use crate::room_states::{RoomStateStore, StateData, SwapResult, VersionedData};
     use crate::types::RoomID;
+    use redis::AsyncCommands;
+    use std::time::Duration;
 
-    #[tokio::test]
-    async fn test_redis() {
+    async fn new_store(room_id: RoomID) -> RedisStateStore {
         let client = redis::Client::open("redis://127.0.0.1").unwrap();
         let conn = client.get_tokio_connection_manager().await.unwrap();
-        let room_id = RoomID::new_v4();
-        let mut store = RedisStateStore::new(room_id, conn);
+        RedisStateStore::new(room_id, conn)
+    }
+
+    #[tokio::test]
+    async fn test_redis() {
+        let mut store = new_store(RoomID::new_v4()).await;
         let missing = store.get_state("test".to_string()).await.unwrap();
-        assert_eq!(missing, None);
+        assert_eq!(missing, VersionedData::default());
         store
-            .save_state("test".to_string(), "hello".into())
+            .save_state("test".to_string(), "hello".into(), None, None)
             .await
             .unwrap();
         let data = store.get_state("test".to_string()).await.unwrap();
-        assert_eq!(data, Some(StateData::from("hello")));
-        store.delete_state("test".to_string()).await.unwrap();
+        assert_eq!(data.data, Some(StateData::from("hello")));
+        store.delete_state("test".to_string(), None).await.unwrap();
         let missing = store.get_state("test".to_string()).await.unwrap();
-        assert_eq!(missing, None);
+        assert_eq!(missing.data, None);
+    }
+
+    #[tokio::test]
+    async fn versions() {
+        let mut store = new_store(RoomID::new_v4()).await;
+        let key = "test".to_string();
+        let result = store
+            .save_state(key.clone(), "a".into(), Some(1), None)
+            .await;
+        assert_eq!(
+            result.unwrap(),
+            SwapResult::Conflict {
+                current: VersionedData::default()
+            }
+        );
+
+        let result = store
+            .save_state(key.clone(), "a".into(), Some(0), None)
+            .await;
+        assert_eq!(result.unwrap(), SwapResult::Swapped { version: 1 });
+        let result = store.save_state(key.clone(), "b".into(), None, None).await;
+        assert_eq!(result.unwrap(), SwapResult::Swapped { version: 2 });
+
+        let result = store
+            .save_state(key.clone(), "c".into(), Some(1), None)
+            .await;
+        assert_eq!(
+            result.unwrap(),
+            SwapResult::Conflict {
+                current: VersionedData {
+                    data: Some("b".into()),
+                    version: 2
+                }
+            }
+        );
+        let result = store.delete_state(key.clone(), Some(1)).await;
+        assert!(matches!(result.unwrap(), SwapResult::Conflict { .. }));
+
+        // The version keeps increasing after the key is deleted and written again.
+        let result = store.delete_state(key.clone(), Some(2)).await;
+        assert_eq!(result.unwrap(), SwapResult::Swapped { version: 3 });
+        let current = store.get_state(key.clone()).await.unwrap();
+        assert_eq!(
+            current,
+            VersionedData {
+                data: None,
+                version: 3
+            }
+        );
+        let result = store.save_state(key.clone(), "d".into(), None, None).await;
+        assert_eq!(result.unwrap(), SwapResult::Swapped { version: 4 });
+    }
+
+    #[tokio::test]
+    async fn get_by_prefix() {
+        let mut store = new_store(RoomID::new_v4()).await;
+        for key in ["player/1", "player/2", "player*", "item/1"] {
+            store
+                .save_state(key.to_string(), key.into(), None, None)
+                .await
+                .unwrap();
+        }
+        store
+            .delete_state("player/2".to_string(), None)
+            .await
+            .unwrap();
+
+        let mut states = store.get_state_by_prefix("player").await.unwrap();
+        states.sort_by(|a, b| a.0.cmp(&b.0));
+        let keys: Vec<_> = states.iter().map(|(key, _)| key.as_str()).collect();
+        assert_eq!(keys, vec!["player*", "player/1"]);
+        assert_eq!(states[1].1.data, Some(StateData::from("player/1")));
+
+        let states = store.get_state_by_prefix("player*").await.unwrap();
+        assert_eq!(states.len(), 1);
+        let states = store.get_state_by_prefix("").await.unwrap();
+        assert_eq!(states.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn bulk() {
+        let mut store = new_store(RoomID::new_v4()).await;
+        let versions = store
+            .save_states(vec![
+                ("a".to_string(), "1".into()),
+                ("b".to_string(), "2".into()),
+                ("a".to_string(), "3".into()),
+            ])
+            .await
+            .unwrap();
+        assert_eq!(versions, vec![1, 1, 2]);
+
+        let mut keys = store.list_state_keys("").await.unwrap();
+        keys.sort();
+        assert_eq!(keys, vec!["a".to_string(), "b".to_string()]);
+
+        let values = store
+            .get_states(vec!["b".to_string(), "c".to_string(), "a".to_string()])
+            .await
+            .unwrap();
+        assert_eq!(
+            values,
+            vec![
+                VersionedData {
+                    data: Some("2".into()),
+                    version: 1
+                },
+                VersionedData::default(),
+                VersionedData {
+                    data: Some("3".into()),
+                    version: 2
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn expiry() {
+        let retention = Duration::from_secs(10);
+        let mut store = new_store(RoomID::new_v4())
+            .await
+            .with_retention(Some(retention));
+        let ttl = Duration::from_millis(100);
+        store
+            .save_state("short".to_string(), "a".into(), None, Some(ttl))
+            .await
+            .unwrap();
+        store
+            .save_state("long".to_string(), "b".into(), None, None)
+            .await
+            .unwrap();
+        store
+            .increment_state("counter".to_string(), 1)
+            .await
+            .unwrap();
+        assert!(pttl(&mut store, "short").await <= 100);
+        assert!(pttl(&mut store, "long").await > 100);
+        assert!(pttl(&mut store, "counter").await > 100);
+
+        // Keys without their own TTL get the retention period again.
+        let _: () = store.conn.persist(store.redis_key("long")).await.unwrap();
+        store.refresh_expiry().await.unwrap();
+        assert!(pttl(&mut store, "long").await > 100);
+        assert!(pttl(&mut store, "short").await <= 100);
+
+        tokio::time::sleep(ttl * 2).await;
+        let state = store.get_state("short".to_string()).await.unwrap();
+        assert_eq!(state, VersionedData::default());
+        let state = store.get_state("long".to_string()).await.unwrap();
+        assert_eq!(state.data, Some(StateData::from("b")));
+    }
+
+    #[tokio::test]
+    async fn deleted_key_expiry() {
+        let ttl = Duration::from_millis(100);
+        let mut store = new_store(RoomID::new_v4()).await.with_deleted_key_ttl(ttl);
+        store
+            .save_state("test".to_string(), "a".into(), None, None)
+            .await
+            .unwrap();
+        store.delete_state("test".to_string(), None).await.unwrap();
+        let state = store.get_state("test".to_string()).await.unwrap();
+        assert_eq!(state.version, 2);
+
+        // Writing again gives the key back the expiry of the room state.
+        store.increment_state("test".to_string(), 1).await.unwrap();
+        assert_eq!(pttl(&mut store, "test").await, -1);
+        store.delete_state("test".to_string(), None).await.unwrap();
+
+        tokio::time::sleep(ttl * 2).await;
+        let exists: bool = store.conn.exists(store.redis_key("test")).await.unwrap();
+        assert!(!exists);
+        assert!(store.list_state_keys("").await.unwrap().is_empty());
+        let index: Vec<String> = store.conn.smembers(store.index_key()).await.unwrap();
+        assert!(index.is_empty());
+    }
+
+    #[tokio::test]
+    async fn bulk_expiry() {
+        let retention = Duration::from_secs(10);
+        let mut store = new_store(RoomID::new_v4())
+            .await
+            .with_retention(Some(retention));
+        let ttl = Duration::from_millis(100);
+        store
+            .save_state("a".to_string(), "1".into(), None, Some(ttl))
+            .await
+            .unwrap();
+
+        // Like `save_state` without a TTL, batch writes expire with the rest of the room state.
+        store
+            .save_states(vec![
+                ("a".to_string(), "2".into()),
+                ("b".to_string(), "3".into()),
+            ])
+            .await
+            .unwrap();
+        assert!(pttl(&mut store, "a").await > 100);
+        assert!(pttl(&mut store, "b").await > 100);
+        let _: () = store.conn.persist(store.redis_key("a")).await.unwrap();
+        store.refresh_expiry().await.unwrap();
+        assert!(pttl(&mut store, "a").await > 100);
+
+        tokio::time::sleep(ttl * 2).await;
+        let state = store.get_state("a".to_string()).await.unwrap();
+        assert_eq!(state.data, Some(StateData::from("2")));
+    }
+
+    #[tokio::test]
+    async fn index_expiry() {
+        let ttl = Duration::from_millis(100);
+        let mut store = new_store(RoomID::new_v4()).await.with_deleted_key_ttl(ttl);
+        store
+            .save_state("a".to_string(), "1".into(), None, None)
+            .await
+            .unwrap();
+        store
+            .save_state("b".to_string(), "2".into(), None, None)
+            .await
+            .unwrap();
+        let index_ttl: i64 = store.conn.pttl(store.index_key()).await.unwrap();
+        assert_eq!(index_ttl, -1);
+
+        // The index is kept without an expiry while any of its keys has none.
+        store.delete_state("a".to_string(), None).await.unwrap();
+        let index_ttl: i64 = store.conn.pttl(store.index_key()).await.unwrap();
+        assert_eq!(index_ttl, -1);
+        store.delete_state("b".to_string(), None).await.unwrap();
+        let index_ttl: i64 = store.conn.pttl(store.index_key()).await.unwrap();
+        assert!(index_ttl > 0 && index_ttl <= 100);
+
+        tokio::time::sleep(ttl * 2).await;
+        let exists: bool = store.conn.exists(store.index_key()).await.unwrap();
+        assert!(!exists);
+    }
+
+    #[tokio::test]
+    async fn migrate_old_keys() {
+        let mut store = new_store(RoomID::new_v4()).await;
+        let _: () = store
+            .conn
+            .set(store.redis_key("test"), "hello")
+            .await
+            .unwrap();
+        let _: () = store
+            .conn
+            .set(store.redis_key("counter"), 5usize.to_le_bytes().to_vec())
+            .await
+            .unwrap();
+        assert!(migrate(&mut store.conn).await.unwrap() >= 2);
+
+        let state = store.get_state("test".to_string()).await.unwrap();
+        assert_eq!(
+            state,
+            VersionedData {
+                data: Some("hello".into()),
+                version: 1
+            }
+        );
+        let counter = store.increment_state("counter".to_string(), 1).await;
+        assert_eq!(counter.unwrap(), Some((6, 2)));
+        let mut keys = store.list_state_keys("").await.unwrap();
+        keys.sort();
+        assert_eq!(keys, vec!["counter".to_string(), "test".to_string()]);
+    }
+
+    async fn pttl(store: &mut RedisStateStore, key: &str) -> i64 {
+        store.conn.pttl(store.redis_key(key)).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn increment() {
+        let room_id = RoomID::new_v4();
+        let mut tasks = vec![];
+        for _ in 0..4 {
+            let mut store = new_store(room_id).await;
+            tasks.push(tokio::spawn(async move {
+                for _ in 0..25 {
+                    store
+                        .increment_state("counter".to_string(), 1)
+                        .await
+                        .unwrap()
+                        .unwrap();
+                }
+            }));
+        }
+        for task in tasks {
+            task.await.unwrap();
+        }
+        let mut store = new_store(room_id).await;
+        let data = store.get_state("counter".to_string()).await.unwrap();
+        assert_eq!(
+            data,
+            VersionedData {
+                data: Some("100".into()),
+                version: 100
+            }
+        );
+        let value = store.increment_state("counter".to_string(), -101).await;
+        assert_eq!(value.unwrap(), Some((-1, 101)));
+
+        store
+            .save_state("text".to_string(), "hello".into(), None, None)
+            .await
+            .unwrap();
+        let value = store.increment_state("text".to_string(), 1).await;
+        assert_eq!(value.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn save_if_absent() {
+        let mut store = new_store(RoomID::new_v4()).await;
+        let key = "test".to_string();
+        let saved = store.save_state_if_absent(key.clone(), "a".into()).await;
+        assert_eq!(saved.unwrap(), Some(1));
+        let saved = store.save_state_if_absent(key.clone(), "b".into()).await;
+        assert_eq!(saved.unwrap(), None);
+        let data = store.get_state(key.clone()).await.unwrap();
+        assert_eq!(data.data, Some(StateData::from("a")));
+
+        store.delete_state(key.clone(), None).await.unwrap();
+        let saved = store.save_state_if_absent(key, "c".into()).await;
+        assert_eq!(saved.unwrap(), Some(3));
     }
 }
@@ -0,0 +1,720 @@
+use crate::room_states::{
+    Expiry, RoomStateStore, StateData, StateKey, StateVersion, SwapResult, VersionedData,
+    DEFAULT_DELETED_KEY_TTL,
+};
+use crate::types::RoomID;
+use anyhow::Context;
+use async_trait::async_trait;
+use binrw::{binrw, BinRead, BinWrite};
+use sled::transaction::{
+    ConflictableTransactionError, ConflictableTransactionResult, TransactionError,
+    TransactionalTree,
+};
+use std::io::Cursor;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+/// Stores each key of a room under the room ID followed by the key.
+/// A deleted key keeps its entry without the value so that its version is not reset,
+/// until the deleted key TTL passes.
+/// Writes reach the disk when sled flushes them, every 500 ms by default, and when `refresh_expiry`
+/// is called, so the writes of the last moments before the process is killed may be lost.
+pub(crate) struct SledStateStore {
+    room_id: RoomID,
+    tree: sled::Tree,
+    /// Keys without their own TTL expire after this period unless `refresh_expiry` is called.
+    retention: Option<Duration>,
+    deleted_key_ttl: Duration,
+}
+
+#[binrw]
+#[brw(little)]
+#[derive(Debug, Default)]
+struct Entry {
+    version: StateVersion,
+    /// Milliseconds since the Unix epoch, or 0 when the entry doesn't expire.
+    /// The wall clock is used so that expiry carries over restarts.
+    expires_at: u64,
+    /// Milliseconds, or 0 unless the key has its own TTL.
+    ttl: u64,
+    #[br(map = |x: u8| x != 0)]
+    #[bw(map = |x: &bool| *x as u8)]
+    has_data: bool,
+    #[br(temp)]
+    #[bw(calc = data.len() as u32)]
+    data_size: u32,
+    #[br(count = data_size)]
+    data: Vec<u8>,
+}
+
+impl Entry {
+    fn from_bytes(bytes: &[u8]) -> crate::Result<Self> {
+        Entry::read(&mut Cursor::new(bytes)).context("failed to decode room state")
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut cursor = Cursor::new(vec![]);
+        self.write_to(&mut cursor).unwrap();
+        cursor.into_inner()
+    }
+
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at != 0 && self.expires_at <= now
+    }
+
+    fn versioned_data(&self) -> VersionedData {
+        VersionedData {
+            data: self.has_data.then(|| self.data.clone()),
+            version: self.version,
+        }
+    }
+}
+
+impl SledStateStore {
+    pub fn new(room_id: RoomID, tree: sled::Tree) -> Self {
+        Self {
+            room_id,
+            tree,
+            retention: None,
+            deleted_key_ttl: DEFAULT_DELETED_KEY_TTL,
+        }
+    }
+
+    pub fn with_retention(mut self, retention: Option<Duration>) -> Self {
+        self.retention = retention;
+        self
+    }
+
+    #[cfg(test)]
+    fn with_deleted_key_ttl(mut self, deleted_key_ttl: Duration) -> Self {
+        self.deleted_key_ttl = deleted_key_ttl;
+        self
+    }
+
+    fn db_key(&self, key: &str) -> Vec<u8> {
+        let mut db_key = self.room_id.as_bytes().to_vec();
+        db_key.extend_from_slice(key.as_bytes());
+        db_key
+    }
+
+    fn room_expiry(&self) -> u64 {
+        self.retention
+            .map(|retention| expires_at(now(), retention))
+            .unwrap_or_default()
+    }
+
+    /// Runs `f` atomically. It is run again if another transaction modifies the same keys.
+    async fn transaction<T: Send + 'static>(
+        &self,
+        f: impl Fn(&TransactionalTree) -> ConflictableTransactionResult<T, anyhow::Error>
+            + Send
+            + 'static,
+    ) -> crate::Result<T> {
+        let tree = self.tree.clone();
+        blocking(move || tree.transaction(f).map_err(transaction_error)).await
+    }
+
+    /// Returns the keys of the room starting with `prefix`, with their live entries.
+    async fn scan(&self, prefix: &str) -> crate::Result<Vec<(StateKey, Entry)>> {
+        let tree = self.tree.clone();
+        let room_prefix_len = self.db_key("").len();
+        let db_prefix = self.db_key(prefix);
+        blocking(move || {
+            let now = now();
+            let mut entries = vec![];
+            for item in tree.scan_prefix(db_prefix) {
+                let (db_key, bytes) = item.context("failed to scan")?;
+                let entry = Entry::from_bytes(&bytes)?;
+                if entry.is_expired(now) {
+                    continue;
+                }
+                let key = String::from_utf8(db_key[room_prefix_len..].to_vec())
+                    .context("invalid room state key")?;
+                entries.push((key, entry));
+            }
+            Ok(entries)
+        })
+        .await
+    }
+}
+
+/// Runs `f` on a thread where blocking is allowed, since sled reads and writes block on disk.
+async fn blocking<T: Send + 'static>(
+    f: impl FnOnce() -> crate::Result<T> + Send + 'static,
+) -> crate::Result<T> {
+    tokio::task::spawn_blocking(f)
+        .await
+        .context("room state task failed")?
+}
+
+fn transaction_error(err: TransactionError<anyhow::Error>) -> anyhow::Error {
+    match err {
+        TransactionError::Abort(err) => err,
+        TransactionError::Storage(err) => anyhow::Error::new(err).context("failed to write"),
+    }
+}
+
+/// Removes the expired entries of every room in `tree`.
+/// Reads skip them anyway, but otherwise they stay on disk unless their room refreshes its expiry.
+fn purge_expired(tree: &sled::Tree) -> crate::Result<()> {
+    let now = now();
+    for item in tree.iter() {
+        let (db_key, bytes) = item.context("failed to scan")?;
+        if Entry::from_bytes(&bytes)?.is_expired(now) {
+            // Keeps the entry if it has been written in the meantime.
+            let _ = tree
+                .compare_and_swap(&db_key, Some(&bytes), None as Option<&[u8]>)
+                .context("failed to remove expired room state")?;
+        }
+    }
+    Ok(())
+}
+
+/// Purges expired entries from `tree` at startup and then every `interval`.
+pub(crate) async fn sweep_expired(tree: sled::Tree, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        let tree = tree.clone();
+        if let Err(err) = blocking(move || purge_expired(&tree)).await {
+            warn!("failed to purge expired room state: {:?}", err);
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+fn millis(duration: Duration) -> u64 {
+    u64::try_from(duration.as_millis()).unwrap_or(u64::MAX)
+}
+
+fn expires_at(now: u64, ttl: Duration) -> u64 {
+    now.saturating_add(millis(ttl))
+}
+
+/// Reads the entry of `db_key`, treating an expired one as missing.
+fn read(
+    tx: &TransactionalTree,
+    db_key: &[u8],
+) -> ConflictableTransactionResult<Option<Entry>, anyhow::Error> {
+    let entry = match tx.get(db_key)? {
+        Some(bytes) => Entry::from_bytes(&bytes).map_err(ConflictableTransactionError::Abort)?,
+        None => return Ok(None),
+    };
+    Ok(Some(entry).filter(|entry| !entry.is_expired(now())))
+}
+
+/// Writes `data`, or deletes the value when it is `None`, and returns the new entry.
+fn write(
+    tx: &TransactionalTree,
+    db_key: &[u8],
+    current: Option<Entry>,
+    data: Option<StateData>,
+    expiry: Expiry,
+    room_expiry: u64,
+) -> ConflictableTransactionResult<Entry, anyhow::Error> {
+    let mut entry = current.unwrap_or_default();
+    let ttl = match expiry {
+        Expiry::Set(ttl) => Some(ttl),
+        Expiry::New if !entry.has_data => Some(None),
+        Expiry::New => None,
+    };
+    if let Some(ttl) = ttl {
+        (entry.ttl, entry.expires_at) = match ttl {
+            Some(ttl) => (millis(ttl), expires_at(now(), ttl)),
+            None => (0, room_expiry),
+        };
+    }
+    entry.has_data = data.is_some();
+    entry.data = data.unwrap_or_default();
+    entry.version += 1;
+    tx.insert(db_key, entry.to_bytes())?;
+    Ok(entry)
+}
+
+fn versioned_data(entry: &Option<Entry>) -> VersionedData {
+    entry
+        .as_ref()
+        .map(Entry::versioned_data)
+        .unwrap_or_default()
+}
+
+#[async_trait]
+impl RoomStateStore for SledStateStore {
+    async fn get_state(&mut self, key: StateKey) -> crate::Result<VersionedData> {
+        let db_key = self.db_key(&key);
+        let tree = self.tree.clone();
+        let entry = blocking(move || match tree.get(db_key).context("failed to get")? {
+            Some(bytes) => Ok(Some(Entry::from_bytes(&bytes)?)),
+            None => Ok(None),
+        })
+        .await?;
+        let entry = entry.filter(|entry| !entry.is_expired(now()));
+        Ok(versioned_data(&entry))
+    }
+
+    async fn list_state_keys(&mut self, prefix: &str) -> crate::Result<Vec<StateKey>> {
+        Ok(self
+            .scan(prefix)
+            .await?
+            .into_iter()
+            .filter(|(_, entry)| entry.has_data)
+            .map(|(key, _)| key)
+            .collect())
+    }
+
+    async fn get_states(&mut self, keys: Vec<StateKey>) -> crate::Result<Vec<VersionedData>> {
+        let db_keys: Vec<_> = keys.iter().map(|key| self.db_key(key)).collect();
+        // Read in a transaction so that the values are from the same point in time.
+        self.transaction(move |tx| {
+            db_keys
+                .iter()
+                .map(|db_key| Ok(versioned_data(&read(tx, db_key)?)))
+                .collect()
+        })
+        .await
+    }
+
+    async fn save_states(
+        &mut self,
+        states: Vec<(StateKey, StateData)>,
+    ) -> crate::Result<Vec<StateVersion>> {
+        let room_expiry = self.room_expiry();
+        let states: Vec<_> = states
+            .into_iter()
+            .map(|(key, data)| (self.db_key(&key), data))
+            .collect();
+        self.transaction(move |tx| {
+            states
+                .iter()
+                .map(|(db_key, data)| {
+                    let current = read(tx, db_key)?;
+                    let data = Some(data.clone());
+                    let entry = write(tx, db_key, current, data, Expiry::Set(None), room_expiry)?;
+                    Ok(entry.version)
+                })
+                .collect()
+        })
+        .await
+    }
+
+    async fn save_state(
+        &mut self,
+        key: StateKey,
+        data: StateData,
+        expected_version: Option<StateVersion>,
+        ttl: Option<Duration>,
+    ) -> crate::Result<SwapResult> {
+        let db_key = self.db_key(&key);
+        let room_expiry = self.room_expiry();
+        self.transaction(move |tx| {
+            let current = read(tx, &db_key)?;
+            let current_data = versioned_data(&current);
+            if matches!(expected_version, Some(expected) if expected != current_data.version) {
+                return Ok(SwapResult::Conflict {
+                    current: current_data,
+                });
+            }
+            let data = Some(data.clone());
+            let entry = write(tx, &db_key, current, data, Expiry::Set(ttl), room_expiry)?;
+            Ok(SwapResult::Swapped {
+                version: entry.version,
+            })
+        })
+        .await
+    }
+
+    async fn delete_state(
+        &mut self,
+        key: StateKey,
+        expected_version: Option<StateVersion>,
+    ) -> crate::Result<SwapResult> {
+        let db_key = self.db_key(&key);
+        let room_expiry = self.room_expiry();
+        // Deleted keys are kept only for their versions, so they expire even without retention.
+        let expiry = Expiry::Set(Some(self.deleted_key_ttl));
+        self.transaction(move |tx| {
+            let current = read(tx, &db_key)?;
+            let current_data = versioned_data(&current);
+            if matches!(expected_version, Some(expected) if expected != current_data.version) {
+                return Ok(SwapResult::Conflict {
+                    current: current_data,
+                });
+            }
+            let entry = write(tx, &db_key, current, None, expiry, room_expiry)?;
+            Ok(SwapResult::Swapped {
+                version: entry.version,
+            })
+        })
+        .await
+    }
+
+    async fn increment_state(
+        &mut self,
+        key: StateKey,
+        delta: i64,
+    ) -> crate::Result<Option<(i64, StateVersion)>> {
+        let db_key = self.db_key(&key);
+        let room_expiry = self.room_expiry();
+        self.transaction(move |tx| {
+            let current = read(tx, &db_key)?;
+            let value = match versioned_data(&current).data {
+                Some(data) => match parse_integer(&data) {
+                    Some(value) => value,
+                    None => return Ok(None),
+                },
+                None => 0,
+            };
+            let value = match value.checked_add(delta) {
+                Some(value) => value,
+                None => return Ok(None),
+            };
+            let data = value.to_string().into_bytes();
+            let entry = write(tx, &db_key, current, Some(data), Expiry::New, room_expiry)?;
+            Ok(Some((value, entry.version)))
+        })
+        .await
+    }
+
+    async fn save_state_if_absent(
+        &mut self,
+        key: StateKey,
+        data: StateData,
+    ) -> crate::Result<Option<StateVersion>> {
+        let db_key = self.db_key(&key);
+        let room_expiry = self.room_expiry();
+        self.transaction(move |tx| {
+            let current = read(tx, &db_key)?;
+            if versioned_data(&current).data.is_some() {
+                return Ok(None);
+            }
+            let data = Some(data.clone());
+            let entry = write(tx, &db_key, current, data, Expiry::New, room_expiry)?;
+            Ok(Some(entry.version))
+        })
+        .await
+    }
+
+    async fn refresh_expiry(&mut self) -> crate::Result<()> {
+        let room_expiry = self.room_expiry();
+        let tree = self.tree.clone();
+        let room_prefix = self.db_key("");
+        blocking(move || {
+            let now = now();
+            for item in tree.scan_prefix(room_prefix) {
+                let (db_key, _) = item.context("failed to scan")?;
+                tree.transaction(|tx| {
+                    let entry = tx
+                        .get(&db_key)?
+                        .map(|bytes| Entry::from_bytes(&bytes))
+                        .transpose()
+                        .map_err(ConflictableTransactionError::Abort)?;
+                    match entry {
+                        // Expired keys are only skipped when read, so they are removed here.
+                        Some(entry) if entry.is_expired(now) => {
+                            tx.remove(&db_key)?;
+                        }
+                        Some(mut entry) if entry.ttl == 0 && room_expiry != 0 => {
+                            entry.expires_at = room_expiry;
+                            tx.insert(&db_key, entry.to_bytes())?;
+                        }
+                        _ => {}
+                    }
+                    Ok(())
+                })
+                .map_err(transaction_error)?;
+            }
+            // Writes are otherwise flushed to disk periodically by sled.
+            tree.flush().context("failed to flush")?;
+            Ok(())
+        })
+        .await
+    }
+}
+
+fn parse_integer(data: &[u8]) -> Option<i64> {
+    std::str::from_utf8(data).ok()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::room_states::sled::{millis, now, purge_expired, Entry, SledStateStore};
+    use crate::room_states::{RoomStateStore, StateData, SwapResult, VersionedData};
+    use crate::types::RoomID;
+    use std::path::Path;
+    use std::time::Duration;
+
+    fn temporary_tree() -> sled::Tree {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        db.open_tree("room_states").unwrap()
+    }
+
+    #[tokio::test]
+    async fn versions() {
+        let tree = temporary_tree();
+        let room_id = RoomID::new_v4();
+        let mut store = SledStateStore::new(room_id, tree.clone());
+        let key = "test".to_string();
+        let result = store
+            .save_state(key.clone(), "a".into(), Some(1), None)
+            .await;
+        assert_eq!(
+            result.unwrap(),
+            SwapResult::Conflict {
+                current: VersionedData::default()
+            }
+        );
+        let result = store
+            .save_state(key.clone(), "a".into(), Some(0), None)
+            .await;
+        assert_eq!(result.unwrap(), SwapResult::Swapped { version: 1 });
+        let result = store.delete_state(key.clone(), Some(1)).await;
+        assert_eq!(result.unwrap(), SwapResult::Swapped { version: 2 });
+
+        // Other rooms don't see the key.
+        let mut other = SledStateStore::new(RoomID::new_v4(), tree.clone());
+        let missing = other.get_state(key.clone()).await.unwrap();
+        assert_eq!(missing, VersionedData::default());
+
+        let mut store = SledStateStore::new(room_id, tree);
+        let current = store.get_state(key.clone()).await.unwrap();
+        assert_eq!(
+            current,
+            VersionedData {
+                data: None,
+                version: 2
+            }
+        );
+        assert_eq!(
+            store.list_state_keys("").await.unwrap(),
+            Vec::<String>::new()
+        );
+        let saved = store.save_state_if_absent(key.clone(), "b".into()).await;
+        assert_eq!(saved.unwrap(), Some(3));
+        let saved = store.save_state_if_absent(key, "c".into()).await;
+        assert_eq!(saved.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn bulk() {
+        let mut store = SledStateStore::new(RoomID::new_v4(), temporary_tree());
+        let versions = store
+            .save_states(vec![
+                ("player/1".to_string(), "a".into()),
+                ("player/2".to_string(), "b".into()),
+                ("monster/1".to_string(), "c".into()),
+            ])
+            .await;
+        assert_eq!(versions.unwrap(), vec![1, 1, 1]);
+        let states = store.get_state_by_prefix("player/").await.unwrap();
+        assert_eq!(
+            states,
+            vec![
+                (
+                    "player/1".to_string(),
+                    VersionedData {
+                        data: Some("a".into()),
+                        version: 1
+                    }
+                ),
+                (
+                    "player/2".to_string(),
+                    VersionedData {
+                        data: Some("b".into()),
+                        version: 1
+                    }
+                ),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn increment() {
+        let mut store = SledStateStore::new(RoomID::new_v4(), temporary_tree());
+        let value = store.increment_state("counter".to_string(), 2).await;
+        assert_eq!(value.unwrap(), Some((2, 1)));
+        let value = store.increment_state("counter".to_string(), i64::MAX).await;
+        assert_eq!(value.unwrap(), None);
+        let data = store.get_state("counter".to_string()).await.unwrap();
+        assert_eq!(data.data, Some(StateData::from("2")));
+    }
+
+    #[tokio::test]
+    async fn expiry() {
+        let mut store = SledStateStore::new(RoomID::new_v4(), temporary_tree())
+            .with_retention(Some(Duration::from_millis(200)));
+        store
+            .save_state(
+                "short".to_string(),
+                "a".into(),
+                None,
+                Some(Duration::from_millis(50)),
+            )
+            .await
+            .unwrap();
+        store
+            .save_state("long".to_string(), "b".into(), None, None)
+            .await
+            .unwrap();
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        store.refresh_expiry().await.unwrap();
+        let keys = store.list_state_keys("").await.unwrap();
+        assert_eq!(keys, vec!["long".to_string()]);
+
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        let state = store.get_state("long".to_string()).await.unwrap();
+        assert_eq!(state.data, Some(StateData::from("b")));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let state = store.get_state("long".to_string()).await.unwrap();
+        assert_eq!(state, VersionedData::default());
+    }
+
+    #[tokio::test]
+    async fn expiry_too_long() {
+        let mut store = SledStateStore::new(RoomID::new_v4(), temporary_tree())
+            .with_retention(Some(Duration::MAX));
+        store
+            .save_state("key".to_string(), "a".into(), None, Some(Duration::MAX))
+            .await
+            .unwrap();
+        store.refresh_expiry().await.unwrap();
+        let state = store.get_state("key".to_string()).await.unwrap();
+        assert_eq!(state.data, Some(StateData::from("a")));
+    }
+
+    #[tokio::test]
+    async fn purge() {
+        let tree = temporary_tree();
+        let mut store = SledStateStore::new(RoomID::new_v4(), tree.clone());
+        store
+            .save_state(
+                "short".to_string(),
+                "a".into(),
+                None,
+                Some(Duration::from_millis(50)),
+            )
+            .await
+            .unwrap();
+        store
+            .save_state("long".to_string(), "b".into(), None, None)
+            .await
+            .unwrap();
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        purge_expired(&tree).unwrap();
+        assert_eq!(tree.len(), 1);
+        let state = store.get_state("long".to_string()).await.unwrap();
+        assert_eq!(state.data, Some(StateData::from("b")));
+    }
+
+    #[tokio::test]
+    async fn deleted_key_expiry() {
+        let ttl = Duration::from_millis(100);
+        let tree = temporary_tree();
+        let mut store =
+            SledStateStore::new(RoomID::new_v4(), tree.clone()).with_deleted_key_ttl(ttl);
+        store
+            .save_state("test".to_string(), "a".into(), None, None)
+            .await
+            .unwrap();
+        store.delete_state("test".to_string(), None).await.unwrap();
+        let state = store.get_state("test".to_string()).await.unwrap();
+        assert_eq!(state.version, 2);
+
+        // Writing again gives the key back the expiry of the room state.
+        store.increment_state("test".to_string(), 1).await.unwrap();
+        assert_eq!(entry(&store, "test").expires_at, 0);
+        store.delete_state("test".to_string(), None).await.unwrap();
+
+        tokio::time::sleep(ttl * 2).await;
+        purge_expired(&tree).unwrap();
+        assert!(tree.is_empty());
+    }
+
+    #[tokio::test]
+    async fn bulk_expiry() {
+        let retention = Duration::from_secs(10);
+        let mut store =
+            SledStateStore::new(RoomID::new_v4(), temporary_tree()).with_retention(Some(retention));
+        let ttl = Duration::from_millis(100);
+        store
+            .save_state("a".to_string(), "1".into(), None, Some(ttl))
+            .await
+            .unwrap();
+
+        // Like `save_state` without a TTL, batch writes expire with the rest of the room state.
+        store
+            .save_states(vec![
+                ("a".to_string(), "2".into()),
+                ("b".to_string(), "3".into()),
+            ])
+            .await
+            .unwrap();
+        assert_eq!(entry(&store, "a").ttl, 0);
+        assert!(entry(&store, "a").expires_at > now() + millis(ttl));
+        assert!(entry(&store, "b").expires_at > now() + millis(ttl));
+
+        tokio::time::sleep(ttl * 2).await;
+        store.refresh_expiry().await.unwrap();
+        let state = store.get_state("a".to_string()).await.unwrap();
+        assert_eq!(state.data, Some(StateData::from("2")));
+    }
+
+    fn entry(store: &SledStateStore, key: &str) -> Entry {
+        let bytes = store.tree.get(store.db_key(key)).unwrap().unwrap();
+        Entry::from_bytes(&bytes).unwrap()
+    }
+
+    fn copy_dir(from: &Path, to: &Path) {
+        std::fs::create_dir_all(to).unwrap();
+        for item in std::fs::read_dir(from).unwrap() {
+            let item = item.unwrap();
+            let to = to.join(item.file_name());
+            if item.file_type().unwrap().is_dir() {
+                copy_dir(&item.path(), &to);
+            } else {
+                std::fs::copy(item.path(), to).unwrap();
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn survives_reopen() {
+        let path = std::env::temp_dir().join(format!("kazahane-test-{}", RoomID::new_v4()));
+        let room_id = RoomID::new_v4();
+        let db = sled::open(&path).unwrap();
+        let mut store = SledStateStore::new(room_id, db.open_tree("room_states").unwrap());
+        store
+            .save_state("test".to_string(), "hello".into(), None, None)
+            .await
+            .unwrap();
+        // The last handle of a tree flushes it when dropped.
+        drop(store);
+        drop(db);
+
+        // sled's background threads may still hold the lock on the files for a moment,
+        // so a copy of them is opened as the restarted server.
+        let copy = path.with_extension("copy");
+        copy_dir(&path, &copy);
+        let db = sled::open(&copy).unwrap();
+        let mut store = SledStateStore::new(room_id, db.open_tree("room_states").unwrap());
+        let state = store.get_state("test".to_string()).await.unwrap();
+        assert_eq!(
+            state,
+            VersionedData {
+                data: Some("hello".into()),
+                version: 1
+            }
+        );
+        drop(store);
+        drop(db);
+        std::fs::remove_dir_all(path).unwrap();
+        std::fs::remove_dir_all(copy).unwrap();
+    }
+}
@@ -1,19 +1,30 @@
-use crate::dispatcher::{Dispatcher, MessageToConnection, MessageToRoom};
-use crate::pubsub::{PubSub, PubSubMessage, PubSubTopic};
-use crate::room_states::{RoomStateStore, StateData};
+use crate::dispatcher::{
+    Dispatcher, JoinRoomError, MessageToConnection, MessageToRoom, MessageToServer, Recipients,
+};
+use crate::packets::ErrorCode;
+use crate::pubsub::{
+    Backoff, PubSub, PubSubChannel, PubSubHealth, PubSubMessage, PubSubTopic, Subscription,
+    SubscriptionHealth,
+};
+use crate::room_states::{
+    RoomStateStore, StateData, StateKey, StateSubscription, StateVersion, SwapResult,
+};
+use crate::server::ServerConfig;
 use crate::types::{ConnectionID, RoomID, ServerID};
 use bytes::Bytes;
-use core::convert::TryInto;
 use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 use tokio::sync::mpsc;
-use tracing::{debug, error};
+use tokio::time::{Instant, Interval};
+use tracing::{debug, error, info, warn};
 
 pub(crate) async fn room_task(
     server_id: ServerID,
     room_id: RoomID,
     mut receiver: mpsc::Receiver<MessageToRoom>,
     dispatcher: Arc<Dispatcher>,
+    config: Arc<ServerConfig>,
     mut state: impl RoomStateStore,
     mut pubsub: impl PubSub,
 ) {
@@ -21,20 +32,36 @@ pub(crate) async fn room_task(
     let mut room = Room {
         server_id,
         room_id,
+        max_members: config.max_room_members_per_server,
         connections: HashMap::new(),
     };
     let topic = format!("{}", room_id);
-    let mut sub = pubsub.subscribe(topic).await.unwrap();
+    // The room keeps serving its local members while the subscription is retried.
+    let mut sub = RoomSubscription::new(topic.clone(), dispatcher.pubsub_health().clone());
+    sub.subscribe(&mut pubsub).await;
+    // Refreshed more often than the retention period so that the state never expires while the room has members.
+    let mut expiry_timer = config
+        .state_retention
+        .map(|retention| tokio::time::interval(retention / 2));
     loop {
         // TODO: shutdown room
         tokio::select! {
+            _ = tick(&mut expiry_timer) => {
+                room.refresh_state_expiry(&mut state).await;
+            }
             Some(msg) = receiver.recv() => {
                 room.handle_message(msg, &dispatcher, &mut pubsub, &mut state).await;
+                if room.connections.is_empty() {
+                    break;
+                }
             }
-            Ok(Some(msg)) = sub.next_message() => {
+            _ = tokio::time::sleep_until(sub.retry_at), if sub.sub.is_none() => {
+                sub.subscribe(&mut pubsub).await;
+            }
+            Some(msg) = sub.next_message() => {
                 match PubSubMessage::from_bytes(msg) {
                     Ok(msg) => {
-                        room.handle_pubsub_message(&msg, &dispatcher).await;
+                        room.handle_pubsub_message(&msg, &dispatcher, &mut pubsub).await;
                     }
                     Err(err) => {
                         error!("failed to parse pubsub message: {:?}", err)
@@ -45,14 +72,54 @@ pub(crate) async fn room_task(
         }
     }
     debug!("drop room: {}", room_id);
+    // Stops hearing from the other servers right away rather than when the subscription is dropped.
+    if let Some(sub) = &mut sub.sub {
+        if let Err(err) = sub.unsubscribe(&PubSubChannel::Topic(topic)).await {
+            warn!(
+                "failed to unsubscribe from pubsub (room_id: {}): {:?}",
+                room_id, err
+            );
+        }
+    }
+    // The retention period starts when the last member leaves.
+    if expiry_timer.is_some() {
+        room.refresh_state_expiry(&mut state).await;
+    }
     dispatcher.drop_room(&room_id);
+    receiver.close();
+    // Joins that were queued while closing are handed back to the server, which opens the room again.
+    while let Some(msg) = receiver.recv().await {
+        if let MessageToRoom::Join {
+            connection_id,
+            user_id,
+            reply,
+        } = msg
+        {
+            dispatcher
+                .publish_to_server(MessageToServer::Join {
+                    connection_id,
+                    room_id,
+                    user_id,
+                    reply,
+                })
+                .await;
+        }
+    }
 }
 
 #[derive(Debug)]
 struct Room {
     server_id: ServerID,
     room_id: RoomID,
-    connections: HashMap<ConnectionID, ()>,
+    /// Counts only the members connected to this server.
+    max_members: Option<usize>,
+    connections: HashMap<ConnectionID, Member>,
+}
+
+#[derive(Debug)]
+struct Member {
+    user_id: Option<String>,
+    state_subscriptions: Vec<StateSubscription>,
 }
 
 impl Room {
@@ -64,81 +131,501 @@ impl Room {
         state: &mut impl RoomStateStore,
     ) {
         match msg {
-            MessageToRoom::Join { connection_id } => {
-                self.connections.insert(connection_id, ());
+            MessageToRoom::Join {
+                connection_id,
+                user_id,
+                reply,
+            } => {
+                // The connection may have been closed while its join was in flight.
+                if !dispatcher.has_connection(&connection_id) {
+                    return;
+                }
+                if matches!(self.max_members, Some(max) if self.connections.len() >= max) {
+                    debug!("[{}] room is full: {}", self.room_id, connection_id);
+                    let _ = reply.send(Err(JoinRoomError::RoomFull));
+                    return;
+                }
+                self.notify_members(
+                    MessageToConnection::PlayerJoined {
+                        player: connection_id,
+                    },
+                    dispatcher,
+                )
+                .await;
+                let member = Member {
+                    user_id,
+                    state_subscriptions: vec![],
+                };
+                self.connections.insert(connection_id, member);
                 debug!("[{}] client joined: {}", self.room_id, connection_id);
+                // A connection closed since then leaves the room again.
+                let _ = reply.send(Ok(()));
+                // The members on other servers are sent by those servers in reply to the pubsub message.
+                let members = self
+                    .connections
+                    .keys()
+                    .copied()
+                    .filter(|member| *member != connection_id)
+                    .collect::<Vec<_>>();
+                for player in members {
+                    dispatcher
+                        .publish_to_connection(
+                            &connection_id,
+                            MessageToConnection::PlayerJoined { player },
+                        )
+                        .await;
+                }
+                let msg = PubSubMessage::PlayerJoined {
+                    sender_server: self.server_id.into_bytes(),
+                    player: connection_id.into_bytes(),
+                };
+                self.publish(msg, pubsub).await;
+            }
+            MessageToRoom::Leave { connection_id } => {
+                if self.connections.remove(&connection_id).is_none() {
+                    return;
+                }
+                debug!("[{}] client left: {}", self.room_id, connection_id);
+                // Nothing is sent to the connection from this room after this response.
                 dispatcher
                     .publish_to_connection(
                         &connection_id,
-                        MessageToConnection::JoinResponse {
+                        MessageToConnection::LeaveResponse {
                             room_id: self.room_id,
                         },
                     )
                     .await;
+                self.notify_members(
+                    MessageToConnection::PlayerLeft {
+                        player: connection_id,
+                    },
+                    dispatcher,
+                )
+                .await;
+                let msg = PubSubMessage::PlayerLeft {
+                    sender_server: self.server_id.into_bytes(),
+                    player: connection_id.into_bytes(),
+                };
+                self.publish(msg, pubsub).await;
             }
             MessageToRoom::Broadcast {
-                payload, sender, ..
+                sender,
+                echo,
+                recipients,
+                payload,
             } => {
-                self.broadcast(sender, payload.clone(), dispatcher).await;
+                let user_id = match self.connections.get(&sender) {
+                    Some(member) => member.user_id.clone(),
+                    None => return,
+                };
+                let timestamp = unix_time_millis();
+                let msg = MessageToConnection::Broadcast {
+                    sender,
+                    user_id: user_id.clone(),
+                    timestamp,
+                    payload: payload.clone(),
+                };
+                self.broadcast(sender, echo, &recipients, msg, dispatcher)
+                    .await;
 
                 // Broadcast to all clients on other servers.
+                let (filter, players) = recipients.to_filter();
                 let msg = PubSubMessage::Broadcast {
                     sender_server: self.server_id.into_bytes(),
                     sender: sender.into_bytes(),
+                    filter,
+                    players,
+                    user_id: user_id.map(String::into_bytes).unwrap_or_default(),
+                    timestamp,
                     payload: payload.to_vec(),
                 };
-                if let Err(err) = pubsub.publish(self.topic(), msg).await {
-                    error!("failed to publish broadcast message: {:?}", err);
-                }
-            }
-            MessageToRoom::TestCountUp { sender } => {
-                let to_usize = |d: StateData| usize::from_le_bytes(d.try_into().unwrap());
-                let mut counter = state
-                    .get_state("counter".into())
-                    .await
-                    .unwrap()
-                    .map_or_else(|| 0, to_usize);
-                debug!("[{}] counter {} -> {}", self.room_id, counter, counter + 1);
-                counter += 1;
-                let write_data = counter.to_le_bytes().to_vec();
-                state
-                    .save_state("counter".into(), write_data)
-                    .await
-                    .unwrap();
-                dispatcher
-                    .publish_to_connection(
-                        &sender,
-                        MessageToConnection::TestCountUpResponse { counter },
+                self.publish(msg, pubsub).await;
+            }
+            MessageToRoom::DirectMessage {
+                sender,
+                targets,
+                payload,
+            } => {
+                let user_id = match self.connections.get(&sender) {
+                    Some(member) => member.user_id.clone(),
+                    None => return,
+                };
+                let timestamp = unix_time_millis();
+                let msg = MessageToConnection::DirectMessage {
+                    sender,
+                    user_id: user_id.clone(),
+                    timestamp,
+                    payload: payload.clone(),
+                };
+                let (local_targets, remote_targets): (Vec<_>, Vec<_>) = targets
+                    .into_iter()
+                    .partition(|target| self.connections.contains_key(target));
+                for target in &local_targets {
+                    dispatcher.publish_to_connection(target, msg.clone()).await;
+                }
+                if remote_targets.is_empty() {
+                    return;
+                }
+
+                // The rest of the targets may be members on other servers.
+                let msg = PubSubMessage::DirectMessage {
+                    sender_server: self.server_id.into_bytes(),
+                    sender: sender.into_bytes(),
+                    targets: remote_targets.iter().map(|t| t.into_bytes()).collect(),
+                    user_id: user_id.map(String::into_bytes).unwrap_or_default(),
+                    timestamp,
+                    payload: payload.to_vec(),
+                };
+                self.publish(msg, pubsub).await;
+            }
+            MessageToRoom::GetState {
+                sender,
+                request_id,
+                key,
+            } => {
+                let msg = match state.get_state(key).await {
+                    Ok(value) => MessageToConnection::GetStateResponse { request_id, value },
+                    Err(err) => self.state_error(request_id, err),
+                };
+                dispatcher.publish_to_connection(&sender, msg).await;
+            }
+            MessageToRoom::SetState {
+                sender,
+                request_id,
+                key,
+                value,
+                expected_version,
+                ttl,
+            } => {
+                let result = state
+                    .save_state(key.clone(), value.clone(), expected_version, ttl)
+                    .await;
+                let result = match result {
+                    Ok(result) => result,
+                    Err(err) => {
+                        let msg = self.state_error(request_id, err);
+                        dispatcher.publish_to_connection(&sender, msg).await;
+                        return;
+                    }
+                };
+                let swapped = match result {
+                    SwapResult::Swapped { version } => Some(version),
+                    SwapResult::Conflict { .. } => None,
+                };
+                let msg = MessageToConnection::SetStateResponse { request_id, result };
+                dispatcher.publish_to_connection(&sender, msg).await;
+                if let Some(version) = swapped {
+                    self.notify_state_changed(
+                        sender,
+                        key,
+                        version,
+                        Some(value),
+                        dispatcher,
+                        pubsub,
                     )
                     .await;
+                }
+            }
+            MessageToRoom::DeleteState {
+                sender,
+                request_id,
+                key,
+                expected_version,
+            } => {
+                let result = match state.delete_state(key.clone(), expected_version).await {
+                    Ok(result) => result,
+                    Err(err) => {
+                        let msg = self.state_error(request_id, err);
+                        dispatcher.publish_to_connection(&sender, msg).await;
+                        return;
+                    }
+                };
+                let swapped = match result {
+                    SwapResult::Swapped { version } => Some(version),
+                    SwapResult::Conflict { .. } => None,
+                };
+                let msg = MessageToConnection::DeleteStateResponse { request_id, result };
+                dispatcher.publish_to_connection(&sender, msg).await;
+                if let Some(version) = swapped {
+                    self.notify_state_changed(sender, key, version, None, dispatcher, pubsub)
+                        .await;
+                }
+            }
+            MessageToRoom::IncrementState {
+                sender,
+                request_id,
+                key,
+                delta,
+            } => {
+                let result = match state.increment_state(key.clone(), delta).await {
+                    Ok(result) => result,
+                    Err(err) => {
+                        let msg = self.state_error(request_id, err);
+                        dispatcher.publish_to_connection(&sender, msg).await;
+                        return;
+                    }
+                };
+                let msg = MessageToConnection::IncrementStateResponse { request_id, result };
+                dispatcher.publish_to_connection(&sender, msg).await;
+                if let Some((value, version)) = result {
+                    let value = value.to_string().into_bytes();
+                    self.notify_state_changed(
+                        sender,
+                        key,
+                        version,
+                        Some(value),
+                        dispatcher,
+                        pubsub,
+                    )
+                    .await;
+                }
+            }
+            MessageToRoom::SetStateIfAbsent {
+                sender,
+                request_id,
+                key,
+                value,
+            } => {
+                let version = match state.save_state_if_absent(key.clone(), value.clone()).await {
+                    Ok(version) => version,
+                    Err(err) => {
+                        let msg = self.state_error(request_id, err);
+                        dispatcher.publish_to_connection(&sender, msg).await;
+                        return;
+                    }
+                };
+                let msg = MessageToConnection::SetStateIfAbsentResponse {
+                    request_id,
+                    version,
+                };
+                dispatcher.publish_to_connection(&sender, msg).await;
+                if let Some(version) = version {
+                    self.notify_state_changed(
+                        sender,
+                        key,
+                        version,
+                        Some(value),
+                        dispatcher,
+                        pubsub,
+                    )
+                    .await;
+                }
+            }
+            MessageToRoom::SubscribeState {
+                sender,
+                request_id,
+                subscription,
+            } => {
+                if !self.connections.contains_key(&sender) {
+                    return;
+                }
+                let snapshot = match &subscription {
+                    StateSubscription::Key(key) => state
+                        .get_state(key.clone())
+                        .await
+                        .map(|value| vec![(key.clone(), value)]),
+                    StateSubscription::Prefix(prefix) => state.get_state_by_prefix(prefix).await,
+                };
+                let snapshot = match snapshot {
+                    Ok(snapshot) => snapshot,
+                    Err(err) => {
+                        let msg = self.state_error(request_id, err);
+                        dispatcher.publish_to_connection(&sender, msg).await;
+                        return;
+                    }
+                };
+                let member = self.connections.get_mut(&sender).unwrap();
+                if !member.state_subscriptions.contains(&subscription) {
+                    member.state_subscriptions.push(subscription);
+                }
+                let snapshot = snapshot
+                    .into_iter()
+                    .filter_map(|(key, value)| Some((key, value.version, value.data?)))
+                    .collect();
+                let msg = MessageToConnection::SubscribeStateResponse {
+                    request_id,
+                    snapshot,
+                };
+                dispatcher.publish_to_connection(&sender, msg).await;
+            }
+            MessageToRoom::UnsubscribeState {
+                sender,
+                request_id,
+                subscription,
+            } => {
+                let member = match self.connections.get_mut(&sender) {
+                    Some(member) => member,
+                    None => return,
+                };
+                let len = member.state_subscriptions.len();
+                member.state_subscriptions.retain(|s| *s != subscription);
+                let found = member.state_subscriptions.len() != len;
+                let msg = MessageToConnection::UnsubscribeStateResponse { request_id, found };
+                dispatcher.publish_to_connection(&sender, msg).await;
+            }
+            MessageToRoom::TestCountUp { sender, request_id } => {
+                let msg = match self.count_up(state).await {
+                    Ok(counter) => MessageToConnection::TestCountUpResponse {
+                        request_id,
+                        counter,
+                    },
+                    Err(err) => self.state_error(request_id, err),
+                };
+                dispatcher.publish_to_connection(&sender, msg).await;
             }
         }
     }
 
+    async fn count_up(&self, state: &mut impl RoomStateStore) -> crate::Result<usize> {
+        let counter = state
+            .increment_state("counter".into(), 1)
+            .await?
+            .map(|(counter, _)| counter)
+            .ok_or_else(|| anyhow::anyhow!("counter is not an integer"))?;
+        debug!("[{}] counter -> {}", self.room_id, counter);
+        Ok(counter as usize)
+    }
+
+    /// Notifies the other subscribers of the key on every server. `value` is `None` when the key is deleted.
+    async fn notify_state_changed(
+        &self,
+        sender: ConnectionID,
+        key: StateKey,
+        version: StateVersion,
+        value: Option<StateData>,
+        dispatcher: &Dispatcher,
+        pubsub: &mut impl PubSub,
+    ) {
+        let (msg, pubsub_msg) = match value {
+            Some(value) => (
+                MessageToConnection::StateChanged {
+                    key: key.clone(),
+                    version,
+                    value: value.clone(),
+                },
+                PubSubMessage::StateChanged {
+                    sender_server: self.server_id.into_bytes(),
+                    key: key.into_bytes(),
+                    version,
+                    value,
+                },
+            ),
+            None => (
+                MessageToConnection::StateDeleted {
+                    key: key.clone(),
+                    version,
+                },
+                PubSubMessage::StateDeleted {
+                    sender_server: self.server_id.into_bytes(),
+                    key: key.into_bytes(),
+                    version,
+                },
+            ),
+        };
+        self.notify_state_subscribers(Some(sender), msg, dispatcher)
+            .await;
+        self.publish(pubsub_msg, pubsub).await;
+    }
+
+    async fn refresh_state_expiry(&self, state: &mut impl RoomStateStore) {
+        if let Err(err) = state.refresh_expiry().await {
+            error!(
+                "[{}] failed to refresh state expiry: {:?}",
+                self.room_id, err
+            );
+        }
+    }
+
+    fn state_error(&self, request_id: u32, err: anyhow::Error) -> MessageToConnection {
+        error!("[{}] failed to access room state: {:?}", self.room_id, err);
+        MessageToConnection::Error {
+            request_id,
+            code: ErrorCode::OperationFailed,
+            message: "failed to access room state".into(),
+        }
+    }
+
     fn topic(&self) -> PubSubTopic {
         format!("{}", self.room_id)
     }
 
-    async fn broadcast(&self, sender: ConnectionID, payload: Bytes, dispatcher: &Dispatcher) {
+    async fn publish(&self, msg: PubSubMessage, pubsub: &mut impl PubSub) {
+        if let Err(err) = pubsub.publish(self.topic(), msg).await {
+            error!("failed to publish pubsub message: {:?}", err);
+        }
+    }
+
+    async fn notify_members(&self, msg: MessageToConnection, dispatcher: &Dispatcher) {
         for connection_id in self.connections.keys() {
-            if *connection_id != sender {
-                dispatcher
-                    .publish_to_connection(
-                        connection_id,
-                        MessageToConnection::Broadcast {
-                            payload: payload.clone(),
-                        },
-                    )
-                    .await;
+            dispatcher
+                .publish_to_connection(connection_id, msg.clone())
+                .await;
+        }
+    }
+
+    /// Sends a state changed or deleted message to the members subscribing to its key.
+    async fn notify_state_subscribers(
+        &self,
+        except: Option<ConnectionID>,
+        msg: MessageToConnection,
+        dispatcher: &Dispatcher,
+    ) {
+        let key = match &msg {
+            MessageToConnection::StateChanged { key, .. }
+            | MessageToConnection::StateDeleted { key, .. } => key,
+            _ => return,
+        };
+        for (connection_id, member) in &self.connections {
+            if Some(*connection_id) == except {
+                continue;
+            }
+            if !member.state_subscriptions.iter().any(|s| s.matches(key)) {
+                continue;
+            }
+            dispatcher
+                .publish_to_connection(connection_id, msg.clone())
+                .await;
+        }
+    }
+
+    async fn broadcast(
+        &self,
+        sender: ConnectionID,
+        echo: bool,
+        recipients: &Recipients,
+        msg: MessageToConnection,
+        dispatcher: &Dispatcher,
+    ) {
+        for connection_id in self.connections.keys() {
+            // The echo goes back to the sender regardless of the recipients filter.
+            let deliver = if *connection_id == sender {
+                echo
+            } else {
+                recipients.includes(connection_id)
+            };
+            if !deliver {
+                continue;
             }
+            dispatcher
+                .publish_to_connection(connection_id, msg.clone())
+                .await;
         }
     }
 
-    async fn handle_pubsub_message(&self, msg: &PubSubMessage, dispatcher: &Dispatcher) {
+    async fn handle_pubsub_message(
+        &self,
+        msg: &PubSubMessage,
+        dispatcher: &Dispatcher,
+        pubsub: &mut impl PubSub,
+    ) {
         match msg {
             PubSubMessage::Broadcast {
                 sender_server,
                 sender,
+                filter,
+                players,
+                user_id,
+                timestamp,
                 payload,
             } => {
                 let sender_server = ServerID::from_bytes(*sender_server);
@@ -147,9 +634,312 @@ impl Room {
                     return;
                 }
                 let sender = ConnectionID::from_bytes(*sender);
-                self.broadcast(sender, Bytes::from(payload.to_vec()), dispatcher)
+                let msg = MessageToConnection::Broadcast {
+                    sender,
+                    user_id: String::from_utf8(user_id.clone())
+                        .ok()
+                        .filter(|user_id| !user_id.is_empty()),
+                    timestamp: *timestamp,
+                    payload: Bytes::from(payload.to_vec()),
+                };
+                // The sender is a member of another server, so echo does not matter here.
+                let recipients = Recipients::from_filter(*filter, players);
+                self.broadcast(sender, false, &recipients, msg, dispatcher)
+                    .await;
+            }
+            PubSubMessage::DirectMessage {
+                sender_server,
+                sender,
+                targets,
+                user_id,
+                timestamp,
+                payload,
+            } => {
+                if ServerID::from_bytes(*sender_server) == self.server_id {
+                    return;
+                }
+                let msg = MessageToConnection::DirectMessage {
+                    sender: ConnectionID::from_bytes(*sender),
+                    user_id: String::from_utf8(user_id.clone())
+                        .ok()
+                        .filter(|user_id| !user_id.is_empty()),
+                    timestamp: *timestamp,
+                    payload: Bytes::from(payload.to_vec()),
+                };
+                for target in targets.iter().copied().map(ConnectionID::from_bytes) {
+                    if self.connections.contains_key(&target) {
+                        dispatcher.publish_to_connection(&target, msg.clone()).await;
+                    }
+                }
+            }
+            PubSubMessage::StateChanged {
+                sender_server,
+                key,
+                version,
+                value,
+            } => {
+                if ServerID::from_bytes(*sender_server) == self.server_id {
+                    return;
+                }
+                let msg = MessageToConnection::StateChanged {
+                    key: String::from_utf8_lossy(key).into_owned(),
+                    version: *version,
+                    value: value.clone(),
+                };
+                self.notify_state_subscribers(None, msg, dispatcher).await;
+            }
+            PubSubMessage::StateDeleted {
+                sender_server,
+                key,
+                version,
+            } => {
+                if ServerID::from_bytes(*sender_server) == self.server_id {
+                    return;
+                }
+                let msg = MessageToConnection::StateDeleted {
+                    key: String::from_utf8_lossy(key).into_owned(),
+                    version: *version,
+                };
+                self.notify_state_subscribers(None, msg, dispatcher).await;
+            }
+            PubSubMessage::PlayerJoined {
+                sender_server,
+                player,
+            } => {
+                if ServerID::from_bytes(*sender_server) == self.server_id {
+                    return;
+                }
+                let player = ConnectionID::from_bytes(*player);
+                self.notify_members(MessageToConnection::PlayerJoined { player }, dispatcher)
                     .await;
+                if self.connections.is_empty() {
+                    return;
+                }
+                // Tells the new player about the members on this server.
+                let msg = PubSubMessage::Members {
+                    sender_server: self.server_id.into_bytes(),
+                    player: player.into_bytes(),
+                    members: self
+                        .connections
+                        .keys()
+                        .map(|member| member.into_bytes())
+                        .collect(),
+                };
+                self.publish(msg, pubsub).await;
+            }
+            PubSubMessage::Members {
+                sender_server,
+                player,
+                members,
+            } => {
+                let player = ConnectionID::from_bytes(*player);
+                if ServerID::from_bytes(*sender_server) == self.server_id
+                    || !self.connections.contains_key(&player)
+                {
+                    return;
+                }
+                for member in members.iter().copied().map(ConnectionID::from_bytes) {
+                    dispatcher
+                        .publish_to_connection(
+                            &player,
+                            MessageToConnection::PlayerJoined { player: member },
+                        )
+                        .await;
+                }
+            }
+            PubSubMessage::PlayerLeft {
+                sender_server,
+                player,
+            } => {
+                if ServerID::from_bytes(*sender_server) == self.server_id {
+                    return;
+                }
+                let player = ConnectionID::from_bytes(*player);
+                self.notify_members(MessageToConnection::PlayerLeft { player }, dispatcher)
+                    .await;
+            }
+        }
+    }
+}
+
+/// The subscription of a room to its topic, subscribed again with a backoff when subscribing fails
+/// or the subscription ends. It is reported to `PubSubHealth` as reconnecting until then.
+struct RoomSubscription {
+    topic: PubSubTopic,
+    sub: Option<Box<dyn Subscription + Send>>,
+    health: SubscriptionHealth,
+    backoff: Backoff,
+    retry_at: Instant,
+}
+
+impl RoomSubscription {
+    fn new(topic: PubSubTopic, health: PubSubHealth) -> Self {
+        Self {
+            topic,
+            sub: None,
+            health: SubscriptionHealth::new(health),
+            backoff: Backoff::new(),
+            retry_at: Instant::now(),
+        }
+    }
+
+    async fn subscribe(&mut self, pubsub: &mut impl PubSub) {
+        match pubsub.subscribe(self.topic.clone()).await {
+            Ok(sub) => {
+                if self.health.is_reconnecting() {
+                    info!("subscribed to pubsub again (topic: {})", self.topic);
+                    self.health.set_reconnecting(false);
+                }
+                self.backoff.reset();
+                self.sub = Some(sub);
+            }
+            Err(err) => {
+                error!(
+                    "failed to subscribe to pubsub, retrying (topic: {}): {:?}",
+                    self.topic, err
+                );
+                self.lost();
+            }
+        }
+    }
+
+    fn lost(&mut self) {
+        self.sub = None;
+        self.health.set_reconnecting(true);
+        self.retry_at = Instant::now() + self.backoff.next_delay();
+    }
+
+    /// Never completes without a subscription.
+    async fn next_message(&mut self) -> Option<Bytes> {
+        let result = match &mut self.sub {
+            Some(sub) => sub.next_message().await,
+            None => std::future::pending().await,
+        };
+        match result {
+            Ok(Some(msg)) => Some(msg.data),
+            Ok(None) => {
+                warn!("pubsub subscription has ended (topic: {})", self.topic);
+                self.lost();
+                None
+            }
+            Err(err) => {
+                error!("failed to receive pubsub message: {:?}", err);
+                None
+            }
+        }
+    }
+}
+
+/// Never completes without a timer.
+async fn tick(timer: &mut Option<Interval>) {
+    match timer {
+        Some(timer) => {
+            timer.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+fn unix_time_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dispatcher::{Dispatcher, MessageToConnection, MessageToRoom};
+    use crate::pubsub::memory::MemoryPubSub;
+    use crate::pubsub::{PubSub, PubSubChannel, PubSubMessage, PubSubTopic, Subscription};
+    use crate::room_states::memory::{MemoryStateStore, MemoryStates};
+    use crate::rooms::room_task;
+    use crate::server::ServerConfig;
+    use crate::types::{ConnectionID, RoomID, ServerID};
+    use anyhow::bail;
+    use async_trait::async_trait;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::sync::oneshot;
+
+    /// Fails to subscribe `failures` times before subscribing to `inner`.
+    struct FailingPubSub {
+        inner: MemoryPubSub,
+        failures: usize,
+    }
+
+    #[async_trait]
+    impl PubSub for FailingPubSub {
+        async fn publish(&mut self, topic: PubSubTopic, msg: PubSubMessage) -> crate::Result<()> {
+            self.inner.publish(topic, msg).await
+        }
+
+        async fn subscribe_channels(
+            &mut self,
+            channels: Vec<PubSubChannel>,
+        ) -> crate::Result<Box<dyn Subscription + Send>> {
+            if self.failures > 0 {
+                self.failures -= 1;
+                bail!("failed to subscribe");
+            }
+            self.inner.subscribe_channels(channels).await
+        }
+    }
+
+    #[tokio::test]
+    async fn subscribe_again() {
+        let dispatcher = Arc::new(Dispatcher::new());
+        let room_id = RoomID::new_v4();
+        let connection_id = ConnectionID::new_v4();
+        let mut connection = dispatcher.register_connection(connection_id);
+        let receiver = dispatcher.register_room(room_id);
+        let pubsub = MemoryPubSub::default();
+        tokio::spawn(room_task(
+            ServerID::new_v4(),
+            room_id,
+            receiver,
+            dispatcher.clone(),
+            Arc::new(ServerConfig::default()),
+            MemoryStateStore::new(room_id, MemoryStates::default()),
+            FailingPubSub {
+                inner: pubsub.clone(),
+                failures: 1,
+            },
+        ));
+        let (reply, joined) = oneshot::channel();
+        dispatcher
+            .publish_to_room(
+                &room_id,
+                MessageToRoom::Join {
+                    connection_id,
+                    user_id: None,
+                    reply,
+                },
+            )
+            .await;
+        joined.await.unwrap().unwrap();
+        assert!(!dispatcher.pubsub_health().is_healthy());
+
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while !dispatcher.pubsub_health().is_healthy() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
             }
+        })
+        .await
+        .unwrap();
+        // Players joining on other servers are heard of again.
+        let player = ConnectionID::new_v4();
+        let msg = PubSubMessage::PlayerJoined {
+            sender_server: ServerID::new_v4().into_bytes(),
+            player: player.into_bytes(),
+        };
+        pubsub
+            .clone()
+            .publish(room_id.to_string(), msg)
+            .await
+            .unwrap();
+        match connection.recv().await.unwrap() {
+            MessageToConnection::PlayerJoined { player: joined } => assert_eq!(joined, player),
+            msg => panic!("unexpected message: {:?}", msg),
         }
     }
 }
@@ -1,74 +1,259 @@
+use crate::auth::{AllowAllAuthenticator, Authenticator};
 use crate::connections::connection_task;
 use crate::connections::Connection;
 use crate::dispatcher::{Dispatcher, MessageToConnection, MessageToRoom, MessageToServer};
-use crate::pubsub::redis::RedisPubSub;
+use crate::packets::DEFAULT_MAX_PACKET_SIZE;
+use crate::pubsub::memory::MemoryPubSub;
+use crate::pubsub::redis::{RedisPubSub, RedisSubscriber};
+use crate::pubsub::redis_streams::{RedisStreamsPubSub, RedisStreamsSubscriber};
+use crate::pubsub::Backoff;
+use crate::room_states;
+use crate::room_states::memory::{MemoryStateStore, MemoryStates};
 use crate::room_states::redis::RedisStateStore;
+use crate::room_states::sled::SledStateStore;
 use crate::rooms::room_task;
 use crate::transports::websocket;
 use crate::types::ServerID;
 use crate::RoomID;
-use std::collections::HashMap;
+use anyhow::Context;
+use std::path::Path;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::net::TcpListener;
-use tracing::{debug, info};
+use tokio::sync::mpsc;
+use tracing::{debug, info, warn};
 
-type RoomMap = HashMap<RoomID, ()>;
+/// Connection attempts to Redis at startup before the server gives up.
+const REDIS_CONNECT_ATTEMPTS: u32 = 10;
 
-pub async fn start(listener: &TcpListener, redis: redis::Client, dispatcher: Arc<Dispatcher>) {
+/// How often expired room state of rooms that are not open is removed from the local backends.
+const EXPIRY_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+pub struct ServerConfig {
+    pub authenticator: Arc<dyn Authenticator + Send + Sync>,
+    /// Connections that don't complete the Hello handshake within this period are closed.
+    pub hello_timeout: Duration,
+    /// Maximum number of members a room may have on this server. `None` means unlimited.
+    /// Members on other servers are not counted, so a room spread over several servers can have more in total.
+    pub max_room_members_per_server: Option<usize>,
+    /// Packets larger than this are rejected with `ErrorCode::PacketTooLarge`.
+    pub max_packet_size: usize,
+    /// Maximum length of a room state key in bytes.
+    pub max_state_key_size: usize,
+    /// Maximum size of a room state value in bytes.
+    pub max_state_value_size: usize,
+    /// Maximum TTL a client may set on a room state key.
+    pub max_state_ttl: Duration,
+    /// Room state expires after the room has had no members on any server for this period.
+    /// `None` keeps it forever.
+    pub state_retention: Option<Duration>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            authenticator: Arc::new(AllowAllAuthenticator),
+            hello_timeout: Duration::from_secs(10),
+            max_room_members_per_server: None,
+            max_packet_size: DEFAULT_MAX_PACKET_SIZE,
+            max_state_key_size: 256,
+            max_state_value_size: 64 * 1024,
+            max_state_ttl: Duration::from_secs(30 * 24 * 60 * 60),
+            state_retention: None,
+        }
+    }
+}
+
+/// Where room state is stored and how rooms hear from the other servers.
+#[derive(Debug, Clone)]
+pub enum Backend {
+    Redis(redis::Client),
+    /// Like `Redis`, but rooms hear from the other servers through Redis Streams,
+    /// so that messages published while a server is briefly disconnected are not lost.
+    /// Each server keeps its position in the stream of each room in a consumer group.
+    /// Pattern subscriptions are not supported and fail.
+    RedisStreams(redis::Client),
+    /// Keeps everything in this process, for a single server without Redis.
+    /// Servers started with clones of the same backend share their rooms.
+    Memory(MemoryBackend),
+    /// Keeps room state on local disk so that it survives restarts, for a single server.
+    Sled(SledBackend),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MemoryBackend {
+    states: MemoryStates,
+    pubsub: MemoryPubSub,
+}
+
+impl MemoryBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SledBackend {
+    tree: sled::Tree,
+    pubsub: MemoryPubSub,
+}
+
+impl SledBackend {
+    pub fn open(path: impl AsRef<Path>) -> crate::Result<Self> {
+        let db = sled::open(path).context("failed to open room state database")?;
+        let tree = db
+            .open_tree("room_states")
+            .context("failed to open room state database")?;
+        Ok(Self {
+            tree,
+            pubsub: MemoryPubSub::default(),
+        })
+    }
+}
+
+/// `Backend` with the connections opened by the server.
+enum RoomBackend {
+    Redis {
+        conn: redis::aio::ConnectionManager,
+        subscriber: RedisSubscriber,
+    },
+    RedisStreams {
+        conn: redis::aio::ConnectionManager,
+        subscriber: RedisStreamsSubscriber,
+    },
+    Memory(MemoryBackend),
+    Sled(SledBackend),
+}
+
+pub async fn start(
+    listener: &TcpListener,
+    redis: redis::Client,
+    dispatcher: Arc<Dispatcher>,
+    config: ServerConfig,
+) -> crate::Result<()> {
+    start_with_backend(listener, Backend::Redis(redis), dispatcher, config).await
+}
+
+/// Fails if the backend is unavailable at startup. Once started, the server keeps running
+/// through backend outages.
+pub async fn start_with_backend(
+    listener: &TcpListener,
+    backend: Backend,
+    dispatcher: Arc<Dispatcher>,
+    config: ServerConfig,
+) -> crate::Result<()> {
     let server_id = ServerID::new_v4();
     debug!(
         "start kazahane server (server_id: {}, listening on {:?})",
         server_id,
         listener.local_addr()
     );
-    let mut rooms = RoomMap::new();
-    let redis_conn = redis.get_tokio_connection_manager().await.unwrap();
+    let config = Arc::new(config);
+    let backend = match backend {
+        Backend::Redis(client) => {
+            let conn = prepare_redis(&client).await?;
+            let subscriber = RedisSubscriber::new(client, dispatcher.pubsub_health().clone());
+            RoomBackend::Redis { conn, subscriber }
+        }
+        Backend::RedisStreams(client) => {
+            let conn = prepare_redis(&client).await?;
+            let subscriber = RedisStreamsSubscriber::new(
+                client,
+                conn.clone(),
+                server_id.to_string(),
+                dispatcher.pubsub_health().clone(),
+            );
+            RoomBackend::RedisStreams { conn, subscriber }
+        }
+        Backend::Memory(backend) => {
+            tokio::spawn(room_states::memory::sweep_expired(
+                backend.states.clone(),
+                EXPIRY_SWEEP_INTERVAL,
+            ));
+            RoomBackend::Memory(backend)
+        }
+        Backend::Sled(backend) => {
+            tokio::spawn(room_states::sled::sweep_expired(
+                backend.tree.clone(),
+                EXPIRY_SWEEP_INTERVAL,
+            ));
+            RoomBackend::Sled(backend)
+        }
+    };
     let mut receiver = dispatcher.register_server();
     loop {
         tokio::select! {
-            Ok(conn) = websocket::accept(listener) => {
+            Ok(conn) = websocket::accept(listener, config.max_packet_size) => {
                 let receiver = dispatcher.register_connection(conn.connection_id());
                 // TODO: instrument task
-                tokio::spawn(connection_task(conn, receiver, dispatcher.clone()));
+                tokio::spawn(connection_task(conn, receiver, dispatcher.clone(), config.clone()));
             }
             Some(msg) = receiver.recv() => {
-                handle_message(server_id, msg, &mut rooms, dispatcher.clone(), redis.clone(), &redis_conn).await;
+                handle_message(server_id, msg, dispatcher.clone(), config.clone(), &backend).await;
             }
             else => break
         }
     }
+    Ok(())
+}
+
+/// Connects to Redis and converts the room state keys written by older servers.
+async fn prepare_redis(client: &redis::Client) -> crate::Result<redis::aio::ConnectionManager> {
+    let mut conn = connect_redis(client).await?;
+    let migrated = room_states::redis::migrate(&mut conn).await?;
+    if migrated > 0 {
+        info!("migrated {} room state keys to versioned keys", migrated);
+    }
+    Ok(conn)
+}
+
+/// Waits until Redis is available, up to `REDIS_CONNECT_ATTEMPTS`.
+/// The connection manager reconnects by itself afterwards.
+async fn connect_redis(client: &redis::Client) -> crate::Result<redis::aio::ConnectionManager> {
+    let mut backoff = Backoff::new();
+    let mut attempt = 1;
+    loop {
+        match client.get_tokio_connection_manager().await {
+            Ok(conn) => return Ok(conn),
+            Err(err) if attempt < REDIS_CONNECT_ATTEMPTS => {
+                warn!("failed to connect to redis, retrying: {:?}", err);
+            }
+            Err(err) => return Err(err).context("failed to connect to redis"),
+        }
+        backoff.wait().await;
+        attempt += 1;
+    }
 }
 
 async fn handle_message(
     server_id: ServerID,
     msg: MessageToServer,
-    rooms: &mut RoomMap,
     dispatcher: Arc<Dispatcher>,
-    redis: redis::Client,
-    redis_conn: &redis::aio::ConnectionManager,
+    config: Arc<ServerConfig>,
+    backend: &RoomBackend,
 ) {
     match msg {
         MessageToServer::Join {
             connection_id,
             room_id,
+            user_id,
+            reply,
         } => {
-            rooms.entry(room_id).or_insert_with(|| {
-                let room_receiver = dispatcher.register_room(room_id);
-                let room_state = RedisStateStore::new(room_id, redis_conn.clone());
-                let pubsub = RedisPubSub::new(redis, redis_conn.clone());
-                // TODO: instrument task
-                tokio::spawn(room_task(
-                    server_id,
-                    room_id,
-                    room_receiver,
-                    dispatcher.clone(),
-                    room_state,
-                    pubsub,
-                ));
-            });
-            dispatcher
-                .publish_to_room(&room_id, MessageToRoom::Join { connection_id })
-                .await;
+            let msg = MessageToRoom::Join {
+                connection_id,
+                user_id,
+                reply,
+            };
+            let msg = match dispatcher.room_sender(&room_id) {
+                Some(sender) => match sender.send(msg).await {
+                    Ok(()) => return,
+                    // The room has just been closed.
+                    Err(mpsc::error::SendError(msg)) => msg,
+                },
+                None => msg,
+            };
+            spawn_room(server_id, room_id, dispatcher.clone(), config, backend);
+            dispatcher.publish_to_room(&room_id, msg).await;
         }
         MessageToServer::Shutdown { reason } => {
             info!("server received shutdown request (reason: {:?})", reason);
@@ -78,3 +263,69 @@ async fn handle_message(
         }
     }
 }
+
+fn spawn_room(
+    server_id: ServerID,
+    room_id: RoomID,
+    dispatcher: Arc<Dispatcher>,
+    config: Arc<ServerConfig>,
+    backend: &RoomBackend,
+) {
+    let room_receiver = dispatcher.register_room(room_id);
+    let retention = config.state_retention;
+    // TODO: instrument task
+    match backend {
+        RoomBackend::Redis { conn, subscriber } => {
+            let room_state = RedisStateStore::new(room_id, conn.clone()).with_retention(retention);
+            let pubsub = RedisPubSub::new(subscriber.clone(), conn.clone());
+            tokio::spawn(room_task(
+                server_id,
+                room_id,
+                room_receiver,
+                dispatcher,
+                config,
+                room_state,
+                pubsub,
+            ));
+        }
+        RoomBackend::RedisStreams { conn, subscriber } => {
+            let room_state = RedisStateStore::new(room_id, conn.clone()).with_retention(retention);
+            let pubsub = RedisStreamsPubSub::new(subscriber.clone(), conn.clone());
+            tokio::spawn(room_task(
+                server_id,
+                room_id,
+                room_receiver,
+                dispatcher,
+                config,
+                room_state,
+                pubsub,
+            ));
+        }
+        RoomBackend::Memory(backend) => {
+            let room_state =
+                MemoryStateStore::new(room_id, backend.states.clone()).with_retention(retention);
+            tokio::spawn(room_task(
+                server_id,
+                room_id,
+                room_receiver,
+                dispatcher,
+                config,
+                room_state,
+                backend.pubsub.clone(),
+            ));
+        }
+        RoomBackend::Sled(backend) => {
+            let room_state =
+                SledStateStore::new(room_id, backend.tree.clone()).with_retention(retention);
+            tokio::spawn(room_task(
+                server_id,
+                room_id,
+                room_receiver,
+                dispatcher,
+                config,
+                room_state,
+                backend.pubsub.clone(),
+            ));
+        }
+    }
+}
@@ -1 +1,354 @@
 pub mod websocket;
+
+use crate::packets::{Packet, ProtocolVersion, MAGIC_SIZE};
+use anyhow::{bail, Context};
+use binrw::io::Cursor;
+use binrw::{BinRead, BinWrite};
+use std::fmt::{Display, Formatter};
+
+/// Returned by `Connection::recv` when a received packet exceeds the maximum packet size.
+/// The packet is discarded and the connection can still be used.
+#[derive(Debug)]
+pub struct PacketTooLarge {
+    pub size: usize,
+    pub max_size: usize,
+}
+
+impl Display for PacketTooLarge {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "packet too large ({} bytes, max {} bytes)",
+            self.size, self.max_size
+        )
+    }
+}
+
+impl std::error::Error for PacketTooLarge {}
+
+/// Returned by `Connection::recv` when a received packet can't be parsed.
+/// The packet is discarded and the connection can still be used.
+#[derive(Debug)]
+pub struct InvalidPacket {
+    pub source: binrw::Error,
+}
+
+impl Display for InvalidPacket {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("invalid packet")
+    }
+}
+
+impl std::error::Error for InvalidPacket {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+/// Returned by `Connection::recv` when a received packet doesn't start with the magic string of a supported
+/// protocol version, or starts with another one than negotiated. The connection can't be used any further.
+#[derive(Debug)]
+pub struct UnsupportedVersion {
+    pub magic: Vec<u8>,
+}
+
+impl Display for UnsupportedVersion {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "unsupported protocol version: {:?}",
+            String::from_utf8_lossy(&self.magic)
+        )
+    }
+}
+
+impl std::error::Error for UnsupportedVersion {}
+
+/// Frames packets with the magic string of the negotiated protocol version.
+#[derive(Debug)]
+pub(crate) struct PacketCodec {
+    /// `None` until the peer's first packet tells the version, unless it is fixed up front.
+    version: Option<ProtocolVersion>,
+    magic_sent: bool,
+    magic_received: bool,
+}
+
+impl PacketCodec {
+    /// For servers, which speak the version chosen by the client.
+    pub(crate) fn negotiate() -> Self {
+        Self {
+            version: None,
+            magic_sent: false,
+            magic_received: false,
+        }
+    }
+
+    /// For clients, which choose the version.
+    pub(crate) fn with_version(version: ProtocolVersion) -> Self {
+        Self {
+            version: Some(version),
+            ..Self::negotiate()
+        }
+    }
+
+    pub(crate) fn encode(&mut self, packet: &Packet) -> crate::Result<Vec<u8>> {
+        // Before negotiation, the latest version is used because the peer can't be asked yet.
+        let version = self.version.unwrap_or(ProtocolVersion::LATEST);
+        if !packet.is_supported_by(version) {
+            bail!("{:?} can't be sent in {:?}", packet, version);
+        }
+        let mut writer = Cursor::new(Vec::new());
+        if !self.magic_sent || version.magic_on_every_packet() {
+            writer.get_mut().extend_from_slice(version.magic());
+            writer.set_position(MAGIC_SIZE as u64);
+            self.magic_sent = true;
+        }
+        packet
+            .write_with_args(&mut writer, (version,))
+            .context("failed to write packet")?;
+        Ok(writer.into_inner())
+    }
+
+    /// The version is negotiated even from a packet that is too large,
+    /// so that the peer can keep using the connection after `PacketTooLarge`.
+    pub(crate) fn decode(&mut self, data: &[u8], max_size: usize) -> crate::Result<Packet> {
+        let size = data.len();
+        let mut data = data;
+        let expects_magic = match self.version {
+            Some(version) => !self.magic_received || version.magic_on_every_packet(),
+            None => true,
+        };
+        if expects_magic {
+            let magic = &data[..data.len().min(MAGIC_SIZE)];
+            let unsupported = || UnsupportedVersion {
+                magic: magic.to_vec(),
+            };
+            let version = ProtocolVersion::from_magic(magic).ok_or_else(unsupported)?;
+            match self.version {
+                Some(negotiated) if negotiated != version => return Err(unsupported().into()),
+                _ => self.version = Some(version),
+            }
+            self.magic_received = true;
+            data = &data[MAGIC_SIZE..];
+        }
+        if size > max_size {
+            return Err(PacketTooLarge { size, max_size }.into());
+        }
+        let version = self.version.unwrap_or(ProtocolVersion::LATEST);
+        Packet::read_args(&mut Cursor::new(data), (version,))
+            .map_err(|source| InvalidPacket { source }.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::packets::{
+        BroadcastFilter, HelloResponseStatusCode, JoinRoomResponseStatusCode, Packet,
+        ProtocolVersion, RoomNotification, DEFAULT_MAX_PACKET_SIZE,
+    };
+    use crate::transports::{InvalidPacket, PacketCodec, PacketTooLarge, UnsupportedVersion};
+
+    #[test]
+    fn negotiate_v1() {
+        let mut server = PacketCodec::negotiate();
+        let mut client = PacketCodec::with_version(ProtocolVersion::V1_1);
+        for _ in 0..2 {
+            // V1 packets don't carry request ids.
+            let data = client
+                .encode(&Packet::LeaveRoomRequest { request_id: 1 })
+                .unwrap();
+            assert_eq!(&data[..], b"KAZAHANE 1.1.0\x09");
+            assert_eq!(
+                server.decode(&data, DEFAULT_MAX_PACKET_SIZE).unwrap(),
+                Packet::LeaveRoomRequest { request_id: 0 }
+            );
+
+            let data = server
+                .encode(&Packet::LeaveRoomResponse { request_id: 0 })
+                .unwrap();
+            assert_eq!(&data[..], b"KAZAHANE 1.1.0\x0A");
+            assert_eq!(
+                client.decode(&data, DEFAULT_MAX_PACKET_SIZE).unwrap(),
+                Packet::LeaveRoomResponse { request_id: 0 }
+            );
+        }
+        assert!(server.decode(b"\x09", DEFAULT_MAX_PACKET_SIZE).is_err());
+    }
+
+    #[test]
+    fn negotiate_v1_0() {
+        let mut server = PacketCodec::negotiate();
+        let mut client = PacketCodec::with_version(ProtocolVersion::V1_0);
+        // Length prefixes are uint16.
+        let data = client
+            .encode(&Packet::HelloRequest {
+                request_id: 0,
+                token: b"hello".to_vec(),
+            })
+            .unwrap();
+        assert_eq!(&data[..], b"KAZAHANE 1.0.0\x01\x05\x00hello");
+        assert_eq!(
+            server.decode(&data, DEFAULT_MAX_PACKET_SIZE).unwrap(),
+            Packet::HelloRequest {
+                request_id: 0,
+                token: b"hello".to_vec()
+            }
+        );
+
+        let data = server
+            .encode(&Packet::HelloResponse {
+                request_id: 0,
+                status_code: HelloResponseStatusCode::OK,
+                message: vec![],
+            })
+            .unwrap();
+        assert_eq!(&data[..], b"KAZAHANE 1.0.0\x02\x01\x00\x00");
+
+        // A message longer than uint16 can't be encoded.
+        assert!(server
+            .encode(&Packet::HelloResponse {
+                request_id: 0,
+                status_code: HelloResponseStatusCode::OK,
+                message: vec![0; 0x10000],
+            })
+            .is_err());
+    }
+
+    #[test]
+    fn v1_0_bodies() {
+        let mut server = PacketCodec::negotiate();
+        // Broadcasts carry only the payload.
+        assert_eq!(
+            server
+                .decode(b"KAZAHANE 1.0.0\x05\x05\x00hello", DEFAULT_MAX_PACKET_SIZE)
+                .unwrap(),
+            Packet::BroadcastRequest {
+                request_id: 0,
+                flags: 0,
+                filter: BroadcastFilter::All,
+                players: vec![],
+                payload: b"hello".to_vec(),
+            }
+        );
+        let data = server
+            .encode(&Packet::RoomNotification(RoomNotification::Broadcast {
+                sender: [1; 16],
+                user_id: b"user".to_vec(),
+                timestamp: 1,
+                payload: b"hello".to_vec(),
+            }))
+            .unwrap();
+        assert_eq!(&data[..], b"KAZAHANE 1.0.0\x06\x03\x05\x00hello");
+
+        // Join responses are empty, so only a successful join can be answered.
+        let join_response = |status_code| Packet::JoinRoomResponse {
+            request_id: 0,
+            status_code,
+            message: vec![],
+        };
+        let data = server
+            .encode(&join_response(JoinRoomResponseStatusCode::OK))
+            .unwrap();
+        assert_eq!(&data[..], b"KAZAHANE 1.0.0\x04");
+        assert!(server
+            .encode(&join_response(JoinRoomResponseStatusCode::RoomFull))
+            .is_err());
+
+        // Packets added after 1.0.0 can't be sent.
+        assert!(server
+            .encode(&Packet::LeaveRoomResponse { request_id: 0 })
+            .is_err());
+    }
+
+    #[test]
+    fn negotiate_v2() {
+        let mut server = PacketCodec::negotiate();
+        let mut client = PacketCodec::with_version(ProtocolVersion::V2_0);
+        let data = client
+            .encode(&Packet::LeaveRoomRequest { request_id: 1 })
+            .unwrap();
+        assert_eq!(&data[..], b"KAZAHANE 2.0.0\x09\x01\x00\x00\x00");
+        assert_eq!(
+            server.decode(&data, DEFAULT_MAX_PACKET_SIZE).unwrap(),
+            Packet::LeaveRoomRequest { request_id: 1 }
+        );
+        let data = server
+            .encode(&Packet::LeaveRoomResponse { request_id: 1 })
+            .unwrap();
+        assert_eq!(&data[..], b"KAZAHANE 2.0.0\x0A\x01\x00\x00\x00");
+        assert_eq!(
+            client.decode(&data, DEFAULT_MAX_PACKET_SIZE).unwrap(),
+            Packet::LeaveRoomResponse { request_id: 1 }
+        );
+
+        // The magic is dropped after the first packet.
+        let data = client
+            .encode(&Packet::LeaveRoomRequest { request_id: 1 })
+            .unwrap();
+        assert_eq!(&data[..], b"\x09\x01\x00\x00\x00");
+        assert_eq!(
+            server.decode(&data, DEFAULT_MAX_PACKET_SIZE).unwrap(),
+            Packet::LeaveRoomRequest { request_id: 1 }
+        );
+        let data = server
+            .encode(&Packet::LeaveRoomResponse { request_id: 1 })
+            .unwrap();
+        assert_eq!(&data[..], b"\x0A\x01\x00\x00\x00");
+        assert_eq!(
+            client.decode(&data, DEFAULT_MAX_PACKET_SIZE).unwrap(),
+            Packet::LeaveRoomResponse { request_id: 1 }
+        );
+    }
+
+    #[test]
+    fn negotiate_with_too_large_packet() {
+        let mut server = PacketCodec::negotiate();
+        let mut client = PacketCodec::with_version(ProtocolVersion::V2_0);
+        let data = client
+            .encode(&Packet::HelloRequest {
+                request_id: 1,
+                token: vec![0; 32],
+            })
+            .unwrap();
+        let err = server.decode(&data, 16).unwrap_err();
+        assert!(err.downcast_ref::<PacketTooLarge>().is_some());
+
+        let data = client
+            .encode(&Packet::LeaveRoomRequest { request_id: 1 })
+            .unwrap();
+        assert_eq!(
+            server.decode(&data, 16).unwrap(),
+            Packet::LeaveRoomRequest { request_id: 1 }
+        );
+    }
+
+    #[test]
+    fn invalid_packet() {
+        let mut server = PacketCodec::negotiate();
+        let err = server
+            .decode(b"KAZAHANE 2.0.0\xFF", DEFAULT_MAX_PACKET_SIZE)
+            .unwrap_err();
+        assert!(err.downcast_ref::<InvalidPacket>().is_some());
+
+        // The version has been negotiated by the invalid packet.
+        let packet = server.decode(b"\x09\x01\x00\x00\x00", DEFAULT_MAX_PACKET_SIZE);
+        assert_eq!(packet.unwrap(), Packet::LeaveRoomRequest { request_id: 1 });
+    }
+
+    #[test]
+    fn unsupported_version() {
+        let mut server = PacketCodec::negotiate();
+        let err = server
+            .decode(b"KAZAHANE 9.9.9\x09", DEFAULT_MAX_PACKET_SIZE)
+            .unwrap_err();
+        assert!(err.downcast_ref::<UnsupportedVersion>().is_some());
+        let err = server.decode(b"\x09", DEFAULT_MAX_PACKET_SIZE).unwrap_err();
+        assert!(err.downcast_ref::<UnsupportedVersion>().is_some());
+
+        let mut client = PacketCodec::with_version(ProtocolVersion::V2_0);
+        let err = client
+            .decode(b"KAZAHANE 1.1.0\x0A", DEFAULT_MAX_PACKET_SIZE)
+            .unwrap_err();
+        assert!(err.downcast_ref::<UnsupportedVersion>().is_some());
+    }
+}
@@ -1,35 +1,62 @@
 use crate::connections::Connection;
-use crate::packets::Packet;
+use crate::packets::{Packet, ProtocolVersion, DEFAULT_MAX_PACKET_SIZE};
+use crate::transports::PacketCodec;
 use crate::types::ConnectionID;
 use anyhow::{anyhow, bail, Context};
 use async_trait::async_trait;
-use binrw::io::Cursor;
-use binrw::{BinRead, BinWrite};
 use futures::stream::{SplitSink, SplitStream};
 use futures::{SinkExt, StreamExt};
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpListener;
 use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
 use tokio_tungstenite::tungstenite::Message;
 use tokio_tungstenite::WebSocketStream;
 use uuid::Uuid;
 
+/// Messages up to this many times the maximum packet size are still read, so that the client gets
+/// `PacketTooLarge` and can keep using the connection. Larger ones close the connection.
+const MAX_MESSAGE_SIZE_FACTOR: usize = 4;
+
 pub async fn connect(url: impl IntoClientRequest + Unpin) -> crate::Result<impl Connection> {
+    connect_with_version(url, ProtocolVersion::LATEST).await
+}
+
+pub async fn connect_with_version(
+    url: impl IntoClientRequest + Unpin,
+    version: ProtocolVersion,
+) -> crate::Result<impl Connection> {
     let (ws_stream, _) = tokio_tungstenite::connect_async(url)
         .await
         .context("failed to connect via websocket")?;
-    Ok(WebSocketConnection::new(ws_stream))
+    let codec = PacketCodec::with_version(version);
+    Ok(WebSocketConnection::new(
+        ws_stream,
+        codec,
+        DEFAULT_MAX_PACKET_SIZE,
+    ))
 }
 
-pub(crate) async fn accept(listener: &TcpListener) -> crate::Result<impl Connection> {
+pub(crate) async fn accept(
+    listener: &TcpListener,
+    max_packet_size: usize,
+) -> crate::Result<impl Connection> {
     let (stream, _) = listener
         .accept()
         .await
         .context("failed to accept TCP connection")?;
-    let ws_stream = tokio_tungstenite::accept_async(stream)
+    // Buffering stops at the limit instead of after the whole message has been read.
+    let limit = max_packet_size.saturating_mul(MAX_MESSAGE_SIZE_FACTOR);
+    let ws_config = WebSocketConfig {
+        max_message_size: Some(limit),
+        max_frame_size: Some(limit),
+        ..Default::default()
+    };
+    let ws_stream = tokio_tungstenite::accept_async_with_config(stream, Some(ws_config))
         .await
         .context("failed to accept as websocket")?;
-    Ok(WebSocketConnection::new(ws_stream))
+    let codec = PacketCodec::negotiate();
+    Ok(WebSocketConnection::new(ws_stream, codec, max_packet_size))
 }
 
 #[derive(Debug)]
@@ -37,10 +64,12 @@ pub(crate) struct WebSocketConnection<S> {
     connection_id: ConnectionID,
     sender: SplitSink<WebSocketStream<S>, Message>,
     receiver: SplitStream<WebSocketStream<S>>,
+    codec: PacketCodec,
+    max_packet_size: usize,
 }
 
 impl<S> WebSocketConnection<S> {
-    fn new(ws: WebSocketStream<S>) -> Self
+    fn new(ws: WebSocketStream<S>, codec: PacketCodec, max_packet_size: usize) -> Self
     where
         S: AsyncRead + AsyncWrite + Unpin,
     {
@@ -49,6 +78,8 @@ impl<S> WebSocketConnection<S> {
             connection_id: Uuid::new_v4(),
             sender,
             receiver,
+            codec,
+            max_packet_size,
         }
     }
 }
@@ -63,12 +94,9 @@ where
     }
 
     async fn send(&mut self, packet: Packet) -> crate::Result<()> {
-        let mut writer = Cursor::new(Vec::new());
-        packet
-            .write_to(&mut writer)
-            .context("failed to write packet")?;
+        let data = self.codec.encode(&packet)?;
         self.sender
-            .send(Message::Binary(writer.into_inner()))
+            .send(Message::Binary(data))
             .await
             .context("failed to send message")
     }
@@ -83,8 +111,7 @@ where
                 .context("failed to receive")?;
             match msg {
                 Message::Binary(data) => {
-                    let mut cursor = Cursor::new(data);
-                    return Packet::read(&mut cursor).context("failed to parse");
+                    return self.codec.decode(&data, self.max_packet_size);
                 }
                 Message::Ping(_) => continue,
                 Message::Pong(_) => continue,
@@ -93,4 +120,8 @@ where
             }
         }
     }
+
+    async fn close(&mut self) -> crate::Result<()> {
+        self.sender.close().await.context("failed to close")
+    }
 }
@@ -1,15 +1,24 @@
 #[cfg(test)]
 mod tests {
+    use futures::{SinkExt, Stream, StreamExt};
+    use jsonwebtoken::{encode, get_current_timestamp, EncodingKey, Header};
+    use kazahane::auth::jwt::{JwtAlgorithm, JwtVerifier};
+    use kazahane::auth::StaticTokenAuthenticator;
     use kazahane::connections::Connection;
     use kazahane::dispatcher::{Dispatcher, MessageToServer, ServerShutdownReason};
     use kazahane::packets::{
-        HelloResponseStatusCode, Packet, RoomNotification, ServerNotification,
+        BroadcastFilter, ErrorCode, HelloResponseStatusCode, JoinRoomResponseStatusCode, Packet,
+        ProtocolVersion, RoomNotification, ServerNotification, StateStatusCode,
+        BROADCAST_FLAG_ECHO, MAGIC_SIZE, STATE_FLAG_EXPECT_VERSION, SUBSCRIBE_STATE_FLAG_PREFIX,
     };
+    use kazahane::server::{Backend, MemoryBackend, ServerConfig, SledBackend};
     use kazahane::transports::websocket;
     use kazahane::RoomID;
     use std::net::SocketAddr;
     use std::sync::{Arc, Once};
+    use std::time::Duration;
     use tokio::net::TcpListener;
+    use tokio_tungstenite::tungstenite::{Error as WsError, Message};
     use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
     struct TestServer {
@@ -19,35 +28,28 @@ mod tests {
 
     impl TestServer {
         async fn connect(&self) -> impl Connection {
+            self.connect_with_version(ProtocolVersion::LATEST).await
+        }
+
+        async fn connect_with_version(&self, version: ProtocolVersion) -> impl Connection {
             let url = format!("ws://{}", self.server_addr);
-            websocket::connect(url).await.expect("failed to connect")
+            websocket::connect_with_version(url, version)
+                .await
+                .expect("failed to connect")
         }
 
         async fn connect_and_join(&self, room_id: RoomID) -> impl Connection {
+            self.connect_and_join_with_token(room_id, b"").await
+        }
+
+        async fn connect_and_join_with_token(
+            &self,
+            room_id: RoomID,
+            token: &[u8],
+        ) -> impl Connection {
             let mut client = self.connect().await;
-            client
-                .send(Packet::HelloRequest {
-                    token: b"".to_vec(),
-                })
-                .await
-                .unwrap();
-            assert!(matches!(
-                client.recv().await.unwrap(),
-                Packet::HelloResponse {
-                    status_code: HelloResponseStatusCode::OK,
-                    ..
-                }
-            ));
-            client
-                .send(Packet::JoinRoomRequest {
-                    room_id: room_id.into_bytes(),
-                })
-                .await
-                .unwrap();
-            assert!(matches!(
-                client.recv().await.unwrap(),
-                Packet::JoinRoomResponse { .. }
-            ));
+            hello(&mut client, token).await;
+            join(&mut client, room_id).await;
             client
         }
 
@@ -61,7 +63,15 @@ mod tests {
     }
 
     async fn spawn_test_server() -> TestServer {
+        spawn_test_server_with_config(ServerConfig::default()).await
+    }
+
+    async fn spawn_test_server_with_config(config: ServerConfig) -> TestServer {
         let redis = redis::Client::open("redis://127.0.0.1").unwrap();
+        spawn_test_server_with_backend(Backend::Redis(redis), config).await
+    }
+
+    async fn spawn_test_server_with_backend(backend: Backend, config: ServerConfig) -> TestServer {
         let listener = TcpListener::bind("127.0.0.1:0")
             .await
             .expect("failed to bind");
@@ -69,7 +79,9 @@ mod tests {
         let dispatcher = Arc::new(Dispatcher::new());
         let disp = dispatcher.clone();
         tokio::spawn(async move {
-            kazahane::server::start(&listener, redis, disp).await;
+            kazahane::server::start_with_backend(&listener, backend, disp, config)
+                .await
+                .unwrap();
         });
         TestServer {
             server_addr: addr,
@@ -86,6 +98,366 @@ mod tests {
         let _ = server.connect_and_join(room_id).await;
     }
 
+    #[tokio::test]
+    async fn hello_with_jwt() {
+        init_tracing();
+
+        let verifier = JwtVerifier::new(JwtAlgorithm::HS256, b"secret")
+            .unwrap()
+            .with_audience("kazahane");
+        let server = spawn_test_server_with_config(ServerConfig {
+            authenticator: Arc::new(verifier),
+            ..Default::default()
+        })
+        .await;
+        let mut client = server.connect().await;
+
+        let claims = serde_json::json!({
+            "sub": "alice",
+            "aud": "kazahane",
+            "exp": get_current_timestamp() - 120,
+        });
+        let token = encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret(b"secret"),
+        );
+        client
+            .send(Packet::HelloRequest {
+                request_id: 0,
+                token: token.unwrap().into_bytes(),
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            client.recv().await.unwrap(),
+            Packet::HelloResponse {
+                request_id: 0,
+                status_code: HelloResponseStatusCode::Denied,
+                message: b"token has expired".to_vec(),
+            }
+        );
+
+        let claims = serde_json::json!({
+            "sub": "alice",
+            "aud": "kazahane",
+            "exp": get_current_timestamp() + 120,
+        });
+        let token = encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret(b"secret"),
+        );
+        client
+            .send(Packet::HelloRequest {
+                request_id: 0,
+                token: token.unwrap().into_bytes(),
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            client.recv().await.unwrap(),
+            Packet::HelloResponse {
+                request_id: 0,
+                status_code: HelloResponseStatusCode::OK,
+                message: vec![],
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn protocol_v1_0() {
+        init_tracing();
+
+        // A 1.0.0 client is driven with raw bytes, so that the test doesn't rely on the server's own codec.
+        let server = spawn_test_server_with_backend(
+            Backend::Memory(MemoryBackend::new()),
+            ServerConfig::default(),
+        )
+        .await;
+        let room_id = new_random_room_id();
+        let (mut client, _) =
+            tokio_tungstenite::connect_async(format!("ws://{}", server.server_addr))
+                .await
+                .unwrap();
+        client
+            .send(Message::Binary(b"KAZAHANE 1.0.0\x01\x00\x00".to_vec()))
+            .await
+            .unwrap();
+        assert_eq!(
+            recv_binary(&mut client).await,
+            b"KAZAHANE 1.0.0\x02\x01\x00\x00"
+        );
+        let mut join_request = b"KAZAHANE 1.0.0\x03".to_vec();
+        join_request.extend_from_slice(room_id.as_bytes());
+        client.send(Message::Binary(join_request)).await.unwrap();
+        assert_eq!(recv_binary(&mut client).await, b"KAZAHANE 1.0.0\x04");
+
+        let mut other = server.connect_and_join(room_id).await;
+        assert_player_joined(&mut other).await;
+        // The player id of the other member isn't known to the test.
+        let player_joined = recv_binary(&mut client).await;
+        assert!(player_joined.starts_with(b"KAZAHANE 1.0.0\x06\x01"));
+        assert_eq!(player_joined.len(), MAGIC_SIZE + 2 + 16);
+
+        client
+            .send(Message::Binary(b"KAZAHANE 1.0.0\x05\x05\x00hello".to_vec()))
+            .await
+            .unwrap();
+        assert_broadcast(&mut other, b"hello").await;
+        other
+            .send(Packet::BroadcastRequest {
+                request_id: 0,
+                flags: 0,
+                filter: BroadcastFilter::All,
+                players: vec![],
+                payload: b"to v1.0".to_vec(),
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            recv_binary(&mut client).await,
+            b"KAZAHANE 1.0.0\x06\x03\x07\x00to v1.0"
+        );
+    }
+
+    #[tokio::test]
+    async fn mixed_protocol_versions() {
+        init_tracing();
+
+        let server = spawn_test_server().await;
+        let room_id = new_random_room_id();
+        let mut c1 = server.connect_with_version(ProtocolVersion::V1_1).await;
+        let mut c2 = server.connect_with_version(ProtocolVersion::V2_0).await;
+        for client in [&mut c1, &mut c2] {
+            hello(client, b"").await;
+            join(client, room_id).await;
+        }
+        assert_player_joined(&mut c1).await;
+        assert_player_joined(&mut c2).await;
+
+        c1.send(Packet::BroadcastRequest {
+            request_id: 0,
+            flags: 0,
+            filter: BroadcastFilter::All,
+            players: vec![],
+            payload: b"from v1".to_vec(),
+        })
+        .await
+        .unwrap();
+        assert_broadcast(&mut c2, b"from v1").await;
+        c2.send(Packet::BroadcastRequest {
+            request_id: 0,
+            flags: 0,
+            filter: BroadcastFilter::All,
+            players: vec![],
+            payload: b"from v2".to_vec(),
+        })
+        .await
+        .unwrap();
+        assert_broadcast(&mut c1, b"from v2").await;
+    }
+
+    #[tokio::test]
+    async fn join_before_hello() {
+        init_tracing();
+
+        let server = spawn_test_server().await;
+        let mut client = server.connect().await;
+        client
+            .send(Packet::JoinRoomRequest {
+                request_id: 0,
+                room_id: new_random_room_id().into_bytes(),
+            })
+            .await
+            .unwrap();
+        assert!(matches!(
+            client.recv().await.unwrap(),
+            Packet::Error {
+                code: ErrorCode::NotAuthenticated,
+                ..
+            }
+        ));
+
+        client
+            .send(Packet::HelloRequest {
+                request_id: 0,
+                token: vec![],
+            })
+            .await
+            .unwrap();
+        assert!(matches!(
+            client.recv().await.unwrap(),
+            Packet::HelloResponse {
+                status_code: HelloResponseStatusCode::OK,
+                ..
+            }
+        ));
+        client
+            .send(Packet::HelloRequest {
+                request_id: 0,
+                token: vec![],
+            })
+            .await
+            .unwrap();
+        assert!(matches!(
+            client.recv().await.unwrap(),
+            Packet::Error {
+                code: ErrorCode::AlreadyAuthenticated,
+                ..
+            }
+        ));
+    }
+
+    #[tokio::test]
+    async fn request_ids() {
+        init_tracing();
+
+        let server = spawn_test_server().await;
+        let room_id = new_random_room_id();
+        let mut client = server.connect().await;
+        client
+            .send(Packet::HelloRequest {
+                request_id: 1,
+                token: vec![],
+            })
+            .await
+            .unwrap();
+        assert!(matches!(
+            client.recv().await.unwrap(),
+            Packet::HelloResponse { request_id: 1, .. }
+        ));
+
+        client
+            .send(Packet::TestCountUp { request_id: 2 })
+            .await
+            .unwrap();
+        assert!(matches!(
+            client.recv().await.unwrap(),
+            Packet::Error {
+                request_id: 2,
+                code: ErrorCode::InvalidState,
+                ..
+            }
+        ));
+
+        client
+            .send(Packet::JoinRoomRequest {
+                request_id: 3,
+                room_id: room_id.into_bytes(),
+            })
+            .await
+            .unwrap();
+        assert!(matches!(
+            client.recv().await.unwrap(),
+            Packet::JoinRoomResponse {
+                request_id: 3,
+                status_code: JoinRoomResponseStatusCode::OK,
+                ..
+            }
+        ));
+        client
+            .send(Packet::JoinRoomRequest {
+                request_id: 4,
+                room_id: room_id.into_bytes(),
+            })
+            .await
+            .unwrap();
+        assert!(matches!(
+            client.recv().await.unwrap(),
+            Packet::Error {
+                request_id: 4,
+                code: ErrorCode::InvalidState,
+                ..
+            }
+        ));
+
+        client
+            .send(Packet::TestCountUp { request_id: 5 })
+            .await
+            .unwrap();
+        assert_eq!(
+            client.recv().await.unwrap(),
+            Packet::TestCountUpResponse {
+                request_id: 5,
+                counter: 1
+            }
+        );
+        client
+            .send(Packet::LeaveRoomRequest { request_id: 6 })
+            .await
+            .unwrap();
+        assert_eq!(
+            client.recv().await.unwrap(),
+            Packet::LeaveRoomResponse { request_id: 6 }
+        );
+    }
+
+    #[tokio::test]
+    async fn responses_before_leave() {
+        init_tracing();
+
+        let server = spawn_test_server().await;
+        let room_id = new_random_room_id();
+        let mut client = server.connect_and_join(room_id).await;
+
+        // The requests are answered by the room after the connection has started leaving it.
+        client
+            .send(Packet::TestCountUp { request_id: 1 })
+            .await
+            .unwrap();
+        client
+            .send(Packet::GetStateRequest {
+                request_id: 2,
+                key: b"missing".to_vec(),
+            })
+            .await
+            .unwrap();
+        client
+            .send(Packet::LeaveRoomRequest { request_id: 3 })
+            .await
+            .unwrap();
+        assert_eq!(
+            client.recv().await.unwrap(),
+            Packet::TestCountUpResponse {
+                request_id: 1,
+                counter: 1
+            }
+        );
+        assert!(matches!(
+            client.recv().await.unwrap(),
+            Packet::GetStateResponse {
+                request_id: 2,
+                status_code: StateStatusCode::NotFound,
+                ..
+            }
+        ));
+        assert_eq!(
+            client.recv().await.unwrap(),
+            Packet::LeaveRoomResponse { request_id: 3 }
+        );
+    }
+
+    #[tokio::test]
+    async fn hello_timeout() {
+        init_tracing();
+
+        let server = spawn_test_server_with_config(ServerConfig {
+            hello_timeout: Duration::from_millis(100),
+            ..Default::default()
+        })
+        .await;
+        let mut client = server.connect().await;
+        assert!(matches!(
+            client.recv().await.unwrap(),
+            Packet::Error {
+                code: ErrorCode::HelloTimeout,
+                ..
+            }
+        ));
+        assert!(client.recv().await.is_err());
+    }
+
     #[tokio::test]
     async fn broadcast() {
         init_tracing();
@@ -95,26 +467,24 @@ mod tests {
         let mut c1 = server.connect_and_join(room_id).await;
         let mut c2 = server.connect_and_join(room_id).await;
         let mut c3 = server.connect_and_join(room_id).await;
+        assert_player_joined(&mut c1).await;
+        assert_player_joined(&mut c1).await;
+        assert_player_joined(&mut c2).await;
+        assert_player_joined(&mut c2).await;
+        assert_player_joined(&mut c3).await;
+        assert_player_joined(&mut c3).await;
 
         let packet = Packet::BroadcastRequest {
+            request_id: 0,
+            flags: 0,
+            filter: BroadcastFilter::All,
+            players: vec![],
             payload: b"hello".to_vec(),
         };
         c1.send(packet).await.unwrap();
 
-        let resp = c2.recv().await.unwrap();
-        assert_eq!(
-            resp,
-            Packet::RoomNotification(RoomNotification::Broadcast {
-                payload: b"hello".to_vec()
-            })
-        );
-        let resp = c3.recv().await.unwrap();
-        assert_eq!(
-            resp,
-            Packet::RoomNotification(RoomNotification::Broadcast {
-                payload: b"hello".to_vec()
-            })
-        );
+        assert_broadcast(&mut c2, b"hello").await;
+        assert_broadcast(&mut c3, b"hello").await;
     }
 
     #[tokio::test]
@@ -129,86 +499,1417 @@ mod tests {
         let mut c1 = server1.connect_and_join(room_id).await;
         let mut c2 = server2.connect_and_join(room_id).await;
         let mut c3 = server3.connect_and_join(room_id).await;
+        assert_player_joined(&mut c1).await;
+        assert_player_joined(&mut c1).await;
+        assert_player_joined(&mut c2).await;
+        assert_player_joined(&mut c2).await;
+        assert_player_joined(&mut c3).await;
+        assert_player_joined(&mut c3).await;
 
         let packet = Packet::BroadcastRequest {
+            request_id: 0,
+            flags: 0,
+            filter: BroadcastFilter::All,
+            players: vec![],
             payload: b"hello".to_vec(),
         };
         c1.send(packet).await.unwrap();
 
-        let resp = c2.recv().await.unwrap();
-        assert_eq!(
-            resp,
-            Packet::RoomNotification(RoomNotification::Broadcast {
-                payload: b"hello".to_vec()
+        assert_broadcast(&mut c2, b"hello").await;
+        assert_broadcast(&mut c3, b"hello").await;
+    }
+
+    #[tokio::test]
+    async fn broadcast_large_payload() {
+        init_tracing();
+
+        let server1 = spawn_test_server().await;
+        let server2 = spawn_test_server().await;
+        let room_id = new_random_room_id();
+        let mut c1 = server1.connect_and_join(room_id).await;
+        let mut c2 = server2.connect_and_join(room_id).await;
+        assert_player_joined(&mut c1).await;
+        assert_player_joined(&mut c2).await;
+
+        let payload = vec![0x42; 200 * 1024];
+        c1.send(Packet::BroadcastRequest {
+            request_id: 0,
+            flags: 0,
+            filter: BroadcastFilter::All,
+            players: vec![],
+            payload: payload.clone(),
+        })
+        .await
+        .unwrap();
+        assert_broadcast(&mut c2, &payload).await;
+    }
+
+    #[tokio::test]
+    async fn packet_too_large() {
+        init_tracing();
+
+        let server = spawn_test_server_with_config(ServerConfig {
+            max_packet_size: 1024,
+            ..Default::default()
+        })
+        .await;
+        let mut client = server.connect().await;
+        client
+            .send(Packet::HelloRequest {
+                request_id: 0,
+                token: vec![0; 2048],
             })
-        );
-        let resp = c3.recv().await.unwrap();
-        assert_eq!(
-            resp,
-            Packet::RoomNotification(RoomNotification::Broadcast {
-                payload: b"hello".to_vec()
+            .await
+            .unwrap();
+        assert!(matches!(
+            client.recv().await.unwrap(),
+            Packet::Error {
+                code: ErrorCode::PacketTooLarge,
+                ..
+            }
+        ));
+
+        // The connection is still usable.
+        client
+            .send(Packet::HelloRequest {
+                request_id: 0,
+                token: vec![],
             })
-        );
+            .await
+            .unwrap();
+        assert!(matches!(
+            client.recv().await.unwrap(),
+            Packet::HelloResponse {
+                status_code: HelloResponseStatusCode::OK,
+                ..
+            }
+        ));
     }
 
     #[tokio::test]
-    async fn room_state() {
+    async fn message_far_too_large() {
+        init_tracing();
+
+        let server = spawn_test_server_with_config(ServerConfig {
+            max_packet_size: 1024,
+            ..Default::default()
+        })
+        .await;
+        let mut client = server.connect().await;
+        // Such a message is not buffered, so the connection is closed instead of answered with an error.
+        client
+            .send(Packet::HelloRequest {
+                request_id: 0,
+                token: vec![0; 64 * 1024],
+            })
+            .await
+            .unwrap();
+        assert!(client.recv().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn unsupported_protocol_version() {
+        use futures::{SinkExt, StreamExt};
+        use tokio_tungstenite::tungstenite::Message;
+
         init_tracing();
 
         let server = spawn_test_server().await;
+        let url = format!("ws://{}", server.server_addr);
+        let (mut stream, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+        stream
+            .send(Message::Binary(b"KAZAHANE 9.9.9\x01".to_vec()))
+            .await
+            .unwrap();
+        // An error packet in the latest version, then the connection is closed.
+        let data = match stream.next().await {
+            Some(Ok(Message::Binary(data))) => data,
+            msg => panic!("unexpected message: {:?}", msg),
+        };
+        assert_eq!(&data[..20], b"KAZAHANE 2.0.0\x08\x00\x00\x00\x00\x08");
+        assert!(matches!(
+            stream.next().await,
+            Some(Ok(Message::Close(_))) | None
+        ));
+    }
+
+    #[tokio::test]
+    async fn broadcast_with_sender_identity() {
+        init_tracing();
+
+        let config = || ServerConfig {
+            authenticator: Arc::new(
+                StaticTokenAuthenticator::new()
+                    .with_token("token-a", "alice")
+                    .with_token("token-b", "bob")
+                    .with_token("token-c", "carol"),
+            ),
+            ..Default::default()
+        };
+        let server1 = spawn_test_server_with_config(config()).await;
+        let server2 = spawn_test_server_with_config(config()).await;
         let room_id = new_random_room_id();
-        let mut c1 = server.connect_and_join(room_id).await;
+        let mut alice = server1
+            .connect_and_join_with_token(room_id, b"token-a")
+            .await;
+        let mut bob = server1
+            .connect_and_join_with_token(room_id, b"token-b")
+            .await;
+        let mut carol = server2
+            .connect_and_join_with_token(room_id, b"token-c")
+            .await;
+        assert_player_joined(&mut alice).await;
+        assert_player_joined(&mut alice).await;
+        assert_player_joined(&mut bob).await;
+        assert_player_joined(&mut bob).await;
+        assert_player_joined(&mut carol).await;
+        assert_player_joined(&mut carol).await;
 
-        c1.send(Packet::TestCountUp {}).await.unwrap();
-        let resp = c1.recv().await.unwrap();
-        assert_eq!(resp, Packet::TestCountUpResponse { counter: 1 });
+        let sent_at = get_current_timestamp() * 1000;
+        alice
+            .send(Packet::BroadcastRequest {
+                request_id: 0,
+                flags: 0,
+                filter: BroadcastFilter::All,
+                players: vec![],
+                payload: b"hello".to_vec(),
+            })
+            .await
+            .unwrap();
 
-        let mut c2 = server.connect_and_join(room_id).await;
-        c2.send(Packet::TestCountUp {}).await.unwrap();
-        let resp = c2.recv().await.unwrap();
-        assert_eq!(resp, Packet::TestCountUpResponse { counter: 2 });
+        let mut senders = vec![];
+        for client in [&mut bob, &mut carol] {
+            match client.recv().await.unwrap() {
+                Packet::RoomNotification(RoomNotification::Broadcast {
+                    sender,
+                    user_id,
+                    timestamp,
+                    payload,
+                }) => {
+                    assert_eq!(user_id, b"alice");
+                    assert_eq!(payload, b"hello");
+                    assert!(timestamp >= sent_at, "timestamp: {}", timestamp);
+                    senders.push((sender, timestamp));
+                }
+                packet => panic!("unexpected packet: {:?}", packet),
+            }
+        }
+        // Members on every server see the same sender and timestamp.
+        assert_eq!(senders[0], senders[1]);
+    }
 
-        let another_room_id = new_random_room_id();
-        let mut c3 = server.connect_and_join(another_room_id).await;
-        c3.send(Packet::TestCountUp {}).await.unwrap();
-        let resp = c3.recv().await.unwrap();
-        assert_eq!(resp, Packet::TestCountUpResponse { counter: 1 });
+    #[tokio::test]
+    async fn broadcast_options() {
+        init_tracing();
+
+        let server1 = spawn_test_server().await;
+        let server2 = spawn_test_server().await;
+        let room_id = new_random_room_id();
+        let mut c1 = server1.connect_and_join(room_id).await;
+        let mut c2 = server1.connect_and_join(room_id).await;
+        let mut c3 = server2.connect_and_join(room_id).await;
+        recv_player_joined(&mut c1).await;
+        let c3_id = recv_player_joined(&mut c1).await;
+        assert_player_joined(&mut c2).await;
+        assert_player_joined(&mut c2).await;
+        assert_player_joined(&mut c3).await;
+        assert_player_joined(&mut c3).await;
+
+        c1.send(Packet::BroadcastRequest {
+            request_id: 0,
+            flags: BROADCAST_FLAG_ECHO,
+            filter: BroadcastFilter::Include,
+            players: vec![c3_id],
+            payload: b"echo and only c3".to_vec(),
+        })
+        .await
+        .unwrap();
+        assert_broadcast(&mut c1, b"echo and only c3").await;
+        assert_broadcast(&mut c3, b"echo and only c3").await;
+
+        c1.send(Packet::BroadcastRequest {
+            request_id: 0,
+            flags: 0,
+            filter: BroadcastFilter::Exclude,
+            players: vec![c3_id],
+            payload: b"except c3".to_vec(),
+        })
+        .await
+        .unwrap();
+        // c2 was not included in the first broadcast.
+        assert_broadcast(&mut c2, b"except c3").await;
+
+        c2.send(Packet::BroadcastRequest {
+            request_id: 0,
+            flags: 0,
+            filter: BroadcastFilter::All,
+            players: vec![],
+            payload: b"everyone".to_vec(),
+        })
+        .await
+        .unwrap();
+        // Neither the sender without echo nor the excluded player received the second broadcast.
+        assert_broadcast(&mut c1, b"everyone").await;
+        assert_broadcast(&mut c3, b"everyone").await;
     }
 
     #[tokio::test]
-    async fn room_live_migration() {
+    async fn direct_message() {
         init_tracing();
 
         let server1 = spawn_test_server().await;
         let server2 = spawn_test_server().await;
+        let room_id = new_random_room_id();
+        let mut c1 = server1.connect_and_join(room_id).await;
+        let mut c2 = server1.connect_and_join(room_id).await;
+        let mut c3 = server2.connect_and_join(room_id).await;
+        let c2_id = recv_player_joined(&mut c1).await;
+        let c3_id = recv_player_joined(&mut c1).await;
+        assert_player_joined(&mut c2).await;
+        assert_player_joined(&mut c2).await;
+        assert_player_joined(&mut c3).await;
+        assert_player_joined(&mut c3).await;
+
+        // To a player on another server.
+        c1.send(Packet::DirectMessageRequest {
+            request_id: 0,
+            targets: vec![c3_id],
+            payload: b"to c3".to_vec(),
+        })
+        .await
+        .unwrap();
+        assert_direct_message(&mut c3, b"to c3").await;
+
+        // To players on both servers.
+        c1.send(Packet::DirectMessageRequest {
+            request_id: 0,
+            targets: vec![c2_id, c3_id],
+            payload: b"to all".to_vec(),
+        })
+        .await
+        .unwrap();
+        assert_direct_message(&mut c2, b"to all").await;
+        assert_direct_message(&mut c3, b"to all").await;
+    }
+
+    #[tokio::test]
+    async fn presence() {
+        init_tracing();
 
+        let server1 = spawn_test_server().await;
+        let server2 = spawn_test_server().await;
         let room_id = new_random_room_id();
 
         let mut c1 = server1.connect_and_join(room_id).await;
-        c1.send(Packet::TestCountUp {}).await.unwrap();
-        let resp = c1.recv().await.unwrap();
-        assert_eq!(resp, Packet::TestCountUpResponse { counter: 1 });
-
         let mut c2 = server1.connect_and_join(room_id).await;
-        c2.send(Packet::TestCountUp {}).await.unwrap();
-        let resp = c2.recv().await.unwrap();
-        assert_eq!(resp, Packet::TestCountUpResponse { counter: 2 });
+        let mut c3 = server2.connect_and_join(room_id).await;
+        let c2_id = recv_player_joined(&mut c1).await;
+        let c3_id = recv_player_joined(&mut c1).await;
+        let c1_id = recv_player_joined(&mut c2).await;
+        assert_eq!(recv_player_joined(&mut c2).await, c3_id);
 
-        server1.shutdown().await;
+        // A new player is told about the members that joined before it, on any server.
+        let mut members = vec![
+            recv_player_joined(&mut c3).await,
+            recv_player_joined(&mut c3).await,
+        ];
+        members.sort();
+        let mut expected = vec![c1_id, c2_id];
+        expected.sort();
+        assert_eq!(members, expected);
 
-        assert_eq!(
+        drop(c2);
+        assert!(matches!(
             c1.recv().await.unwrap(),
-            Packet::ServerNotification(ServerNotification::Shutdown)
+            Packet::RoomNotification(RoomNotification::PlayerLeft { .. })
+        ));
+        drop(c3);
+        assert!(matches!(
+            c1.recv().await.unwrap(),
+            Packet::RoomNotification(RoomNotification::PlayerLeft { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn switch_rooms() {
+        init_tracing();
+
+        let server = spawn_test_server().await;
+        let room1 = new_random_room_id();
+        let room2 = new_random_room_id();
+
+        let mut c1 = server.connect_and_join(room1).await;
+        let mut c2 = server.connect_and_join(room1).await;
+        let mut c3 = server.connect_and_join(room2).await;
+        assert_player_joined(&mut c1).await;
+        assert_player_joined(&mut c2).await;
+
+        c2.send(Packet::LeaveRoomRequest { request_id: 0 })
+            .await
+            .unwrap();
+        assert_eq!(
+            c2.recv().await.unwrap(),
+            Packet::LeaveRoomResponse { request_id: 0 }
         );
+        assert!(matches!(
+            c1.recv().await.unwrap(),
+            Packet::RoomNotification(RoomNotification::PlayerLeft { .. })
+        ));
+
+        join(&mut c2, room2).await;
+        assert_player_joined(&mut c2).await;
+        assert_player_joined(&mut c3).await;
+
+        // c2 no longer receives broadcasts in room1.
+        c1.send(Packet::BroadcastRequest {
+            request_id: 0,
+            flags: 0,
+            filter: BroadcastFilter::All,
+            players: vec![],
+            payload: b"room1".to_vec(),
+        })
+        .await
+        .unwrap();
+        c3.send(Packet::BroadcastRequest {
+            request_id: 0,
+            flags: 0,
+            filter: BroadcastFilter::All,
+            players: vec![],
+            payload: b"room2".to_vec(),
+        })
+        .await
+        .unwrap();
+        assert_broadcast(&mut c2, b"room2").await;
+    }
+
+    #[tokio::test]
+    async fn join_full_room() {
+        init_tracing();
+
+        let server = spawn_test_server_with_config(ServerConfig {
+            max_room_members_per_server: Some(1),
+            ..Default::default()
+        })
+        .await;
+        let room_id = new_random_room_id();
+        let mut c1 = server.connect_and_join(room_id).await;
+
+        let mut c2 = server.connect().await;
+        c2.send(Packet::HelloRequest {
+            request_id: 0,
+            token: vec![],
+        })
+        .await
+        .unwrap();
+        assert!(matches!(
+            c2.recv().await.unwrap(),
+            Packet::HelloResponse {
+                status_code: HelloResponseStatusCode::OK,
+                ..
+            }
+        ));
+        c2.send(Packet::JoinRoomRequest {
+            request_id: 0,
+            room_id: room_id.into_bytes(),
+        })
+        .await
+        .unwrap();
         assert_eq!(
             c2.recv().await.unwrap(),
-            Packet::ServerNotification(ServerNotification::Shutdown)
+            Packet::JoinRoomResponse {
+                request_id: 0,
+                status_code: JoinRoomResponseStatusCode::RoomFull,
+                message: b"room is full".to_vec(),
+            }
         );
 
-        let mut c1_next = server2.connect_and_join(room_id).await;
-        c1_next.send(Packet::TestCountUp {}).await.unwrap();
-        let resp = c1_next.recv().await.unwrap();
-        assert_eq!(resp, Packet::TestCountUpResponse { counter: 3 });
+        c1.send(Packet::LeaveRoomRequest { request_id: 0 })
+            .await
+            .unwrap();
+        assert_eq!(
+            c1.recv().await.unwrap(),
+            Packet::LeaveRoomResponse { request_id: 0 }
+        );
+        join(&mut c2, room_id).await;
+    }
+
+    #[tokio::test]
+    async fn room_state() {
+        init_tracing();
+
+        let server = spawn_test_server().await;
+        let room_id = new_random_room_id();
+        let mut c1 = server.connect_and_join(room_id).await;
+
+        c1.send(Packet::TestCountUp { request_id: 0 })
+            .await
+            .unwrap();
+        let resp = c1.recv().await.unwrap();
+        assert_eq!(
+            resp,
+            Packet::TestCountUpResponse {
+                request_id: 0,
+                counter: 1,
+            }
+        );
+
+        let mut c2 = server.connect_and_join(room_id).await;
+        assert_player_joined(&mut c2).await;
+        c2.send(Packet::TestCountUp { request_id: 0 })
+            .await
+            .unwrap();
+        let resp = c2.recv().await.unwrap();
+        assert_eq!(
+            resp,
+            Packet::TestCountUpResponse {
+                request_id: 0,
+                counter: 2,
+            }
+        );
+
+        let another_room_id = new_random_room_id();
+        let mut c3 = server.connect_and_join(another_room_id).await;
+        c3.send(Packet::TestCountUp { request_id: 0 })
+            .await
+            .unwrap();
+        let resp = c3.recv().await.unwrap();
+        assert_eq!(
+            resp,
+            Packet::TestCountUpResponse {
+                request_id: 0,
+                counter: 1,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn room_state_api() {
+        init_tracing();
+
+        let config = || ServerConfig {
+            max_state_value_size: 16,
+            ..Default::default()
+        };
+        let server1 = spawn_test_server_with_config(config()).await;
+        let server2 = spawn_test_server_with_config(config()).await;
+        let room_id = new_random_room_id();
+        let mut c1 = server1.connect_and_join(room_id).await;
+        let mut c2 = server1.connect_and_join(room_id).await;
+        let mut c3 = server2.connect_and_join(room_id).await;
+        assert_player_joined(&mut c1).await;
+        assert_player_joined(&mut c1).await;
+        assert_player_joined(&mut c2).await;
+        assert_player_joined(&mut c2).await;
+        assert_player_joined(&mut c3).await;
+        assert_player_joined(&mut c3).await;
+        for client in [&mut c1, &mut c2, &mut c3] {
+            subscribe_state(client, SUBSCRIBE_STATE_FLAG_PREFIX, b"").await;
+        }
+
+        c1.send(Packet::GetStateRequest {
+            request_id: 1,
+            key: b"color".to_vec(),
+        })
+        .await
+        .unwrap();
+        assert_eq!(
+            c1.recv().await.unwrap(),
+            Packet::GetStateResponse {
+                request_id: 1,
+                status_code: StateStatusCode::NotFound,
+                version: 0,
+                value: vec![],
+            }
+        );
+
+        c1.send(Packet::SetStateRequest {
+            request_id: 2,
+            key: b"color".to_vec(),
+            flags: 0,
+            expected_version: 0,
+            ttl: 0,
+            value: b"red".to_vec(),
+        })
+        .await
+        .unwrap();
+        assert_eq!(
+            c1.recv().await.unwrap(),
+            Packet::SetStateResponse {
+                request_id: 2,
+                status_code: StateStatusCode::OK,
+                version: 1,
+            }
+        );
+        for client in [&mut c2, &mut c3] {
+            assert_eq!(
+                client.recv().await.unwrap(),
+                Packet::RoomNotification(RoomNotification::StateChanged {
+                    key: b"color".to_vec(),
+                    version: 1,
+                    value: b"red".to_vec(),
+                })
+            );
+        }
+
+        c3.send(Packet::GetStateRequest {
+            request_id: 3,
+            key: b"color".to_vec(),
+        })
+        .await
+        .unwrap();
+        assert_eq!(
+            c3.recv().await.unwrap(),
+            Packet::GetStateResponse {
+                request_id: 3,
+                status_code: StateStatusCode::OK,
+                version: 1,
+                value: b"red".to_vec(),
+            }
+        );
+
+        c3.send(Packet::DeleteStateRequest {
+            request_id: 4,
+            key: b"color".to_vec(),
+            flags: 0,
+            expected_version: 0,
+        })
+        .await
+        .unwrap();
+        assert_eq!(
+            c3.recv().await.unwrap(),
+            Packet::DeleteStateResponse {
+                request_id: 4,
+                status_code: StateStatusCode::OK,
+                version: 2,
+            }
+        );
+        for client in [&mut c1, &mut c2] {
+            assert_eq!(
+                client.recv().await.unwrap(),
+                Packet::RoomNotification(RoomNotification::StateDeleted {
+                    key: b"color".to_vec(),
+                    version: 2,
+                })
+            );
+        }
+
+        // Limits are checked before the room is asked.
+        for (request_id, key, value, status_code) in [
+            (5, vec![], vec![], StateStatusCode::InvalidKey),
+            (6, vec![0xFF], vec![], StateStatusCode::InvalidKey),
+            (
+                7,
+                b"color".to_vec(),
+                vec![0; 17],
+                StateStatusCode::ValueTooLarge,
+            ),
+        ] {
+            c1.send(Packet::SetStateRequest {
+                request_id,
+                key,
+                flags: 0,
+                expected_version: 0,
+                ttl: 0,
+                value,
+            })
+            .await
+            .unwrap();
+            assert_eq!(
+                c1.recv().await.unwrap(),
+                Packet::SetStateResponse {
+                    request_id,
+                    status_code,
+                    version: 0,
+                }
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn atomic_room_state() {
+        init_tracing();
+
+        let server1 = spawn_test_server().await;
+        let server2 = spawn_test_server().await;
+        let room_id = new_random_room_id();
+        let mut c1 = server1.connect_and_join(room_id).await;
+        let mut c2 = server2.connect_and_join(room_id).await;
+        assert_player_joined(&mut c1).await;
+        assert_player_joined(&mut c2).await;
+        subscribe_state(&mut c2, 0, b"score").await;
+
+        c1.send(Packet::IncrementStateRequest {
+            request_id: 1,
+            key: b"score".to_vec(),
+            delta: 5,
+        })
+        .await
+        .unwrap();
+        assert_eq!(
+            c1.recv().await.unwrap(),
+            Packet::IncrementStateResponse {
+                request_id: 1,
+                status_code: StateStatusCode::OK,
+                version: 1,
+                value: 5,
+            }
+        );
+        assert_eq!(
+            c2.recv().await.unwrap(),
+            Packet::RoomNotification(RoomNotification::StateChanged {
+                key: b"score".to_vec(),
+                version: 1,
+                value: b"5".to_vec(),
+            })
+        );
+
+        c2.send(Packet::SetStateIfAbsentRequest {
+            request_id: 2,
+            key: b"score".to_vec(),
+            value: b"0".to_vec(),
+        })
+        .await
+        .unwrap();
+        assert_eq!(
+            c2.recv().await.unwrap(),
+            Packet::SetStateIfAbsentResponse {
+                request_id: 2,
+                status_code: StateStatusCode::AlreadyExists,
+                version: 0,
+            }
+        );
+
+        // The result overflows.
+        c1.send(Packet::IncrementStateRequest {
+            request_id: 3,
+            key: b"score".to_vec(),
+            delta: i64::MAX,
+        })
+        .await
+        .unwrap();
+        assert_eq!(
+            c1.recv().await.unwrap(),
+            Packet::IncrementStateResponse {
+                request_id: 3,
+                status_code: StateStatusCode::NotAnInteger,
+                version: 0,
+                value: 0,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn versioned_room_state() {
+        init_tracing();
+
+        let server = spawn_test_server().await;
+        let room_id = new_random_room_id();
+        let mut c1 = server.connect_and_join(room_id).await;
+        let mut c2 = server.connect_and_join(room_id).await;
+        assert_player_joined(&mut c1).await;
+        assert_player_joined(&mut c2).await;
+        subscribe_state(&mut c1, 0, b"inventory").await;
+        subscribe_state(&mut c2, 0, b"inventory").await;
+
+        let set = |request_id, expected_version, value: &[u8]| Packet::SetStateRequest {
+            request_id,
+            key: b"inventory".to_vec(),
+            flags: STATE_FLAG_EXPECT_VERSION,
+            expected_version,
+            ttl: 0,
+            value: value.to_vec(),
+        };
+        let delete = |request_id, expected_version| Packet::DeleteStateRequest {
+            request_id,
+            key: b"inventory".to_vec(),
+            flags: STATE_FLAG_EXPECT_VERSION,
+            expected_version,
+        };
+
+        // Both players edit the version they have seen, only the first write wins.
+        c1.send(set(1, 0, b"sword")).await.unwrap();
+        assert_eq!(
+            c1.recv().await.unwrap(),
+            Packet::SetStateResponse {
+                request_id: 1,
+                status_code: StateStatusCode::OK,
+                version: 1,
+            }
+        );
+        assert_eq!(
+            c2.recv().await.unwrap(),
+            Packet::RoomNotification(RoomNotification::StateChanged {
+                key: b"inventory".to_vec(),
+                version: 1,
+                value: b"sword".to_vec(),
+            })
+        );
+        c2.send(set(2, 0, b"shield")).await.unwrap();
+        assert_eq!(
+            c2.recv().await.unwrap(),
+            Packet::SetStateResponse {
+                request_id: 2,
+                status_code: StateStatusCode::VersionMismatch,
+                version: 1,
+            }
+        );
+        c2.send(set(3, 1, b"sword,shield")).await.unwrap();
+        assert_eq!(
+            c2.recv().await.unwrap(),
+            Packet::SetStateResponse {
+                request_id: 3,
+                status_code: StateStatusCode::OK,
+                version: 2,
+            }
+        );
+        c1.recv().await.unwrap();
+
+        c1.send(delete(4, 1)).await.unwrap();
+        assert_eq!(
+            c1.recv().await.unwrap(),
+            Packet::DeleteStateResponse {
+                request_id: 4,
+                status_code: StateStatusCode::VersionMismatch,
+                version: 2,
+            }
+        );
+        c1.send(delete(5, 2)).await.unwrap();
+        assert_eq!(
+            c1.recv().await.unwrap(),
+            Packet::DeleteStateResponse {
+                request_id: 5,
+                status_code: StateStatusCode::OK,
+                version: 3,
+            }
+        );
+        assert_eq!(
+            c2.recv().await.unwrap(),
+            Packet::RoomNotification(RoomNotification::StateDeleted {
+                key: b"inventory".to_vec(),
+                version: 3,
+            })
+        );
+
+        // The version is kept after the delete.
+        c2.send(Packet::GetStateRequest {
+            request_id: 6,
+            key: b"inventory".to_vec(),
+        })
+        .await
+        .unwrap();
+        assert_eq!(
+            c2.recv().await.unwrap(),
+            Packet::GetStateResponse {
+                request_id: 6,
+                status_code: StateStatusCode::NotFound,
+                version: 3,
+                value: vec![],
+            }
+        );
+        c2.send(set(7, 0, b"potion")).await.unwrap();
+        assert_eq!(
+            c2.recv().await.unwrap(),
+            Packet::SetStateResponse {
+                request_id: 7,
+                status_code: StateStatusCode::VersionMismatch,
+                version: 3,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn room_state_subscriptions() {
+        init_tracing();
+
+        let server1 = spawn_test_server().await;
+        let server2 = spawn_test_server().await;
+        let room_id = new_random_room_id();
+        let mut c1 = server1.connect_and_join(room_id).await;
+        let set = |request_id, key: &[u8], value: &[u8]| Packet::SetStateRequest {
+            request_id,
+            key: key.to_vec(),
+            flags: 0,
+            expected_version: 0,
+            ttl: 0,
+            value: value.to_vec(),
+        };
+        for (request_id, key) in [(1, &b"player/1"[..]), (2, b"player/2"), (3, b"monster/1")] {
+            c1.send(set(request_id, key, b"hp=10")).await.unwrap();
+            c1.recv().await.unwrap();
+        }
+
+        // A late joiner receives a snapshot of the keys it subscribes to.
+        let mut c2 = server2.connect_and_join(room_id).await;
+        assert_player_joined(&mut c1).await;
+        assert_player_joined(&mut c2).await;
+        c2.send(Packet::SubscribeStateRequest {
+            request_id: 4,
+            flags: SUBSCRIBE_STATE_FLAG_PREFIX,
+            key: b"player/".to_vec(),
+        })
+        .await
+        .unwrap();
+        assert_eq!(
+            c2.recv().await.unwrap(),
+            Packet::SubscribeStateResponse {
+                request_id: 4,
+                status_code: StateStatusCode::OK,
+            }
+        );
+        let mut snapshot = vec![];
+        for _ in 0..2 {
+            match c2.recv().await.unwrap() {
+                Packet::RoomNotification(RoomNotification::StateChanged { key, .. }) => {
+                    snapshot.push(key)
+                }
+                packet => panic!("unexpected packet: {:?}", packet),
+            }
+        }
+        snapshot.sort();
+        assert_eq!(snapshot, vec![b"player/1".to_vec(), b"player/2".to_vec()]);
+
+        // Changes from another server are delivered only for the subscribed keys.
+        c1.send(set(5, b"monster/1", b"hp=5")).await.unwrap();
+        c1.recv().await.unwrap();
+        c1.send(set(6, b"player/1", b"hp=3")).await.unwrap();
+        c1.recv().await.unwrap();
+        assert_state_changed(&mut c2, b"player/1", 2, b"hp=3").await;
+
+        subscribe_state(&mut c2, 0, b"monster/1").await;
+        assert_state_changed(&mut c2, b"monster/1", 2, b"hp=5").await;
+
+        c2.send(Packet::UnsubscribeStateRequest {
+            request_id: 7,
+            flags: SUBSCRIBE_STATE_FLAG_PREFIX,
+            key: b"player/".to_vec(),
+        })
+        .await
+        .unwrap();
+        assert_eq!(
+            c2.recv().await.unwrap(),
+            Packet::UnsubscribeStateResponse {
+                request_id: 7,
+                status_code: StateStatusCode::OK,
+            }
+        );
+        c1.send(set(8, b"player/2", b"hp=0")).await.unwrap();
+        c1.recv().await.unwrap();
+        c1.send(set(9, b"monster/1", b"hp=0")).await.unwrap();
+        c1.recv().await.unwrap();
+        assert_state_changed(&mut c2, b"monster/1", 3, b"hp=0").await;
+    }
+
+    #[tokio::test]
+    async fn room_state_large_snapshot() {
+        init_tracing();
+
+        let server = spawn_test_server().await;
+        let room_id = new_random_room_id();
+        let mut client = server.connect_and_join(room_id).await;
+        // More keys than the capacity of the channels between a connection and its room.
+        let keys: Vec<Vec<u8>> = (0..32).map(|i| format!("key/{}", i).into_bytes()).collect();
+        for key in &keys {
+            client
+                .send(Packet::SetStateRequest {
+                    request_id: 0,
+                    key: key.clone(),
+                    flags: 0,
+                    expected_version: 0,
+                    ttl: 0,
+                    value: b"value".to_vec(),
+                })
+                .await
+                .unwrap();
+            client.recv().await.unwrap();
+        }
+
+        // Requests sent while the snapshot is delivered must not stall the room.
+        client
+            .send(Packet::SubscribeStateRequest {
+                request_id: 1,
+                flags: SUBSCRIBE_STATE_FLAG_PREFIX,
+                key: b"key/".to_vec(),
+            })
+            .await
+            .unwrap();
+        for key in &keys {
+            client
+                .send(Packet::GetStateRequest {
+                    request_id: 2,
+                    key: key.clone(),
+                })
+                .await
+                .unwrap();
+        }
+        let (mut snapshot, mut responses) = (0, 0);
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while snapshot + responses < 2 * keys.len() {
+                match client.recv().await.unwrap() {
+                    Packet::SubscribeStateResponse { request_id: 1, .. } => {}
+                    Packet::RoomNotification(RoomNotification::StateChanged { .. }) => {
+                        snapshot += 1
+                    }
+                    Packet::GetStateResponse { request_id: 2, .. } => responses += 1,
+                    packet => panic!("unexpected packet: {:?}", packet),
+                }
+            }
+        })
+        .await
+        .expect("the room is stalled");
+        assert_eq!((snapshot, responses), (keys.len(), keys.len()));
+    }
+
+    #[tokio::test]
+    async fn room_state_expiry() {
+        init_tracing();
+
+        let config = ServerConfig {
+            state_retention: Some(Duration::from_millis(400)),
+            ..Default::default()
+        };
+        let server = spawn_test_server_with_config(config).await;
+        let room_id = new_random_room_id();
+        let mut c1 = server.connect_and_join(room_id).await;
+        for (key, ttl) in [(&b"short"[..], 100), (b"long", 0)] {
+            c1.send(Packet::SetStateRequest {
+                request_id: 0,
+                key: key.to_vec(),
+                flags: 0,
+                expected_version: 0,
+                ttl,
+                value: b"value".to_vec(),
+            })
+            .await
+            .unwrap();
+            c1.recv().await.unwrap();
+        }
+
+        // The retention period does not pass while the room has members.
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        assert_eq!(
+            get_state_status(&mut c1, b"short").await,
+            StateStatusCode::NotFound
+        );
+        assert_eq!(
+            get_state_status(&mut c1, b"long").await,
+            StateStatusCode::OK
+        );
+
+        drop(c1);
+        let mut c2 = server.connect_and_join(room_id).await;
+        assert_eq!(
+            get_state_status(&mut c2, b"long").await,
+            StateStatusCode::OK
+        );
+        c2.send(Packet::LeaveRoomRequest { request_id: 0 })
+            .await
+            .unwrap();
+        c2.recv().await.unwrap();
+
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        join(&mut c2, room_id).await;
+        assert_eq!(
+            get_state_status(&mut c2, b"long").await,
+            StateStatusCode::NotFound
+        );
+    }
+
+    #[tokio::test]
+    async fn room_state_ttl_too_long() {
+        init_tracing();
+
+        let server = spawn_test_server().await;
+        let mut client = server.connect_and_join(new_random_room_id()).await;
+        client
+            .send(Packet::SetStateRequest {
+                request_id: 1,
+                key: b"key".to_vec(),
+                flags: 0,
+                expected_version: 0,
+                ttl: u64::MAX,
+                value: b"value".to_vec(),
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            client.recv().await.unwrap(),
+            Packet::SetStateResponse {
+                request_id: 1,
+                status_code: StateStatusCode::InvalidTtl,
+                version: 0,
+            }
+        );
+        assert_eq!(
+            get_state_status(&mut client, b"key").await,
+            StateStatusCode::NotFound
+        );
+    }
+
+    #[tokio::test]
+    async fn redis_streams_backend() {
+        init_tracing();
+
+        let redis = redis::Client::open("redis://127.0.0.1").unwrap();
+        let server1 = spawn_test_server_with_backend(
+            Backend::RedisStreams(redis.clone()),
+            ServerConfig::default(),
+        )
+        .await;
+        let server2 =
+            spawn_test_server_with_backend(Backend::RedisStreams(redis), ServerConfig::default())
+                .await;
+        let room_id = new_random_room_id();
+        let mut c1 = server1.connect_and_join(room_id).await;
+        let mut c2 = server2.connect_and_join(room_id).await;
+        assert_player_joined(&mut c1).await;
+        assert_player_joined(&mut c2).await;
+
+        for payload in [&b"hello"[..], b"world"] {
+            c1.send(Packet::BroadcastRequest {
+                request_id: 0,
+                flags: 0,
+                filter: BroadcastFilter::All,
+                players: vec![],
+                payload: payload.to_vec(),
+            })
+            .await
+            .unwrap();
+        }
+        assert_broadcast(&mut c2, b"hello").await;
+        assert_broadcast(&mut c2, b"world").await;
+    }
+
+    #[tokio::test]
+    async fn memory_backend() {
+        init_tracing();
+
+        let backend = MemoryBackend::new();
+        let server1 = spawn_test_server_with_backend(
+            Backend::Memory(backend.clone()),
+            ServerConfig::default(),
+        )
+        .await;
+        let server2 =
+            spawn_test_server_with_backend(Backend::Memory(backend), ServerConfig::default()).await;
+        let room_id = new_random_room_id();
+        let mut c1 = server1.connect_and_join(room_id).await;
+        let mut c2 = server2.connect_and_join(room_id).await;
+        assert_player_joined(&mut c1).await;
+        assert_player_joined(&mut c2).await;
+
+        c1.send(Packet::BroadcastRequest {
+            request_id: 0,
+            flags: 0,
+            filter: BroadcastFilter::All,
+            players: vec![],
+            payload: b"hello".to_vec(),
+        })
+        .await
+        .unwrap();
+        assert_broadcast(&mut c2, b"hello").await;
+
+        subscribe_state(&mut c2, 0, b"score").await;
+        c1.send(Packet::SetStateRequest {
+            request_id: 1,
+            key: b"score".to_vec(),
+            flags: 0,
+            expected_version: 0,
+            ttl: 0,
+            value: b"10".to_vec(),
+        })
+        .await
+        .unwrap();
+        assert_eq!(
+            c1.recv().await.unwrap(),
+            Packet::SetStateResponse {
+                request_id: 1,
+                status_code: StateStatusCode::OK,
+                version: 1,
+            }
+        );
+        assert_state_changed(&mut c2, b"score", 1, b"10").await;
+        assert_eq!(
+            get_state_status(&mut c2, b"score").await,
+            StateStatusCode::OK
+        );
+    }
+
+    #[tokio::test]
+    async fn sled_backend() {
+        init_tracing();
+
+        let path = std::env::temp_dir().join(format!("kazahane-test-{}", new_random_room_id()));
+        let backend = SledBackend::open(&path).unwrap();
+        let server =
+            spawn_test_server_with_backend(Backend::Sled(backend), ServerConfig::default()).await;
+        let room_id = new_random_room_id();
+        let mut c1 = server.connect_and_join(room_id).await;
+        c1.send(Packet::IncrementStateRequest {
+            request_id: 1,
+            key: b"counter".to_vec(),
+            delta: 3,
+        })
+        .await
+        .unwrap();
+        assert_eq!(
+            c1.recv().await.unwrap(),
+            Packet::IncrementStateResponse {
+                request_id: 1,
+                status_code: StateStatusCode::OK,
+                version: 1,
+                value: 3,
+            }
+        );
+
+        let mut c2 = server.connect_and_join(room_id).await;
+        assert_player_joined(&mut c2).await;
+        c2.send(Packet::GetStateRequest {
+            request_id: 2,
+            key: b"counter".to_vec(),
+        })
+        .await
+        .unwrap();
+        assert_eq!(
+            c2.recv().await.unwrap(),
+            Packet::GetStateResponse {
+                request_id: 2,
+                status_code: StateStatusCode::OK,
+                version: 1,
+                value: b"3".to_vec(),
+            }
+        );
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    async fn get_state_status(client: &mut impl Connection, key: &[u8]) -> StateStatusCode {
+        client
+            .send(Packet::GetStateRequest {
+                request_id: 0,
+                key: key.to_vec(),
+            })
+            .await
+            .unwrap();
+        match client.recv().await.unwrap() {
+            Packet::GetStateResponse { status_code, .. } => status_code,
+            packet => panic!("unexpected packet: {:?}", packet),
+        }
+    }
+
+    #[tokio::test]
+    async fn rejoin_after_disconnect() {
+        init_tracing();
+
+        let server = spawn_test_server().await;
+        let room_id = new_random_room_id();
+
+        let mut c1 = server.connect_and_join(room_id).await;
+        c1.send(Packet::TestCountUp { request_id: 0 })
+            .await
+            .unwrap();
+        let resp = c1.recv().await.unwrap();
+        assert_eq!(
+            resp,
+            Packet::TestCountUpResponse {
+                request_id: 0,
+                counter: 1,
+            }
+        );
+
+        // The room is closed when its last member disconnects.
+        drop(c1);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let mut c2 = server.connect_and_join(room_id).await;
+        c2.send(Packet::TestCountUp { request_id: 0 })
+            .await
+            .unwrap();
+        let resp = c2.recv().await.unwrap();
+        assert_eq!(
+            resp,
+            Packet::TestCountUpResponse {
+                request_id: 0,
+                counter: 2,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn room_live_migration() {
+        init_tracing();
+
+        let server1 = spawn_test_server().await;
+        let server2 = spawn_test_server().await;
+
+        let room_id = new_random_room_id();
+
+        let mut c1 = server1.connect_and_join(room_id).await;
+        c1.send(Packet::TestCountUp { request_id: 0 })
+            .await
+            .unwrap();
+        let resp = c1.recv().await.unwrap();
+        assert_eq!(
+            resp,
+            Packet::TestCountUpResponse {
+                request_id: 0,
+                counter: 1,
+            }
+        );
+
+        let mut c2 = server1.connect_and_join(room_id).await;
+        assert_player_joined(&mut c1).await;
+        assert_player_joined(&mut c2).await;
+        c2.send(Packet::TestCountUp { request_id: 0 })
+            .await
+            .unwrap();
+        let resp = c2.recv().await.unwrap();
+        assert_eq!(
+            resp,
+            Packet::TestCountUpResponse {
+                request_id: 0,
+                counter: 2,
+            }
+        );
+
+        server1.shutdown().await;
+
+        assert_eq!(
+            c1.recv().await.unwrap(),
+            Packet::ServerNotification(ServerNotification::Shutdown)
+        );
+        assert_eq!(
+            c2.recv().await.unwrap(),
+            Packet::ServerNotification(ServerNotification::Shutdown)
+        );
+
+        // c1 and c2 are still connected to the room on server1.
+        let mut c1_next = server2.connect_and_join(room_id).await;
+        assert_player_joined(&mut c1_next).await;
+        assert_player_joined(&mut c1_next).await;
+        c1_next
+            .send(Packet::TestCountUp { request_id: 0 })
+            .await
+            .unwrap();
+        let resp = c1_next.recv().await.unwrap();
+        assert_eq!(
+            resp,
+            Packet::TestCountUpResponse {
+                request_id: 0,
+                counter: 3,
+            }
+        );
+    }
+
+    async fn hello(client: &mut impl Connection, token: &[u8]) {
+        client
+            .send(Packet::HelloRequest {
+                request_id: 0,
+                token: token.to_vec(),
+            })
+            .await
+            .unwrap();
+        assert!(matches!(
+            client.recv().await.unwrap(),
+            Packet::HelloResponse {
+                status_code: HelloResponseStatusCode::OK,
+                ..
+            }
+        ));
+    }
+
+    async fn join(client: &mut impl Connection, room_id: RoomID) {
+        client
+            .send(Packet::JoinRoomRequest {
+                request_id: 0,
+                room_id: room_id.into_bytes(),
+            })
+            .await
+            .unwrap();
+        assert!(matches!(
+            client.recv().await.unwrap(),
+            Packet::JoinRoomResponse {
+                status_code: JoinRoomResponseStatusCode::OK,
+                ..
+            }
+        ));
+    }
+
+    async fn subscribe_state(client: &mut impl Connection, flags: u8, key: &[u8]) {
+        client
+            .send(Packet::SubscribeStateRequest {
+                request_id: 0,
+                flags,
+                key: key.to_vec(),
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            client.recv().await.unwrap(),
+            Packet::SubscribeStateResponse {
+                request_id: 0,
+                status_code: StateStatusCode::OK,
+            }
+        );
+    }
+
+    async fn assert_state_changed(
+        client: &mut impl Connection,
+        key: &[u8],
+        version: u64,
+        value: &[u8],
+    ) {
+        assert_eq!(
+            client.recv().await.unwrap(),
+            Packet::RoomNotification(RoomNotification::StateChanged {
+                key: key.to_vec(),
+                version,
+                value: value.to_vec(),
+            })
+        );
+    }
+
+    async fn assert_broadcast(client: &mut impl Connection, expected: &[u8]) {
+        match client.recv().await.unwrap() {
+            Packet::RoomNotification(RoomNotification::Broadcast { payload, .. }) => {
+                assert_eq!(payload, expected);
+            }
+            packet => panic!("unexpected packet: {:?}", packet),
+        }
+    }
+
+    async fn assert_direct_message(client: &mut impl Connection, expected: &[u8]) {
+        match client.recv().await.unwrap() {
+            Packet::RoomNotification(RoomNotification::DirectMessage { payload, .. }) => {
+                assert_eq!(payload, expected);
+            }
+            packet => panic!("unexpected packet: {:?}", packet),
+        }
+    }
+
+    async fn recv_binary<S>(ws: &mut S) -> Vec<u8>
+    where
+        S: Stream<Item = Result<Message, WsError>> + Unpin,
+    {
+        loop {
+            match ws.next().await.unwrap().unwrap() {
+                Message::Binary(data) => return data,
+                Message::Ping(_) | Message::Pong(_) => continue,
+                msg => panic!("unexpected message: {:?}", msg),
+            }
+        }
+    }
+
+    async fn recv_player_joined(client: &mut impl Connection) -> uuid::Bytes {
+        match client.recv().await.unwrap() {
+            Packet::RoomNotification(RoomNotification::PlayerJoined { player }) => player,
+            packet => panic!("unexpected packet: {:?}", packet),
+        }
+    }
+
+    async fn assert_player_joined(client: &mut impl Connection) {
+        assert!(matches!(
+            client.recv().await.unwrap(),
+            Packet::RoomNotification(RoomNotification::PlayerJoined { .. })
+        ));
     }
 
     static LOGGER_INIT: Once = Once::new();